@@ -1,54 +1,119 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::dedup::DedupLog;
+use crate::limits::{Limits, LimitsCfg, Refusal};
+use crate::trust::Trust;
 use anyhow::{bail, Result};
+use bytes::Buf;
 use crossbeam_channel::{
     Receiver as ReceiverCB, RecvTimeoutError, Sender as SenderCB, TryRecvError,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use packetcrypt_pool::paymaker;
 use packetcrypt_pool::paymakerclient::{self, PaymakerClient};
 use packetcrypt_pool::poolcfg::AnnHandlerCfg;
 use packetcrypt_sys::{check_ann, PacketCryptAnn, ValidateCtx};
+use packetcrypt_util::annfiles::{self, AnnFiles};
+use packetcrypt_util::metrics::{Counter, MetricsOut};
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
-use packetcrypt_util::protocol::{AnnPostReply, AnnsEvent, BlockInfo, MasterConf};
+use packetcrypt_util::protocol::{AnnPostReply, AnnRejectReason, AnnsEvent, BlockInfo, MasterConf};
 use packetcrypt_util::{hash, util};
 use parking_lot::Mutex as MutexB; // blocking
 use regex::Regex;
 use std::cmp::max;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::Infallible;
 use std::convert::TryInto;
-use std::net::SocketAddr;
-use std::sync::atomic::{self, AtomicUsize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{self, AtomicU8, AtomicUsize};
 use std::sync::Arc;
+use tokio::stream::{Stream, StreamExt};
 use tokio::sync::oneshot;
+use warp::http::StatusCode;
 use warp::Filter;
 
 const NUM_BLOCKS_TRACKING: usize = 6;
 const POOL_UPDATE_QUEUE_LEN: usize = 20;
 const RECV_WAIT_MS: u64 = 10;
 
-fn mk_dedups(w: &mut Worker) -> HashMap<u64, usize> {
-    let mut out = HashMap::new();
-    for (i, ann_opt) in (0..).zip(w.anns.iter()) {
-        let h = hash::compress32(&ann_opt.as_ref().unwrap().bytes[..]);
-        out.insert(u64::from_le_bytes(h[..8].try_into().unwrap()), i);
+// Each ann file is at most 1MB
+const MAX_ANNS_PER_FILE: usize = 1024;
+
+// Write out a (partial) ann file if this long has elapsed since the last one
+const MAX_MS_BETWEEN_FILES: u64 = 10_000;
+
+// How often to check whether there are enough anns to write a file
+const ANN_FILE_POLL_MS: u64 = 250;
+
+// Not the fault of the miner, so not held against them
+const NOT_READY: &str = "server not ready";
+
+// The post is for a block which we are not taking anns for, or no longer
+#[derive(Debug)]
+struct WrongBlock {
+    expect: i32,
+    got: i32,
+}
+impl std::fmt::Display for WrongBlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "block number out of range, expect {} got {}",
+            self.expect, self.got
+        )
     }
-    out
+}
+impl std::error::Error for WrongBlock {}
+
+// All of the anns are rejected, in a way which the miner can act on
+fn wrong_block_reply(e: &WrongBlock, anns: usize) -> AnnPostReply {
+    let mut rejected = BTreeMap::new();
+    rejected.insert(AnnRejectReason::WrongParentHeight, anns as u32);
+    AnnPostReply {
+        error: vec![e.to_string()],
+        warn: vec![],
+        result: None,
+        rejected,
+    }
+}
+
+// Unless max_anns_per_post is configured, the same as the ann miner's batch size
+const DEFAULT_MAX_ANNS_PER_POST: usize = 1024;
+
+// Anns are passed to the workers in chunks of this many while the post is being read
+const CHUNK_ANNS: usize = 128;
+
+// The dedup hash of each ann
+fn mk_dedups(w: &mut Worker) -> Vec<u64> {
+    w.anns
+        .iter()
+        .map(|ann_opt| {
+            let h = hash::compress32(&ann_opt.as_ref().unwrap().bytes[..]);
+            u64::from_le_bytes(h[..8].try_into().unwrap())
+        })
+        .collect()
 }
 
 #[derive(Debug)]
 struct Output {
     config: Config,
     dedup_tbl: HashSet<u64>,
+    // dedup_tbl is also written here so that it survives a restart
+    dedup_log: DedupLog,
+    // The block which dedup_tbl was loaded for at startup
+    restored: Option<(i32, [u8; 32])>,
 }
 
 pub struct Global {
     outputs: [MutexB<Output>; NUM_BLOCKS_TRACKING],
 
+    // The config as it was at startup, see live_cfg for the parts which can be reloaded
     cfg: AnnHandlerCfg,
+    live_cfg: MutexB<AnnHandlerCfg>,
 
-    // Http posts
+    // Http posts, the channel is unbounded but we refuse posts past input_queue_len
     submit_send: SenderCB<AnnPost>,
     submit_recv: ReceiverCB<AnnPost>,
+    input_queue_len: AtomicUsize,
 
     // Work updates
     pc: PoolClient,
@@ -59,12 +124,29 @@ pub struct Global {
 
     sockaddr: std::net::SocketAddr,
 
-    skip_check_chance: u8,
+    // The chance for miners which are fully trusted, see trust.rs
+    skip_check_chance: AtomicU8,
+    trust: Trust,
+
+    // Per IP and pay_to rate limits and bans
+    limits: Limits,
 
     sprayer: packetcrypt_sprayer::Sprayer,
 
-    overloads: AtomicUsize,
-    timeouts: AtomicUsize,
+    // Accepted anns which have not yet been written to an ann file
+    ann_file_buf: MutexB<Vec<bytes::Bytes>>,
+    anndir: String,
+    tmpdir: String,
+
+    overloads: Counter,
+    timeouts: Counter,
+    anns_accepted: Counter,
+    anns_dup: Counter,
+    anns_invalid: Counter,
+    anns_checked: Counter,
+    anns_skipped: Counter,
+    anns_check_failed: Counter,
+    batches_failed: Counter,
     last_log_time: AtomicUsize,
 }
 
@@ -116,62 +198,113 @@ fn hash_num_ok(pnr: &AnnPostMeta, ann: &PacketCryptAnn, dedup: u64, conf: &Confi
     }
 }
 
+// Everything about an ann which can be checked without validating it
+fn check_fields(
+    ann: &PacketCryptAnn,
+    dedup_hash: u64,
+    pnr: &AnnPostMeta,
+    conf: &Config,
+) -> Result<(), AnnRejectReason> {
+    if util::is_zero(ann.signing_key()) {
+    } else if let Some(sk) = conf.signing_key {
+        if sk != ann.signing_key() {
+            return Err(AnnRejectReason::WrongSigningKey);
+        }
+    } else {
+        return Err(AnnRejectReason::UnexpectedSignature);
+    }
+    if conf.parent_block_height != ann.parent_block_height() {
+        Err(AnnRejectReason::WrongParentHeight)
+    } else if conf.min_work < ann.work_bits() {
+        Err(AnnRejectReason::NotEnoughWork)
+    } else if dedup_hash == 0 || dedup_hash == u64::MAX {
+        Err(AnnRejectReason::BadHash)
+    } else if !hash_num_ok(pnr, ann, dedup_hash, conf) {
+        Err(AnnRejectReason::SubmitElsewhere)
+    } else if conf.ann_version != ann.version() {
+        Err(AnnRejectReason::UnsupportedVersion)
+    } else {
+        Ok(())
+    }
+}
+
+// Returns the reason why each ann is rejected, None for the ones which are good
 fn validate_anns(
     w: &mut Worker,
     res: &mut AnnsEvent,
     pnr: &AnnPostMeta,
     conf: &Config,
-    dedups: &HashMap<u64, usize>,
-) -> Result<()> {
+    dedups: &[u64],
+) -> Vec<Option<AnnRejectReason>> {
     res.target = 0;
-    for (ann_opt, (dedup_hash, _dedup_index)) in w.anns.iter().zip(dedups.iter()) {
+    let now_ms = util::now_ms();
+    let trust = w.global.trust.level(now_ms, &pnr.pay_to);
+    let skip_below =
+        (w.global.skip_check_chance.load(atomic::Ordering::Relaxed) as f64 * trust) as u8;
+    let mut pbh = conf.parent_block_hash;
+    pbh.reverse();
+    let mut out = Vec::with_capacity(w.anns.len());
+    let mut skipped = Vec::new();
+    let (mut checked, mut failed) = (0, 0);
+    for (i, (ann_opt, dedup_hash)) in w.anns.iter().zip(dedups).enumerate() {
         let ann = if let Some(x) = ann_opt {
             x
         } else {
-            bail!("empty ann entry");
+            out.push(Some(AnnRejectReason::Invalid));
+            continue;
         };
-        let unsigned = util::is_zero(ann.signing_key());
-        if unsigned {
-        } else if let Some(sk) = conf.signing_key {
-            if sk != ann.signing_key() {
-                bail!("wrong signing key");
-            }
+        if let Err(r) = check_fields(ann, *dedup_hash, pnr, conf) {
+            out.push(Some(r));
+        } else if failed == 0 && (*dedup_hash as u8 ^ w.random) < skip_below {
+            skipped.push(i);
+            out.push(None);
         } else {
-            bail!("unexpected signed ann");
-        }
-        if conf.parent_block_height != ann.parent_block_height() {
-            bail!(
-                "wrong parent block height, want {} got {}",
-                conf.parent_block_height,
-                ann.parent_block_height()
-            );
-        } else if conf.min_work < ann.work_bits() {
-            bail!("not enough work");
-        } else if *dedup_hash == 0 || *dedup_hash == u64::MAX {
-            bail!("zero or fff hash");
-        } else if !hash_num_ok(pnr, ann, *dedup_hash, conf) {
-            bail!("submit elsewhere");
-        } else if conf.ann_version != ann.version() {
-            bail!("unsupported ann version");
-        } else if (*dedup_hash as u8 ^ w.random) < w.global.skip_check_chance {
-            // fallthrough
-        } else {
-            let mut pbh = conf.parent_block_hash;
-            pbh.reverse();
+            checked += 1;
             if let Err(x) = check_ann(ann, &pbh, &mut w.vctx) {
-                bail!("check_ann() -> {}", x);
+                debug!("check_ann() -> {}", x);
+                failed += 1;
+                out.push(Some(AnnRejectReason::Invalid));
+            } else {
+                out.push(None);
+            }
+        }
+    }
+    if failed > 0 {
+        // Caught cheating, so nothing in this batch gets the benefit of the doubt
+        for &i in &skipped {
+            checked += 1;
+            if check_ann(w.anns[i].as_ref().unwrap(), &pbh, &mut w.vctx).is_err() {
+                failed += 1;
+                out[i] = Some(AnnRejectReason::Invalid);
             }
         }
-        res.unsigned += unsigned as u32;
-        // higher number represents less work
-        res.target = max(res.target, ann.work_bits());
+        skipped.clear();
+        if w.global.trust.failed(now_ms, &pnr.pay_to) {
+            info!(
+                "{} submitted an invalid ann, checking all of their anns for a while",
+                pnr.pay_to
+            );
+        }
+    } else {
+        w.global.trust.verified(now_ms, &pnr.pay_to, checked);
+    }
+    w.global.anns_checked.add(checked);
+    w.global.anns_skipped.add(skipped.len() as u64);
+    w.global.anns_check_failed.add(failed);
+
+    for (ann_opt, reason) in w.anns.iter().zip(&out) {
+        if let (Some(ann), None) = (ann_opt, reason) {
+            res.unsigned += util::is_zero(ann.signing_key()) as u32;
+            // higher number represents less work
+            res.target = max(res.target, ann.work_bits());
+        }
     }
     res.target = if res.target == 0 {
         conf.min_work
     } else {
         res.target
     };
-    Ok(())
+    out
 }
 
 fn get_output(g: &Arc<Global>, parent_block_height: i32) -> &MutexB<Output> {
@@ -183,34 +316,66 @@ fn process_batch(
     res: &mut AnnsEvent,
     pnr: &AnnPostMeta,
     conf: &Config,
+    rejected: &mut BTreeMap<AnnRejectReason, u32>,
 ) -> Result<()> {
     let dedups = mk_dedups(w);
-    validate_anns(w, res, pnr, conf, &dedups)?;
-    res.dup = (dedups.len() - w.anns.len()) as u32;
+    let reasons = validate_anns(w, res, pnr, conf, &dedups);
+
+    // Hash to index of the good anns, skipping duplicates within the batch
+    let mut good = HashMap::with_capacity(dedups.len());
+    for (i, (reason, h)) in reasons.iter().zip(&dedups).enumerate() {
+        match reason {
+            Some(r) => {
+                *rejected.entry(*r).or_default() += 1;
+                match r {
+                    AnnRejectReason::BadHash | AnnRejectReason::SubmitElsewhere => {
+                        res.bad_hash += 1
+                    }
+                    _ => res.inval += 1,
+                }
+            }
+            None => {
+                if good.insert(*h, i).is_some() {
+                    res.dup += 1;
+                }
+            }
+        }
+    }
+    if res.dup > 0 {
+        rejected.insert(AnnRejectReason::Duplicate, res.dup);
+    }
 
     let g = w.global.clone();
     let output_mtx = get_output(&g, conf.parent_block_height);
-    let mut dedup_set: HashSet<u64> = dedups.keys().cloned().collect();
+    let mut dedup_set: HashSet<u64> = good.keys().cloned().collect();
     {
         let mut output = output_mtx.lock();
         //if let Some(out) = output.
         if output.config.parent_block_height != conf.parent_block_height {
             // we were too late
-            bail!("block number out of range");
+            return Err(WrongBlock {
+                expect: output.config.parent_block_height,
+                got: conf.parent_block_height,
+            }
+            .into());
         }
         let v: HashSet<u64> = output.dedup_tbl.intersection(&dedup_set).cloned().collect();
         for dup in v {
             res.dup += 1;
+            *rejected.entry(AnnRejectReason::Duplicate).or_default() += 1;
             dedup_set.remove(&dup);
         }
         output.dedup_tbl.extend(&dedup_set);
+        if let Err(e) = output.dedup_log.append(&dedup_set) {
+            error!("Unable to write dedup log {}", e);
+        }
     }
     res.accepted += dedup_set.len() as u32;
 
     // done in 2 stages because borrow checker
     let good_anns = dedup_set
         .iter()
-        .filter_map(|h| dedups.get(h))
+        .filter_map(|h| good.get(h))
         .filter_map(|i| w.anns[*i].take())
         .collect::<Vec<_>>();
     w.global.sprayer.push_anns(
@@ -219,10 +384,29 @@ fn process_batch(
             .map(|ann| &ann.bytes[..])
             .collect::<Vec<_>>()[..],
     );
+    w.global
+        .ann_file_buf
+        .lock()
+        .extend(good_anns.into_iter().map(|ann| ann.bytes));
 
     Ok(())
 }
 
+// Start deduplicating for a different block, unless it's the one we restored
+fn new_block(out: &mut Output, height: i32, hash: [u8; 32]) {
+    if out.restored == Some((height, hash)) {
+        if let Err(e) = out.dedup_log.resume() {
+            error!("Unable to reopen dedup log {}", e);
+        }
+        return;
+    }
+    out.restored = None;
+    out.dedup_tbl.clear();
+    if let Err(e) = out.dedup_log.reset(height, &hash) {
+        error!("Unable to create dedup log {}", e);
+    }
+}
+
 fn process_update(w: &mut Worker, conf: &MasterConf, bi: BlockInfo) {
     let g = w.global.clone();
     // note: this conf.current_height is the next height to be made, so we subtract 1
@@ -235,8 +419,7 @@ fn process_update(w: &mut Worker, conf: &MasterConf, bi: BlockInfo) {
             );
             return;
         }
-        let out = &mut *output;
-        out.dedup_tbl.clear();
+        new_block(&mut output, bi.header.height, bi.header.hash);
         debug!("New work: height: {}", bi.header.height);
     } else if bi.header.hash != output.config.parent_block_hash {
         info!(
@@ -244,7 +427,7 @@ fn process_update(w: &mut Worker, conf: &MasterConf, bi: BlockInfo) {
             hex::encode(bi.header.hash),
             hex::encode(output.config.parent_block_hash)
         );
-        output.dedup_tbl.clear();
+        new_block(&mut output, bi.header.height, bi.header.hash);
     }
     output.config.handler_num = if let Some(x) = conf
         .submit_ann_urls
@@ -305,23 +488,25 @@ struct AnnPost {
     bytes: bytes::Bytes,
     reply: Option<oneshot::Sender<AnnPostReply>>,
 }
-fn process_submit1(w: &mut Worker, sub: AnnPost) -> Result<AnnPostReply> {
-    let (meta, mut bytes) = (sub.meta, sub.bytes);
-    let config = {
-        get_output(&w.global, meta.next_block_height - 1)
-            .lock()
-            .config
-    };
-    if config.parent_block_height != meta.next_block_height - 1 {
+// The config for the block which the miner is working on, if we are still taking anns for it
+fn get_config(g: &Arc<Global>, next_block_height: i32) -> Result<Config> {
+    let config = { get_output(g, next_block_height - 1).lock().config };
+    if config.parent_block_height != next_block_height - 1 {
         if config.parent_block_height < 1 {
-            bail!("server not ready");
+            bail!(NOT_READY);
         }
-        bail!(
-            "block number out of range, expect {} got {}",
-            config.parent_block_height,
-            meta.next_block_height - 1
-        );
+        return Err(WrongBlock {
+            expect: config.parent_block_height,
+            got: next_block_height - 1,
+        }
+        .into());
     }
+    Ok(config)
+}
+
+fn process_submit1(w: &mut Worker, sub: AnnPost) -> Result<AnnPostReply> {
+    let (meta, mut bytes) = (sub.meta, sub.bytes);
+    let config = get_config(&w.global, meta.next_block_height)?;
     if !w.payto_regex.is_match(meta.pay_to.as_str()) {
         bail!("invalid payto {}", meta.pay_to.as_str());
     }
@@ -348,34 +533,55 @@ fn process_submit1(w: &mut Worker, sub: AnnPost) -> Result<AnnPostReply> {
     res.pay_to = meta.pay_to.clone();
     res.event_id = hex::encode(&hash::compress32(&bytes)[..16]);
     res.time = util::now_ms();
-    process_batch(w, &mut res, &meta, &config)?;
+    let mut rejected = BTreeMap::new();
+    process_batch(w, &mut res, &meta, &config, &mut rejected)?;
     Ok(AnnPostReply {
         error: vec![],
-        warn: vec![],
+        warn: rejected
+            .iter()
+            .map(|(r, n)| format!("{} anns rejected: {:?}", n, r))
+            .collect(),
         result: Some(res),
+        rejected,
     })
 }
 
 fn process_submit0(w: &mut Worker, mut sub: AnnPost) {
     let remote_addr: Option<SocketAddr> = sub.meta.remote_addr.take();
+    let anns = sub.bytes.len() / 1024;
     match sub
         .reply
         .take()
         .unwrap()
         .send(match process_submit1(w, sub) {
-            Ok(resp) => resp,
+            Ok(resp) => {
+                if let Some(res) = &resp.result {
+                    w.global.anns_accepted.add(res.accepted as u64);
+                    w.global.anns_dup.add(res.dup as u64);
+                    w.global
+                        .anns_invalid
+                        .add((res.inval + res.bad_hash + res.runt) as u64);
+                }
+                resp
+            }
             Err(e) => {
+                w.global.batches_failed.add(1);
                 debug!("Error processing req from [{:?}] [{:?}]", &remote_addr, e);
-                AnnPostReply {
-                    error: vec![e.to_string()],
-                    warn: vec![],
-                    result: None,
+                if let Some(wb) = e.downcast_ref::<WrongBlock>() {
+                    wrong_block_reply(wb, anns)
+                } else {
+                    AnnPostReply {
+                        error: vec![e.to_string()],
+                        warn: vec![],
+                        result: None,
+                        rejected: BTreeMap::new(),
+                    }
                 }
             }
         }) {
         Ok(_) => (),
         Err(_) => {
-            w.global.timeouts.fetch_add(1, atomic::Ordering::Relaxed);
+            w.global.timeouts.add(1);
             //info!("Error sending reply [{:?}]", e);
         }
     }
@@ -396,12 +602,16 @@ fn worker_loop(g: Arc<Global>, thread_num: usize) {
             let llt = w.global.last_log_time.load(atomic::Ordering::Relaxed);
             let now = util::now_ms() / 1000;
             if (now as usize) - llt > 5 {
-                let overloads = w.global.overloads.swap(0, atomic::Ordering::Relaxed);
-                let timeouts = w.global.timeouts.swap(0, atomic::Ordering::Relaxed);
+                let overloads = w.global.overloads.take();
+                let timeouts = w.global.timeouts.take();
+                let limited = w.global.limits.rate_limited.take();
+                let banned = w.global.limits.banned.take();
                 info!(
-                    "overloads: {} timeout: {} q: {}",
+                    "overloads: {} timeout: {} limited: {} banned: {} q: {}",
                     overloads,
                     timeouts,
+                    limited,
+                    banned,
                     w.global.submit_recv.len()
                 );
                 w.global
@@ -440,22 +650,55 @@ fn worker_loop(g: Arc<Global>, thread_num: usize) {
 
 pub type AnnHandler = Arc<Global>;
 
-pub async fn new(
-    pc: &PoolClient,
-    pmc: &PaymakerClient,
-    mut cfg: AnnHandlerCfg,
-) -> Result<AnnHandler> {
+fn skip_check_threshold(cfg: &AnnHandlerCfg) -> Result<u8> {
     if cfg.skip_check_chance > 1.0 || cfg.skip_check_chance < 0.0 {
         bail!(
             "skip_check_chance must be a number between 0 and 1, got {}",
             cfg.skip_check_chance
         );
     }
+    Ok((255.0 * cfg.skip_check_chance) as u8)
+}
+
+pub async fn new(
+    pc: &PoolClient,
+    pmc: &PaymakerClient,
+    cfg: AnnHandlerCfg,
+    workdir: &str,
+) -> Result<AnnHandler> {
+    let skip_check_chance = skip_check_threshold(&cfg)?;
+    if cfg.files_to_keep == 0 {
+        bail!("files_to_keep must be at least 1");
+    }
+    let anndir = format!("{}/anns", workdir);
+    let tmpdir = format!("{}/tmp", workdir);
+    let dedupdir = format!("{}/dedup", workdir);
+    util::ensure_exists_dir(&anndir).await?;
+    util::ensure_exists_dir(&tmpdir).await?;
+    util::ensure_exists_dir(&dedupdir).await?;
     let outputs: Box<[_; NUM_BLOCKS_TRACKING]> = (0..NUM_BLOCKS_TRACKING)
-        .map(|_| {
+        .map(|i| {
+            let dedup_log = DedupLog::new(&dedupdir, i);
+            let (restored, dedup_tbl) = match dedup_log.load() {
+                Ok(Some((height, hash, dedup_tbl))) => {
+                    info!(
+                        "Restored {} dedup entries for block {}",
+                        dedup_tbl.len(),
+                        height
+                    );
+                    (Some((height, hash)), dedup_tbl)
+                }
+                Ok(None) => (None, HashSet::new()),
+                Err(e) => {
+                    warn!("Unable to load dedup log {}", e);
+                    (None, HashSet::new())
+                }
+            };
             MutexB::new(Output {
                 config: Config::default(),
-                dedup_tbl: HashSet::new(),
+                dedup_tbl,
+                dedup_log,
+                restored,
             })
         })
         .collect::<Vec<_>>()
@@ -471,91 +714,565 @@ pub async fn new(
         subscribe_to: cfg.subscribe_to.clone(),
         log_peer_stats: true,
         mss: if let Some(mss) = cfg.mss { mss } else { 1472 },
-        spray_at: cfg.spray_at.take().unwrap_or_else(Vec::new),
-        mcast: "".to_owned(),
+        spray_at: cfg.spray_at.clone().unwrap_or_else(Vec::new),
+        mcast: cfg.mcast.clone().unwrap_or_default(),
+        nack: false,
+        max_kbps: cfg.spray_max_kbps.unwrap_or(0),
+        adaptive: cfg.spray_adaptive.unwrap_or(false),
+        tcp_bind: cfg.spray_tcp_bind.clone().unwrap_or_default(),
+        relay: false,
+        min_work: 0,
+        filter_work: 0,
+        filter_height: 0,
     })?;
 
-    let (submit_send, submit_recv) = crossbeam_channel::bounded(cfg.input_queue_len);
+    let (submit_send, submit_recv) = crossbeam_channel::unbounded();
     let (pc_update_send, pc_update_recv) = crossbeam_channel::bounded(POOL_UPDATE_QUEUE_LEN);
     let global = Arc::new(Global {
         outputs: *outputs,
         submit_send,
         submit_recv,
+        input_queue_len: AtomicUsize::new(cfg.input_queue_len),
         pc: pc.clone(),
         pc_update_recv,
         pc_update_send,
         pmc: pmc.clone(),
         sockaddr: bind_pub,
-        skip_check_chance: 255 * cfg.skip_check_chance as u8,
+        skip_check_chance: AtomicU8::new(skip_check_chance),
+        trust: Trust::default(),
+        limits: Limits::new(LimitsCfg::new(&cfg)),
+        live_cfg: MutexB::new(cfg.clone()),
         cfg,
         sprayer,
-        overloads: AtomicUsize::new(0),
-        timeouts: AtomicUsize::new(0),
+        ann_file_buf: MutexB::new(Vec::new()),
+        anndir,
+        tmpdir,
+        overloads: Counter::default(),
+        timeouts: Counter::default(),
+        anns_accepted: Counter::default(),
+        anns_dup: Counter::default(),
+        anns_invalid: Counter::default(),
+        anns_checked: Counter::default(),
+        anns_skipped: Counter::default(),
+        anns_check_failed: Counter::default(),
+        batches_failed: Counter::default(),
         last_log_time: AtomicUsize::new(0),
     });
 
     Ok(global)
 }
 
-async fn handle_submit(
+fn error_reply(err: String, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&AnnPostReply {
+            error: vec![err],
+            warn: vec![],
+            result: None,
+            rejected: BTreeMap::new(),
+        }),
+        status,
+    )
+}
+
+// Refuse a post, counting `bad` anns against the source
+fn refuse(
+    ah: &AnnHandler,
+    ip: Option<IpAddr>,
+    pay_to: &str,
+    bad: usize,
+    err: String,
+    status: StatusCode,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    ah.limits.score(util::now_ms(), ip, pay_to, 0, bad as u64);
+    error_reply(err, status)
+}
+
+// Read the anns from the body of a post as they arrive, passing every CHUNK_ANNS of
+// them to `on_chunk` so that they can be validated while the rest are still coming,
+// and giving up as soon as the post is too big. Returns the number of anns, an empty
+// post is passed on as one empty chunk. If the post is no good, the error includes
+// the number of anns which should be counted as bad.
+async fn read_anns<S, B>(
+    body: S,
+    max_len: usize,
+    mut on_chunk: impl FnMut(bytes::Bytes),
+) -> std::result::Result<usize, (String, StatusCode, usize)>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    tokio::pin!(body);
+    let chunk_len = CHUNK_ANNS * 1024;
+    let mut buf = bytes::BytesMut::with_capacity(chunk_len);
+    let mut len = 0;
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| {
+            (
+                format!("error reading post {}", e),
+                StatusCode::BAD_REQUEST,
+                0,
+            )
+        })?;
+        len += chunk.remaining();
+        if len > max_len {
+            return Err((
+                format!("too many anns, at most {} per post", max_len / 1024),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                max_len / 1024,
+            ));
+        }
+        while chunk.has_remaining() {
+            let b = chunk.bytes();
+            let n = std::cmp::min(b.len(), chunk_len - buf.len());
+            buf.extend_from_slice(&b[..n]);
+            chunk.advance(n);
+            if buf.len() == chunk_len {
+                on_chunk(buf.split().freeze());
+            }
+        }
+    }
+    if len % 1024 != 0 {
+        return Err((
+            "size not an even multiple of 1024".into(),
+            StatusCode::BAD_REQUEST,
+            (len + 1023) / 1024,
+        ));
+    }
+    if !buf.is_empty() || len == 0 {
+        on_chunk(buf.freeze());
+    }
+    Ok(len / 1024)
+}
+
+// Add up the results for the chunks of a post
+fn add_result(out: &mut AnnsEvent, res: &AnnsEvent) {
+    out.accepted += res.accepted;
+    out.dup += res.dup;
+    out.inval += res.inval;
+    out.bad_hash += res.bad_hash;
+    out.runt += res.runt;
+    out.internal_err += res.internal_err;
+    out.unsigned += res.unsigned;
+    out.total_len += res.total_len;
+    // higher number represents less work
+    out.target = max(out.target, res.target);
+}
+
+// Wait for the workers to be done with each chunk of a post, and put their replies
+// together into one. The source is scored and paid for whatever was accepted.
+async fn finish_post(
+    ah: &AnnHandler,
+    ip: Option<IpAddr>,
+    pay_to: &str,
+    chunks: Vec<(usize, oneshot::Receiver<AnnPostReply>)>,
+) -> AnnPostReply {
+    let mut out = AnnPostReply::default();
+    let mut event_ids = Vec::new();
+    let (mut good, mut bad) = (0, 0);
+    for (anns, getreply) in chunks {
+        let reply = getreply.await.unwrap();
+        if let Some(res) = &reply.result {
+            good += res.accepted;
+            bad += res.inval + res.bad_hash + res.runt;
+        } else if !reply.error.iter().any(|e| e == NOT_READY) {
+            // Nothing in the chunk could be used
+            bad += anns as u32;
+        }
+        for e in reply.error {
+            if !out.error.contains(&e) {
+                out.error.push(e);
+            }
+        }
+        for (r, n) in reply.rejected {
+            *out.rejected.entry(r).or_default() += n;
+        }
+        if let Some(res) = reply.result {
+            event_ids.push(res.event_id.clone());
+            if let Some(o) = &mut out.result {
+                add_result(o, &res);
+            } else {
+                out.result = Some(res);
+            }
+        }
+    }
+    ah.limits
+        .score(util::now_ms(), ip, pay_to, good as u64, bad as u64);
+    if let Some(res) = &mut out.result {
+        if event_ids.len() > 1 {
+            // Still the same for the same post
+            res.event_id = hex::encode(&hash::compress32(event_ids.concat().as_bytes())[..16]);
+        }
+        if let Err(e) = paymakerclient::handle_paylog(&ah.pmc, &res).await {
+            error!("Unable to send paylog {}", e);
+        }
+        out.warn = out
+            .rejected
+            .iter()
+            .map(|(r, n)| format!("{} anns rejected: {:?}", n, r))
+            .collect();
+    }
+    out
+}
+
+async fn handle_submit<S, B>(
     ah: AnnHandler,
     remote_addr: Option<SocketAddr>,
-    bytes: bytes::Bytes,
-    //content_length: usize,
+    content_length: Option<u64>,
     sver: u32,
     next_block_height: i32,
     pay_to: String,
-) -> Result<impl warp::Reply, Infallible> {
-    let (reply, getreply) = oneshot::channel();
-    match ah.submit_send.try_send(AnnPost {
-        meta: AnnPostMeta {
-            sver,
-            next_block_height,
-            pay_to,
-            remote_addr,
-        },
-        bytes,
-        reply: Some(reply),
-    }) {
-        Ok(_) => {
-            let reply = getreply.await.unwrap();
-            let ok = reply.error.is_empty();
-            if let Some(res) = &reply.result {
-                if let Err(e) = paymakerclient::handle_paylog(&ah.pmc, &res).await {
-                    error!("Unable to send paylog {}", e);
-                }
+    body: S,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, Infallible>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    // Everything which can be checked before reading the body
+    let ip = remote_addr.map(|a| a.ip());
+    let max_anns = ah
+        .live_cfg
+        .lock()
+        .max_anns_per_post
+        .unwrap_or(DEFAULT_MAX_ANNS_PER_POST);
+    let anns = content_length.map_or(0, |len| (len as usize + 1023) / 1024);
+    // A post which is too big is only charged for as many anns as it may have
+    if let Err(r) = ah
+        .limits
+        .check(util::now_ms(), ip, &pay_to, anns.min(max_anns))
+    {
+        return Ok(error_reply(
+            r.to_string(),
+            match r {
+                Refusal::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                Refusal::Banned => StatusCode::FORBIDDEN,
+            },
+        ));
+    }
+    // From here on, a post which is refused counts against the source
+    if let Some(len) = content_length {
+        if len > (max_anns * 1024) as u64 {
+            return Ok(refuse(
+                &ah,
+                ip,
+                &pay_to,
+                max_anns,
+                format!("too many anns, at most {} per post", max_anns),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ));
+        } else if len % 1024 != 0 {
+            return Ok(refuse(
+                &ah,
+                ip,
+                &pay_to,
+                anns,
+                "size not an even multiple of 1024".into(),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    }
+    if let Err(e) = get_config(&ah, next_block_height) {
+        if let Some(wb) = e.downcast_ref::<WrongBlock>() {
+            // Without a length we don't know how many anns to count against the
+            // source, so the post is read and the workers refuse it.
+            if content_length.is_some() {
+                ah.limits.score(util::now_ms(), ip, &pay_to, 0, anns as u64);
+                return Ok(warp::reply::with_status(
+                    warp::reply::json(&wrong_block_reply(wb, anns)),
+                    StatusCode::BAD_REQUEST,
+                ));
             }
-            Ok(warp::reply::with_status(
-                warp::reply::json(&reply),
-                if ok {
-                    warp::http::StatusCode::OK
-                } else {
-                    warp::http::StatusCode::BAD_REQUEST
-                },
-            ))
-        }
-        Err(e) => {
-            let err: String = (if e.is_full() {
-                ah.overloads.fetch_add(1, atomic::Ordering::Relaxed);
-                "overloaded"
-            } else {
-                error!("channel disconnected");
-                "disconnected"
-            })
-            .into();
-            Ok(warp::reply::with_status(
-                warp::reply::json(&AnnPostReply {
-                    error: vec![err],
-                    warn: vec![],
-                    result: None,
-                }),
-                warp::http::StatusCode::SERVICE_UNAVAILABLE,
-            ))
+        } else {
+            return Ok(error_reply(e.to_string(), StatusCode::BAD_REQUEST));
+        }
+    }
+    // Once a post is taken, all of its chunks go to the workers
+    if ah.submit_send.len() >= ah.input_queue_len.load(atomic::Ordering::Relaxed) {
+        ah.overloads.add(1);
+        return Ok(error_reply(
+            "overloaded".into(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ));
+    }
+
+    let mut chunks = Vec::new();
+    let read = read_anns(body, max_anns * 1024, |bytes| {
+        let anns = bytes.len() / 1024;
+        let (reply, getreply) = oneshot::channel();
+        let post = AnnPost {
+            meta: AnnPostMeta {
+                sver,
+                next_block_height,
+                pay_to: pay_to.clone(),
+                remote_addr,
+            },
+            bytes,
+            reply: Some(reply),
+        };
+        if ah.submit_send.try_send(post).is_ok() {
+            chunks.push((anns, getreply));
+        } else {
+            error!("channel disconnected");
+        }
+    })
+    .await;
+    let anns = match read {
+        Ok(anns) => anns,
+        Err((e, status, bad)) => {
+            // Whatever was already passed on is still paid for
+            finish_post(&ah, ip, &pay_to, chunks).await;
+            return Ok(refuse(&ah, ip, &pay_to, bad, e, status));
+        }
+    };
+    if content_length.is_none() {
+        ah.limits.charge(util::now_ms(), ip, &pay_to, anns);
+    }
+    let reply = finish_post(&ah, ip, &pay_to, chunks).await;
+    let status = if reply.error.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&reply), status))
+}
+
+async fn ann_file_cycle(ah: &AnnHandler, af: &mut AnnFiles) -> Result<()> {
+    let now = util::now_ms();
+    let anns = {
+        let mut buf = ah.ann_file_buf.lock();
+        if buf.len() < MAX_ANNS_PER_FILE
+            && (buf.is_empty() || af.last_write_ms + MAX_MS_BETWEEN_FILES > now)
+        {
+            return Ok(());
+        }
+        std::mem::take(&mut *buf)
+    };
+    for chunk in anns.chunks(MAX_ANNS_PER_FILE) {
+        annfiles::write(af, chunk).await?;
+    }
+    af.last_write_ms = now;
+    annfiles::write_index(af).await
+}
+
+async fn ann_file_loop(ah: &AnnHandler) {
+    let mut af = loop {
+        match annfiles::open(&ah.anndir, &ah.tmpdir, ah.cfg.files_to_keep).await {
+            Ok(af) => break af,
+            Err(e) => {
+                error!("Unable to open ann files in {}: {}", ah.anndir, e);
+                util::sleep_ms(5_000).await;
+            }
+        }
+    };
+    if let Err(e) = annfiles::write_index(&af).await {
+        error!("Unable to write ann file index {}", e);
+    }
+    loop {
+        if let Err(e) = ann_file_cycle(ah, &mut af).await {
+            error!("Unable to write ann file {}", e);
         }
+        util::sleep_ms(ANN_FILE_POLL_MS).await;
+    }
+}
+
+async fn handle_anns_auth(ah: AnnHandler, auth: Option<String>) -> Result<(), warp::Rejection> {
+    let passwd = ah.live_cfg.lock().block_miner_passwd.clone();
+    if passwd.is_empty() {
+        return Ok(());
+    }
+    if paymaker::check_basic_auth(auth.as_deref(), &passwd) {
+        Ok(())
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+/// Apply the parts of a new config which can be changed while running, returns the
+/// names of any fields which have changed but will only take effect after a restart.
+pub fn reload(ah: &AnnHandler, cfg: AnnHandlerCfg) -> Result<Vec<&'static str>> {
+    let skip_check_chance = skip_check_threshold(&cfg)?;
+    let mut live = ah.live_cfg.lock();
+    if cfg.block_miner_passwd != live.block_miner_passwd
+        || cfg.subscribe_to != live.subscribe_to
+        || cfg.spray_at != live.spray_at
+    {
+        ah.sprayer.reconfigure(
+            &cfg.block_miner_passwd,
+            &cfg.subscribe_to,
+            cfg.spray_at.as_deref().unwrap_or(&[]),
+        )?;
+        if cfg.block_miner_passwd != live.block_miner_passwd {
+            info!("Reload: block_miner_passwd changed");
+        }
+        if cfg.subscribe_to != live.subscribe_to {
+            info!(
+                "Reload: subscribe_to {:?} -> {:?}",
+                live.subscribe_to, cfg.subscribe_to
+            );
+        }
+        if cfg.spray_at != live.spray_at {
+            info!("Reload: spray_at {:?} -> {:?}", live.spray_at, cfg.spray_at);
+        }
+    }
+    if cfg.skip_check_chance != live.skip_check_chance {
+        info!(
+            "Reload: skip_check_chance {} -> {}",
+            live.skip_check_chance, cfg.skip_check_chance
+        );
+        ah.skip_check_chance
+            .store(skip_check_chance, atomic::Ordering::Relaxed);
+    }
+    let limits = LimitsCfg::new(&cfg);
+    if limits != LimitsCfg::new(&live) {
+        info!(
+            "Reload: rate limits and bans {:?} -> {:?}",
+            LimitsCfg::new(&live),
+            limits
+        );
+        ah.limits.set_cfg(limits);
+    }
+    if cfg.input_queue_len != live.input_queue_len {
+        info!(
+            "Reload: input_queue_len {} -> {}",
+            live.input_queue_len, cfg.input_queue_len
+        );
+        ah.input_queue_len
+            .store(cfg.input_queue_len, atomic::Ordering::Relaxed);
+    }
+
+    let mut need_restart = Vec::new();
+    if cfg.num_workers != ah.cfg.num_workers {
+        need_restart.push("num_workers");
+    }
+    if cfg.public_url != ah.cfg.public_url {
+        need_restart.push("public_url");
+    }
+    if cfg.bind_pub != ah.cfg.bind_pub {
+        need_restart.push("bind_pub");
+    }
+    if cfg.files_to_keep != ah.cfg.files_to_keep {
+        need_restart.push("files_to_keep");
+    }
+    if cfg.bind_pvt != ah.cfg.bind_pvt {
+        need_restart.push("bind_pvt");
+    }
+    if cfg.spray_workers != ah.cfg.spray_workers {
+        need_restart.push("spray_workers");
+    }
+    if cfg.mss != ah.cfg.mss {
+        need_restart.push("mss");
+    }
+    if cfg.mcast != ah.cfg.mcast {
+        need_restart.push("mcast");
     }
+    if cfg.spray_max_kbps != ah.cfg.spray_max_kbps {
+        need_restart.push("spray_max_kbps");
+    }
+    if cfg.spray_adaptive != ah.cfg.spray_adaptive {
+        need_restart.push("spray_adaptive");
+    }
+    if cfg.spray_tcp_bind != ah.cfg.spray_tcp_bind {
+        need_restart.push("spray_tcp_bind");
+    }
+    for field in &need_restart {
+        warn!("Reload: change to {} requires a restart, ignoring", field);
+    }
+    *live = cfg;
+    Ok(need_restart)
+}
+
+pub fn metrics(ah: &AnnHandler, out: &mut MetricsOut) {
+    let anns = [
+        ("accepted", &ah.anns_accepted),
+        ("dup", &ah.anns_dup),
+        ("invalid", &ah.anns_invalid),
+    ];
+    for (result, c) in &anns {
+        out.counter(
+            "annhandler_anns_total",
+            "Announcements received, by result",
+            &[("result", result)],
+            c.get() as f64,
+        );
+    }
+    let checks = [
+        ("checked", &ah.anns_checked),
+        ("skipped", &ah.anns_skipped),
+        ("failed", &ah.anns_check_failed),
+    ];
+    for (result, c) in &checks {
+        out.counter(
+            "annhandler_anns_validation_total",
+            "Announcements which were validated or skipped, depending on trust in the miner",
+            &[("result", result)],
+            c.get() as f64,
+        );
+    }
+    let batches = [
+        ("failed", &ah.batches_failed),
+        ("overload", &ah.overloads),
+        ("timeout", &ah.timeouts),
+    ];
+    for (result, c) in &batches {
+        out.counter(
+            "annhandler_batches_rejected_total",
+            "Announcement batches which were not processed, by reason",
+            &[("reason", result)],
+            c.get() as f64,
+        );
+    }
+    let limited = [
+        ("rate_limited", &ah.limits.rate_limited),
+        ("banned", &ah.limits.banned),
+    ];
+    for (result, c) in &limited {
+        out.counter(
+            "annhandler_batches_refused_total",
+            "Announcement batches refused because of the source's rate limit or ban",
+            &[("reason", result)],
+            c.get() as f64,
+        );
+    }
+    out.counter(
+        "annhandler_bans_total",
+        "Sources which have been banned for submitting too many bad announcements",
+        &[],
+        ah.limits.bans.get() as f64,
+    );
+    let (ips, paytos) = ah.limits.banned_count(util::now_ms());
+    let h = "Sources which are banned right now";
+    out.gauge("annhandler_banned", h, &[("kind", "ip")], ips as f64);
+    out.gauge(
+        "annhandler_banned",
+        h,
+        &[("kind", "ip_payto")],
+        paytos as f64,
+    );
+    out.gauge(
+        "annhandler_queue_length",
+        "Announcement batches waiting to be validated",
+        &[],
+        ah.submit_recv.len() as f64,
+    );
+    out.gauge(
+        "annhandler_ann_file_buffer",
+        "Accepted announcements not yet written to an ann file",
+        &[],
+        ah.ann_file_buf.lock().len() as f64,
+    );
+    ah.sprayer.metrics(out);
 }
 
 pub async fn start(ah: &AnnHandler) {
+    let anns = warp::get()
+        .and(warp::path("anns"))
+        .and((|ah: AnnHandler| warp::any().map(move || ah.clone()))(
+            ah.clone(),
+        ))
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(handle_anns_auth)
+        .untuple_one()
+        .and(warp::fs::dir(ah.anndir.clone()));
+
     let sub = warp::post()
         .and(warp::path("submit"))
         .and(warp::path::end())
@@ -563,11 +1280,12 @@ pub async fn start(ah: &AnnHandler) {
             ah.clone(),
         ))
         .and(warp::filters::addr::remote())
-        .and(warp::body::bytes())
-        //.and(warp::header::<usize>("content-length"))
+        .and(warp::header::optional::<u64>("content-length"))
         .and(warp::header::<u32>("x-pc-sver"))
         .and(warp::header::<i32>("x-pc-worknum"))
         .and(warp::header::<String>("x-pc-payto"))
+        // Streamed so that bad posts can be turned away before they are all read
+        .and(warp::body::stream())
         .and_then(handle_submit);
 
     // Pipe new work updates through to a crossbeam channel
@@ -578,7 +1296,11 @@ pub async fn start(ah: &AnnHandler) {
     )
     .await;
 
-    packetcrypt_util::async_spawn!(ah, { warp::serve(sub).run(ah.sockaddr).await });
+    packetcrypt_util::async_spawn!(ah, { warp::serve(sub.or(anns)).run(ah.sockaddr).await });
+
+    packetcrypt_util::async_spawn!(ah, {
+        ann_file_loop(&ah).await;
+    });
 
     for i in 0..(ah.cfg.num_workers) {
         let g = ah.clone();
@@ -592,9 +1314,9 @@ pub async fn start(ah: &AnnHandler) {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use hex_literal::hex;
-    use packetcrypt_sys::{check_ann, PacketCryptAnn, ValidateCtx};
-    use packetcrypt_util::{hash, util};
+    use packetcrypt_pool::paymakerclient::PaymakerClientCfg;
 
     static ANN: [u8; 1024] = hex!(
         "
@@ -656,4 +1378,181 @@ mod tests {
             Err(x) => panic!("Checkanns failed with {}", x),
         }
     }
+
+    fn ann(f: impl Fn(&mut [u8])) -> Option<PacketCryptAnn> {
+        let mut bytes = ANN;
+        f(&mut bytes[..]);
+        Some(PacketCryptAnn {
+            bytes: util::aligned_bytes(&bytes, 4),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_process_batch() {
+        let dir = util::TempDir::new("annhandler");
+        let dir = dir.path();
+        let pc = poolclient::new("http://localhost", 1, 10);
+        let pmc = paymakerclient::new(
+            &pc,
+            PaymakerClientCfg {
+                paylogdir: format!("{}/paylogs", dir),
+                password: String::new(),
+                paylog_submit_every_ms: 10_000,
+            },
+        )
+        .await
+        .unwrap();
+        let cfg = AnnHandlerCfg {
+            skip_check_chance: 1.0,
+            bind_pub: "127.0.0.1:0".into(),
+            bind_pvt: "127.0.0.1:0".into(),
+            files_to_keep: 1,
+            ..Default::default()
+        };
+        let g = super::new(&pc, &pmc, cfg, dir).await.unwrap();
+
+        let good = ann(|_| ()).unwrap();
+        let mut conf = Config {
+            ann_version: good.version(),
+            handler_num: 0,
+            handler_count: 1,
+            signing_key: Some(good.signing_key().try_into().unwrap()),
+            parent_block_hash: hex!(
+                "255094b788fe98be51bafb4d941d507d4d5a949c751d1f68dfad0715215e1e48"
+            ),
+            min_work: good.work_bits(),
+            parent_block_height: good.parent_block_height(),
+        };
+        get_output(&g, conf.parent_block_height).lock().config = conf;
+        let pnr = AnnPostMeta {
+            sver: 0,
+            next_block_height: conf.parent_block_height + 1,
+            pay_to: "pkt1x".into(),
+            remote_addr: None,
+        };
+        // Fully trusted, so every ann which can be is skipped until one fails
+        g.trust.verified(util::now_ms(), &pnr.pay_to, 1 << 20);
+
+        let mut w = Worker {
+            global: g.clone(),
+            random: 0,
+            payto_regex: Regex::new(r"^[a-zA-Z0-9]+$").unwrap(),
+            anns: vec![
+                Some(good.clone()),
+                ann(|b| b[13] ^= 1),
+                ann(|b| b[1000] ^= 1),
+                Some(good.clone()),
+            ],
+            vctx: ValidateCtx::default(),
+        };
+        let dedups = mk_dedups(&mut w);
+        // The bad ann is the only one not skipped, the good one comes before it
+        w.random = dedups[2] as u8 ^ 0xff;
+        assert_ne!(dedups[0] as u8, dedups[2] as u8);
+        let batch = std::mem::replace(&mut w.anns, vec![Some(good.clone())]);
+
+        // On its own, the good ann gets the benefit of the doubt
+        let mut res = AnnsEvent::default();
+        let mut rejected = BTreeMap::new();
+        process_batch(&mut w, &mut res, &pnr, &conf, &mut rejected).unwrap();
+        assert_eq!(res.accepted, 1);
+        assert_eq!((g.anns_checked.take(), g.anns_skipped.take()), (0, 1));
+        get_output(&g, conf.parent_block_height)
+            .lock()
+            .dedup_tbl
+            .clear();
+        g.ann_file_buf.lock().clear();
+
+        w.anns = batch;
+        let mut res = AnnsEvent::default();
+        let mut rejected = BTreeMap::new();
+        process_batch(&mut w, &mut res, &pnr, &conf, &mut rejected).unwrap();
+        assert_eq!(
+            rejected.into_iter().collect::<Vec<_>>(),
+            vec![
+                (AnnRejectReason::WrongParentHeight, 1),
+                (AnnRejectReason::Duplicate, 1),
+                (AnnRejectReason::Invalid, 1),
+            ]
+        );
+        assert_eq!(
+            (res.accepted, res.dup, res.inval, res.bad_hash, res.unsigned),
+            (1, 1, 2, 0, 0)
+        );
+        assert_eq!(res.target, good.work_bits());
+        // The good ann was checked after all
+        assert_eq!(g.anns_checked.take(), 3);
+        assert_eq!(g.anns_skipped.take(), 0);
+        assert_eq!(g.anns_check_failed.take(), 1);
+        assert_eq!(g.ann_file_buf.lock().len(), 1);
+
+        // Already have that one
+        w.anns = vec![Some(good.clone())];
+        let mut res = AnnsEvent::default();
+        let mut rejected = BTreeMap::new();
+        process_batch(&mut w, &mut res, &pnr, &conf, &mut rejected).unwrap();
+        assert_eq!((res.accepted, res.dup), (0, 1));
+
+        // Too late
+        conf.parent_block_height -= 1;
+        w.anns = vec![Some(good)];
+        let mut res = AnnsEvent::default();
+        let e = process_batch(&mut w, &mut res, &pnr, &conf, &mut rejected).unwrap_err();
+        assert!(e.downcast_ref::<WrongBlock>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_read_anns() {
+        // Pieces which don't line up with the anns
+        let pieces: Vec<Result<bytes::Bytes, warp::Error>> = vec![
+            Ok(bytes::Bytes::from(vec![0_u8; 1000])),
+            Ok(bytes::Bytes::from(vec![
+                0_u8;
+                (2 * CHUNK_ANNS + 1) * 1024 - 1000
+            ])),
+        ];
+        let mut chunks = Vec::new();
+        let anns = read_anns(tokio::stream::iter(pieces), 1024 * 1024, |b| {
+            chunks.push(b.len())
+        })
+        .await
+        .unwrap();
+        assert_eq!(anns, 2 * CHUNK_ANNS + 1);
+        assert_eq!(chunks, vec![CHUNK_ANNS * 1024, CHUNK_ANNS * 1024, 1024]);
+
+        // An empty post is still passed on so that it gets a reply
+        let mut chunks = Vec::new();
+        let pieces: Vec<Result<bytes::Bytes, warp::Error>> = Vec::new();
+        let anns = read_anns(tokio::stream::iter(pieces), 1024, |b| chunks.push(b.len()))
+            .await
+            .unwrap();
+        assert_eq!((anns, chunks), (0, vec![0]));
+
+        let pieces: Vec<Result<bytes::Bytes, warp::Error>> =
+            vec![Ok(bytes::Bytes::from(vec![0_u8; 1500]))];
+        let err = read_anns(tokio::stream::iter(pieces), 4096, |_| ())
+            .await
+            .unwrap_err();
+        assert_eq!((err.1, err.2), (StatusCode::BAD_REQUEST, 2));
+    }
+
+    #[tokio::test]
+    async fn test_read_anns_too_many() {
+        // A chunked upload with no length which never ends, it is refused as soon as
+        // it goes over the limit.
+        let read = std::sync::atomic::AtomicUsize::new(0);
+        let body = tokio::stream::iter(std::iter::repeat(()).map(|_| {
+            read.fetch_add(1, atomic::Ordering::Relaxed);
+            Ok::<_, warp::Error>(bytes::Bytes::from(vec![0_u8; 4096]))
+        }));
+        let max_anns = 2 * CHUNK_ANNS;
+        let mut chunks = 0;
+        let err = read_anns(body, max_anns * 1024, |_| chunks += 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.1, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(err.2, max_anns);
+        assert_eq!(read.load(atomic::Ordering::Relaxed), max_anns / 4 + 1);
+        assert_eq!(chunks, 2);
+    }
 }
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Append only log of the dedup hashes of the anns which were accepted for one parent
+// block, so that a restarted handler does not accept (and pay for) the same anns twice.
+//
+// The file is the block which it is for, followed by the 8 byte hashes as they are
+// accepted:
+//   [ parent block height (i32 LE) ][ parent block hash ][ hash (u64 LE) ]...
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+
+const HEADER_LEN: usize = 4 + 32;
+
+#[derive(Debug)]
+pub struct DedupLog {
+    path: String,
+    file: Option<File>,
+}
+impl DedupLog {
+    pub fn new(dir: &str, slot: usize) -> DedupLog {
+        DedupLog {
+            path: format!("{}/dedup_{}.bin", dir, slot),
+            file: None,
+        }
+    }
+
+    /// Read back what was logged, returns the height and hash of the block and the
+    /// dedup hashes, or None if there is no log.
+    pub fn load(&self) -> Result<Option<(i32, [u8; 32], HashSet<u64>)>> {
+        let mut bytes = Vec::new();
+        match File::open(&self.path) {
+            Ok(mut f) => f.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("File::open({})", self.path)),
+        };
+        if bytes.len() < HEADER_LEN {
+            bail!("{} is truncated", self.path);
+        }
+        let height = i32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let hash = bytes[4..HEADER_LEN].try_into().unwrap();
+        // A partial entry at the end is from a write which was cut off
+        let dedups = bytes[HEADER_LEN..]
+            .chunks_exact(8)
+            .map(|h| u64::from_le_bytes(h.try_into().unwrap()))
+            .collect();
+        Ok(Some((height, hash, dedups)))
+    }
+
+    /// Start a new log for a different block.
+    pub fn reset(&mut self, height: i32, hash: &[u8; 32]) -> Result<()> {
+        self.file = None;
+        let mut f =
+            File::create(&self.path).with_context(|| format!("File::create({})", self.path))?;
+        let mut header = [0_u8; HEADER_LEN];
+        header[..4].copy_from_slice(&height.to_le_bytes());
+        header[4..].copy_from_slice(hash);
+        f.write_all(&header)?;
+        self.file = Some(f);
+        Ok(())
+    }
+
+    /// Carry on with the log which was loaded.
+    pub fn resume(&mut self) -> Result<()> {
+        let f = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("OpenOptions::open({})", self.path))?;
+        // Drop any partial entry so that what we append lines up
+        let len = f.metadata()?.len();
+        let extra = len.saturating_sub(HEADER_LEN as u64) % 8;
+        if extra > 0 {
+            f.set_len(len - extra)?;
+        }
+        self.file = Some(f);
+        Ok(())
+    }
+
+    pub fn append(&mut self, dedups: &HashSet<u64>) -> Result<()> {
+        if dedups.is_empty() {
+            return Ok(());
+        }
+        let f = if let Some(f) = &mut self.file {
+            f
+        } else {
+            bail!("{} is not open", self.path);
+        };
+        let mut buf = Vec::with_capacity(dedups.len() * 8);
+        for d in dedups {
+            buf.extend_from_slice(&d.to_le_bytes());
+        }
+        // One write so that a crash leaves at most one partial entry
+        f.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_log() {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        let slot = std::process::id() as usize;
+        let mut log = DedupLog::new(dir, slot);
+        assert!(log.load().unwrap().is_none());
+
+        log.reset(100, &[7; 32]).unwrap();
+        log.append(&[1, 2].iter().cloned().collect()).unwrap();
+        let (height, hash, dedups) = log.load().unwrap().unwrap();
+        assert_eq!((height, hash), (100, [7; 32]));
+        assert_eq!(dedups, [1, 2].iter().cloned().collect());
+
+        // Cut off in the middle of an entry, then restarted
+        log.file.as_mut().unwrap().write_all(&[9; 3]).unwrap();
+        let mut log = DedupLog::new(dir, slot);
+        assert_eq!(log.load().unwrap().unwrap().2.len(), 2);
+        log.resume().unwrap();
+        log.append(&[3].iter().cloned().collect()).unwrap();
+        assert_eq!(
+            log.load().unwrap().unwrap().2,
+            [1, 2, 3].iter().cloned().collect()
+        );
+
+        log.reset(101, &[8; 32]).unwrap();
+        assert!(log.load().unwrap().unwrap().2.is_empty());
+        std::fs::remove_file(&log.path).unwrap();
+    }
+}
@@ -1 +1,4 @@
 pub mod annhandler;
+mod dedup;
+mod limits;
+mod trust;
@@ -0,0 +1,389 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Limits on ann submissions per source, a source being a remote IP or a pay_to address
+// submitting from a remote IP. The pay_to is whatever the client says it is, so it is
+// never limited or banned on its own, otherwise anyone could get someone else's
+// address banned by submitting garbage in its name.
+//
+// Each source has a token bucket counted in anns, a batch is let in as long as the
+// bucket is not in debt and then it is charged in full, so that a batch of any size
+// can get in. Sources are also scored on how many of their anns were bad, with older
+// results decaying away, and a source with too high a ratio of bad anns is banned for
+// a while.
+use log::info;
+use packetcrypt_pool::poolcfg::AnnHandlerCfg;
+use packetcrypt_util::metrics::Counter;
+use parking_lot::Mutex as MutexB; // blocking
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Bucket holds this many seconds worth of anns
+const BURST_SECS: f64 = 10.0;
+
+// Bad and good counts halve over this time
+const SCORE_HALF_LIFE_MS: f64 = 60_000.0;
+
+// Don't judge a source on fewer than this many anns
+const BAN_MIN_ANNS: f64 = 1024.0;
+
+const DEFAULT_BAN_SECS: u64 = 600;
+
+// Forget about sources which have not submitted anything in this long
+const IDLE_MS: u64 = 600_000;
+const SWEEP_EVERY_MS: u64 = 60_000;
+
+// Longer than any valid pay_to, those are refused anyway so we don't keep track of them
+const MAX_PAYTO_LEN: usize = 63;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimitsCfg {
+    ip_anns_per_sec: f64,
+    payto_anns_per_sec: f64,
+    ban_bad_ratio: f64,
+    ban_secs: u64,
+}
+impl LimitsCfg {
+    pub fn new(cfg: &AnnHandlerCfg) -> LimitsCfg {
+        LimitsCfg {
+            ip_anns_per_sec: cfg.ip_anns_per_sec.unwrap_or(0.0),
+            payto_anns_per_sec: cfg.payto_anns_per_sec.unwrap_or(0.0),
+            ban_bad_ratio: cfg.ban_bad_ratio.unwrap_or(0.0),
+            ban_secs: cfg.ban_secs.unwrap_or(DEFAULT_BAN_SECS),
+        }
+    }
+    fn enabled(&self) -> bool {
+        self.ip_anns_per_sec > 0.0 || self.payto_anns_per_sec > 0.0 || self.ban_bad_ratio > 0.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    RateLimited,
+    Banned,
+}
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Refusal::RateLimited => "rate limited",
+            Refusal::Banned => "banned",
+        })
+    }
+}
+
+struct Source {
+    tokens: f64,
+    last_ms: u64,
+    good: f64,
+    bad: f64,
+    scored_ms: u64,
+    banned_until_ms: u64,
+}
+impl Source {
+    fn new(now_ms: u64, anns_per_sec: f64) -> Source {
+        Source {
+            tokens: anns_per_sec * BURST_SECS,
+            last_ms: now_ms,
+            good: 0.0,
+            bad: 0.0,
+            scored_ms: now_ms,
+            banned_until_ms: 0,
+        }
+    }
+
+    fn refill(&mut self, now_ms: u64, anns_per_sec: f64) {
+        let secs = now_ms.saturating_sub(self.last_ms) as f64 / 1000.0;
+        self.tokens = f64::min(self.tokens + anns_per_sec * secs, anns_per_sec * BURST_SECS);
+        self.last_ms = now_ms;
+    }
+
+    // Returns true if the source should now be banned
+    fn score(&mut self, now_ms: u64, good: u64, bad: u64, bad_ratio: f64) -> bool {
+        let ms = now_ms.saturating_sub(self.scored_ms) as f64;
+        let decay = 0.5_f64.powf(ms / SCORE_HALF_LIFE_MS);
+        self.good = self.good * decay + good as f64;
+        self.bad = self.bad * decay + bad as f64;
+        self.scored_ms = now_ms;
+        let total = self.good + self.bad;
+        bad_ratio > 0.0 && total >= BAN_MIN_ANNS && self.bad / total > bad_ratio
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct IpPayTo(IpAddr, String);
+impl std::fmt::Display for IpPayTo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} from {}", self.1, self.0)
+    }
+}
+
+struct Sources<K> {
+    m: HashMap<K, Source>,
+    anns_per_sec: f64,
+}
+impl<K: std::hash::Hash + Eq + Clone + std::fmt::Display> Sources<K> {
+    fn check(&mut self, now_ms: u64, k: &K) -> Result<(), Refusal> {
+        let anns_per_sec = self.anns_per_sec;
+        let s = if let Some(s) = self.m.get_mut(k) {
+            s
+        } else {
+            return Ok(());
+        };
+        if s.banned_until_ms > now_ms {
+            return Err(Refusal::Banned);
+        }
+        if anns_per_sec > 0.0 {
+            s.refill(now_ms, anns_per_sec);
+            if s.tokens < 0.0 {
+                return Err(Refusal::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    fn charge(&mut self, now_ms: u64, k: &K, anns: usize) {
+        let anns_per_sec = self.anns_per_sec;
+        let s = self
+            .m
+            .entry(k.clone())
+            .or_insert_with(|| Source::new(now_ms, anns_per_sec));
+        if anns_per_sec > 0.0 {
+            s.refill(now_ms, anns_per_sec);
+            s.tokens -= anns as f64;
+        }
+        s.last_ms = now_ms;
+    }
+
+    // Returns true if this made a new ban
+    fn score(&mut self, now_ms: u64, k: &K, good: u64, bad: u64, cfg: &LimitsCfg) -> bool {
+        let s = if let Some(s) = self.m.get_mut(k) {
+            s
+        } else {
+            return false;
+        };
+        if !s.score(now_ms, good, bad, cfg.ban_bad_ratio) {
+            return false;
+        }
+        info!(
+            "Banning {} for {} seconds, {:.0}% of its last {:.0} anns were bad",
+            k,
+            cfg.ban_secs,
+            s.bad * 100.0 / (s.good + s.bad),
+            s.good + s.bad
+        );
+        s.banned_until_ms = now_ms + cfg.ban_secs * 1000;
+        s.good = 0.0;
+        s.bad = 0.0;
+        true
+    }
+
+    fn sweep(&mut self, now_ms: u64) {
+        self.m
+            .retain(|_, s| s.banned_until_ms > now_ms || s.last_ms + IDLE_MS > now_ms);
+    }
+
+    fn banned(&self, now_ms: u64) -> usize {
+        self.m
+            .values()
+            .filter(|s| s.banned_until_ms > now_ms)
+            .count()
+    }
+}
+
+struct LimitsMut {
+    cfg: LimitsCfg,
+    ips: Sources<IpAddr>,
+    paytos: Sources<IpPayTo>,
+    last_sweep_ms: u64,
+}
+impl LimitsMut {
+    fn charge(&mut self, now_ms: u64, ip: IpAddr, pay_to: Option<&IpPayTo>, anns: usize) {
+        self.ips.charge(now_ms, &ip, anns);
+        if let Some(pay_to) = pay_to {
+            self.paytos.charge(now_ms, pay_to, anns);
+        }
+    }
+}
+
+// Without an IP there is nothing to go on, a pay_to which is too long is refused anyway
+// so we don't keep track of it
+fn ip_pay_to(ip: Option<IpAddr>, pay_to: &str) -> Option<IpPayTo> {
+    match ip {
+        Some(ip) if pay_to.len() <= MAX_PAYTO_LEN => Some(IpPayTo(ip, pay_to.to_owned())),
+        _ => None,
+    }
+}
+
+pub struct Limits {
+    m: MutexB<LimitsMut>,
+    pub rate_limited: Counter,
+    pub banned: Counter,
+    pub bans: Counter,
+}
+impl Limits {
+    pub fn new(cfg: LimitsCfg) -> Limits {
+        Limits {
+            m: MutexB::new(LimitsMut {
+                cfg,
+                ips: Sources {
+                    m: HashMap::new(),
+                    anns_per_sec: cfg.ip_anns_per_sec,
+                },
+                paytos: Sources {
+                    m: HashMap::new(),
+                    anns_per_sec: cfg.payto_anns_per_sec,
+                },
+                last_sweep_ms: 0,
+            }),
+            rate_limited: Counter::default(),
+            banned: Counter::default(),
+            bans: Counter::default(),
+        }
+    }
+
+    pub fn set_cfg(&self, cfg: LimitsCfg) {
+        let mut m = self.m.lock();
+        m.cfg = cfg;
+        m.ips.anns_per_sec = cfg.ip_anns_per_sec;
+        m.paytos.anns_per_sec = cfg.payto_anns_per_sec;
+    }
+
+    /// Whether a batch of anns from this source may be submitted now, if so then it
+    /// is counted against the source's rate limits. Sources without an IP are not
+    /// limited.
+    pub fn check(
+        &self,
+        now_ms: u64,
+        ip: Option<IpAddr>,
+        pay_to: &str,
+        anns: usize,
+    ) -> Result<(), Refusal> {
+        let mut m = self.m.lock();
+        let ip = match ip {
+            Some(ip) if m.cfg.enabled() => ip,
+            _ => return Ok(()),
+        };
+        if m.last_sweep_ms + SWEEP_EVERY_MS < now_ms {
+            m.ips.sweep(now_ms);
+            m.paytos.sweep(now_ms);
+            m.last_sweep_ms = now_ms;
+        }
+        let pay_to = ip_pay_to(Some(ip), pay_to);
+        let mut res = m.ips.check(now_ms, &ip);
+        if let Some(pay_to) = &pay_to {
+            res = res.and(m.paytos.check(now_ms, pay_to));
+        }
+        match res {
+            Ok(()) => m.charge(now_ms, ip, pay_to.as_ref(), anns),
+            Err(Refusal::RateLimited) => self.rate_limited.add(1),
+            Err(Refusal::Banned) => self.banned.add(1),
+        }
+        res
+    }
+
+    /// Count more anns against the source's rate limits, for when the size of the
+    /// batch was not known at the time of check().
+    pub fn charge(&self, now_ms: u64, ip: Option<IpAddr>, pay_to: &str, anns: usize) {
+        let mut m = self.m.lock();
+        let ip = match ip {
+            Some(ip) if m.cfg.enabled() => ip,
+            _ => return,
+        };
+        let pay_to = ip_pay_to(Some(ip), pay_to);
+        m.charge(now_ms, ip, pay_to.as_ref(), anns);
+    }
+
+    /// Record how many anns from a source turned out to be good and bad.
+    pub fn score(&self, now_ms: u64, ip: Option<IpAddr>, pay_to: &str, good: u64, bad: u64) {
+        let mut m = self.m.lock();
+        let m = &mut *m;
+        if m.cfg.ban_bad_ratio <= 0.0 {
+            return;
+        }
+        let mut bans = 0;
+        if let Some(ip) = &ip {
+            bans += m.ips.score(now_ms, ip, good, bad, &m.cfg) as u64;
+        }
+        if let Some(pay_to) = ip_pay_to(ip, pay_to) {
+            bans += m.paytos.score(now_ms, &pay_to, good, bad, &m.cfg) as u64;
+        }
+        self.bans.add(bans);
+    }
+
+    /// The number of IPs, and of pay_to addresses from a given IP, which are banned
+    /// right now.
+    pub fn banned_count(&self, now_ms: u64) -> (usize, usize) {
+        let m = self.m.lock();
+        (m.ips.banned(now_ms), m.paytos.banned(now_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(ip_anns_per_sec: f64, ban_bad_ratio: f64) -> Limits {
+        Limits::new(LimitsCfg {
+            ip_anns_per_sec,
+            payto_anns_per_sec: 0.0,
+            ban_bad_ratio,
+            ban_secs: 10,
+        })
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let l = limits(100.0, 0.0);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        let other = Some("10.0.0.2".parse().unwrap());
+        // A full bucket is 1000 anns, one batch can go over
+        assert_eq!(l.check(1000, ip, "pkt1x", 1024), Ok(()));
+        assert_eq!(l.check(1000, ip, "pkt1x", 1), Err(Refusal::RateLimited));
+        assert_eq!(l.check(1000, other, "pkt1x", 1), Ok(()));
+        // 24 anns in debt, paid off after 240ms
+        assert_eq!(l.check(1200, ip, "pkt1x", 1), Err(Refusal::RateLimited));
+        assert_eq!(l.check(1240, ip, "pkt1x", 1), Ok(()));
+        assert_eq!(l.rate_limited.get(), 2);
+        // Size only known afterwards
+        assert_eq!(l.check(1260, ip, "pkt1x", 0), Ok(()));
+        l.charge(1260, ip, "pkt1x", 2);
+        assert_eq!(l.check(1260, ip, "pkt1x", 0), Err(Refusal::RateLimited));
+    }
+
+    #[test]
+    fn test_ban() {
+        let l = limits(0.0, 0.5);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        assert_eq!(l.check(1000, ip, "pkt1x", 1024), Ok(()));
+        // Not enough to judge yet
+        l.score(1000, ip, "pkt1x", 0, 1000);
+        assert_eq!(l.check(1000, ip, "pkt1y", 1024), Ok(()));
+        l.score(1000, ip, "pkt1y", 1000, 24);
+        assert_eq!(l.bans.get(), 1);
+        assert_eq!(l.check(2000, ip, "pkt1z", 1), Err(Refusal::Banned));
+        assert_eq!(l.check(2000, None, "pkt1y", 1), Ok(()));
+        assert_eq!(l.banned_count(2000), (1, 0));
+        assert_eq!(l.check(11_000, ip, "pkt1z", 1), Ok(()));
+    }
+
+    #[test]
+    fn test_ban_payto() {
+        let l = limits(0.0, 0.5);
+        let ip = Some("10.0.0.1".parse().unwrap());
+        let other = Some("10.0.0.2".parse().unwrap());
+        assert_eq!(l.check(1000, ip, "pkt1x", 2048), Ok(()));
+        assert_eq!(l.check(1000, ip, "pkt1y", 1100), Ok(()));
+        assert_eq!(l.check(1000, other, "pkt1y", 1), Ok(()));
+        l.score(1000, ip, "pkt1x", 2048, 0);
+        l.score(1000, ip, "pkt1y", 0, 1100);
+        // Only pkt1y from that IP is banned, the IP itself is mostly good
+        assert_eq!(l.bans.get(), 1);
+        assert_eq!(l.banned_count(2000), (0, 1));
+        assert_eq!(l.check(2000, ip, "pkt1y", 1), Err(Refusal::Banned));
+        assert_eq!(l.check(2000, ip, "pkt1x", 1), Ok(()));
+        assert_eq!(l.check(2000, other, "pkt1y", 1), Ok(()));
+        // Nobody can be banned without an IP
+        l.score(2000, None, "pkt1x", 0, 5000);
+        assert_eq!(l.check(2000, None, "pkt1x", 1), Ok(()));
+        assert_eq!(l.bans.get(), 1);
+    }
+}
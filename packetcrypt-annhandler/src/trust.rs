@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// How much we trust each miner (pay_to address) to submit valid anns, this decides
+// how much of skip_check_chance applies to them.
+//
+// A miner we don't know gets every ann checked, and as more of its anns pass the
+// check, more of them are skipped until it gets the full skip_check_chance. Any ann
+// which fails the check takes the trust away and the miner is checked in full for a
+// while, so cheating costs more than it can win.
+use parking_lot::Mutex as MutexB; // blocking
+use std::collections::HashMap;
+
+// Anns which have to pass the check before a miner is fully trusted
+const TRUST_ANNS: f64 = 65536.0;
+
+// After a failed check, everything from the miner is checked for this long
+const DISTRUST_MS: u64 = 60 * 60 * 1000;
+
+// Forget about miners which have not submitted anything in this long
+const IDLE_MS: u64 = 24 * 60 * 60 * 1000;
+const SWEEP_EVERY_MS: u64 = 10 * 60 * 1000;
+
+#[derive(Default)]
+struct Miner {
+    verified: u64,
+    distrust_until_ms: u64,
+    last_ms: u64,
+}
+
+#[derive(Default)]
+struct TrustMut {
+    miners: HashMap<String, Miner>,
+    last_sweep_ms: u64,
+}
+
+#[derive(Default)]
+pub struct Trust {
+    m: MutexB<TrustMut>,
+}
+impl Trust {
+    /// How much this miner is trusted, between 0 (check everything) and 1.
+    pub fn level(&self, now_ms: u64, pay_to: &str) -> f64 {
+        match self.m.lock().miners.get(pay_to) {
+            Some(m) if m.distrust_until_ms <= now_ms => {
+                f64::min(m.verified as f64 / TRUST_ANNS, 1.0)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Some anns from the miner were checked and all of them were valid.
+    pub fn verified(&self, now_ms: u64, pay_to: &str, anns: u64) {
+        let mut t = self.m.lock();
+        if t.last_sweep_ms + SWEEP_EVERY_MS < now_ms {
+            t.miners
+                .retain(|_, m| m.distrust_until_ms > now_ms || m.last_ms + IDLE_MS > now_ms);
+            t.last_sweep_ms = now_ms;
+        }
+        let m = t.miners.entry(pay_to.to_owned()).or_default();
+        m.verified += anns;
+        m.last_ms = now_ms;
+    }
+
+    /// An ann from the miner failed the check, returns false if it was already
+    /// being checked in full because of an earlier one.
+    pub fn failed(&self, now_ms: u64, pay_to: &str) -> bool {
+        let mut t = self.m.lock();
+        let m = t.miners.entry(pay_to.to_owned()).or_default();
+        let was_trusted = m.distrust_until_ms <= now_ms;
+        m.verified = 0;
+        m.distrust_until_ms = now_ms + DISTRUST_MS;
+        m.last_ms = now_ms;
+        was_trusted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust() {
+        let t = Trust::default();
+        assert_eq!(t.level(0, "pkt1x"), 0.0);
+        t.verified(0, "pkt1x", TRUST_ANNS as u64 / 2);
+        assert_eq!(t.level(0, "pkt1x"), 0.5);
+        t.verified(0, "pkt1x", TRUST_ANNS as u64);
+        assert_eq!(t.level(0, "pkt1x"), 1.0);
+        assert!(t.failed(1000, "pkt1x"));
+        assert!(!t.failed(1000, "pkt1x"));
+        assert_eq!(t.level(1000, "pkt1x"), 0.0);
+        t.verified(2000, "pkt1x", TRUST_ANNS as u64);
+        assert_eq!(t.level(2000, "pkt1x"), 0.0);
+        assert_eq!(t.level(1000 + DISTRUST_MS, "pkt1x"), 1.0);
+    }
+}
@@ -1,29 +1,37 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
 use crate::annminer::{self, AnnResult};
+use crate::spool::Spool;
 use anyhow::{bail, Result};
 use core::time::Duration;
 use log::{debug, info, trace, warn};
 use packetcrypt_sys::PacketCryptAnn;
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
-use packetcrypt_util::protocol::{AnnPostReply, BlockInfo};
-use packetcrypt_util::util;
+use packetcrypt_util::protocol::{AnnPostReply, AnnRejectReason, BlockInfo};
+use packetcrypt_util::metrics::{Counter, Gauge, MetricsOut};
+use packetcrypt_util::{hash, util};
+use serde::Deserialize;
 use std::cmp::max;
+use std::convert::TryInto;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver};
 
 const RECENT_WORK_BUF: usize = 8;
 const MAX_ANN_BATCH_SIZE: usize = 1024;
 const MAX_MS_BETWEEN_POSTS: u64 = 10_000;
 
+#[derive(Clone)]
 struct AnnBatch {
     parent_block_height: i32,
     create_time: u64,
     anns: Vec<PacketCryptAnn>,
+    // Sent again because it went to the wrong handler the first time
+    rerouted: bool,
 }
 
 struct Handler {
@@ -46,16 +54,39 @@ struct PoolMut {
     currently_mining: i32,
     recent_work: [Option<BlockInfo>; RECENT_WORK_BUF],
     handlers: Vec<Arc<Handler>>,
+    // Anns for blocks before this one are no longer accepted by the pool
+    oldest_accepted: i32,
 }
+// What a pool wants us to mine
+#[derive(Clone, Copy)]
+struct AnnJob {
+    // Reversed, the way the miner wants it
+    parent_block_hash: [u8; 32],
+    parent_block_height: i32,
+    target: u32,
+    signing_key: Option<[u8; 32]>,
+}
+
 struct Pool {
     primary: bool,
+    // Share of the mining which this pool gets, if the pools are weighted
+    weight: Option<f64>,
+    index: usize,
+    // The latest job from this pool, in weighted mode
+    job: Mutex<Option<AnnJob>>,
     pcli: PoolClient,
     m: Mutex<PoolMut>,
     inflight_anns: AtomicUsize,
-    lost_anns: AtomicUsize,
-    accepted_anns: AtomicUsize,
-    rejected_anns: AtomicUsize,
-    overload_anns: AtomicUsize,
+    lost_anns: Counter,
+    accepted_anns: Counter,
+    rejected_anns: Counter,
+    overload_anns: Counter,
+    rejected_reasons: Mutex<BTreeMap<AnnRejectReason, Counter>>,
+    // Batches which a handler said belong to another handler, by the url of the handler,
+    // waiting for an updated list of handlers
+    misrouted: Mutex<VecDeque<(String, AnnBatch)>>,
+    spool: Option<Arc<Spool>>,
+    last_upload_ok: AtomicU64,
 }
 
 struct AnnMineM {
@@ -66,50 +97,128 @@ struct AnnMineM {
 
 pub struct AnnMineS {
     m: tokio::sync::Mutex<AnnMineM>,
-    miner: annminer::AnnMiner,
+    // One miner per pool in weighted mode, otherwise one for the primary pool. Each one
+    // has cfg.workers threads, in weighted mode all but one of them are paused.
+    miners: Vec<annminer::AnnMiner>,
+    weighted: bool,
+    // Which pool's miner is running, in weighted mode
+    slice: Mutex<Option<usize>>,
     pools: Vec<Arc<Pool>>,
     cfg: AnnMineCfg,
     upload_num: AtomicUsize,
+    // Estimated encryptions per second, updated by the stats loop
+    hashrate: Gauge,
 }
 pub type AnnMine = Arc<AnnMineS>;
 
+// Field names in the config file are the same as the command line flags
+#[derive(Deserialize)]
 pub struct AnnMineCfg {
     pub pools: Vec<String>,
+    #[serde(skip)]
     pub miner_id: u32,
+    #[serde(rename = "threads")]
     pub workers: usize,
     pub uploaders: usize,
+    #[serde(rename = "paymentaddr")]
     pub pay_to: String,
+    #[serde(rename = "uploadtimeout")]
     pub upload_timeout: usize,
+    #[serde(rename = "mineold")]
     pub mine_old_anns: i32,
+    #[serde(rename = "spooldir", default)]
+    pub spool_dir: Option<String>,
+    #[serde(rename = "spoolmaxmb")]
+    pub spool_max_mb: u64,
+    #[serde(rename = "spoolmaxage")]
+    pub spool_max_age: u64,
 }
 
 const UPLOAD_CHANNEL_LEN: usize = 100;
 
 const PREFETCH_HISTORY_DEPTH: i32 = 6;
 
+// How often to look for spooled anns to send
+const SPOOL_CHECK_MS: u64 = 5_000;
+
+// Spooled anns are only sent once a handler has taken an upload this recently
+const SPOOL_UPLOAD_OK_MS: u64 = 30_000;
+
+// In weighted mode, every pool gets its share of each this many ms of mining
+const SLICE_CYCLE_MS: u64 = 10_000;
+
+// Split "url@weight" into the url and the weight
+fn parse_pool(pool: &str) -> Result<(&str, Option<f64>)> {
+    if let Some(i) = pool.rfind('@') {
+        // Could be user@host, in which case it's not a weight
+        if let Ok(w) = pool[i + 1..].parse::<f64>() {
+            if !w.is_finite() || w < 0.0 {
+                bail!("Invalid weight for pool [{}]", pool);
+            }
+            return Ok((&pool[..i], Some(w)));
+        }
+    }
+    Ok((pool, None))
+}
+
 pub async fn new(cfg: AnnMineCfg) -> Result<AnnMine> {
-    let pools = cfg
+    let parsed = cfg
         .pools
         .iter()
+        .map(|p| parse_pool(p))
+        .collect::<Result<Vec<_>>>()?;
+    let weighted = parsed.iter().any(|(_, w)| w.is_some());
+    if weighted {
+        if parsed.iter().any(|(_, w)| w.is_none()) {
+            bail!("Either every pool needs a weight (e.g. http://pool.example@0.5) or none");
+        } else if parsed.iter().all(|(_, w)| *w == Some(0.0)) {
+            bail!("At least one pool needs a weight above zero");
+        }
+    }
+    let pools = parsed
+        .into_iter()
         .zip(0..)
-        .map(|(x, i)| {
-            Arc::new(Pool {
+        .map(|((x, weight), i)| {
+            let spool = if let Some(dir) = &cfg.spool_dir {
+                // One spool per pool, named for the pool's url
+                let dir = format!("{}/{}", dir, hex::encode(&hash::compress32(x.as_bytes())[..8]));
+                let spool = Spool::open(&dir, cfg.spool_max_mb << 20, cfg.spool_max_age * 1000)?;
+                info!("Spooling unsent anns for {} in {}, {} anns waiting", x, dir, spool.anns());
+                Some(Arc::new(spool))
+            } else {
+                None
+            };
+            if let Some(w) = weight {
+                info!("Pool {} gets {} of the mining", x, w);
+            }
+            Ok(Arc::new(Pool {
                 primary: i == 0,
+                weight,
+                index: i,
+                job: Mutex::new(None),
                 m: Mutex::new(PoolMut {
                     currently_mining: -1,
                     recent_work: [None; RECENT_WORK_BUF],
                     handlers: Vec::new(),
+                    oldest_accepted: -1,
                 }),
                 pcli: poolclient::new(x, PREFETCH_HISTORY_DEPTH, 5),
                 inflight_anns: AtomicUsize::new(0),
-                lost_anns: AtomicUsize::new(0),
-                accepted_anns: AtomicUsize::new(0),
-                rejected_anns: AtomicUsize::new(0),
-                overload_anns: AtomicUsize::new(0),
-            })
+                lost_anns: Counter::default(),
+                accepted_anns: Counter::default(),
+                rejected_anns: Counter::default(),
+                overload_anns: Counter::default(),
+                rejected_reasons: Mutex::new(BTreeMap::new()),
+                misrouted: Mutex::new(VecDeque::new()),
+                spool,
+                last_upload_ok: AtomicU64::new(0),
+            }))
         })
-        .collect::<Vec<_>>();
-    let (miner, recv_ann) = annminer::new(cfg.miner_id, cfg.workers);
+        .collect::<Result<Vec<_>>>()?;
+    let (send_ann, recv_ann) = mpsc::unbounded_channel();
+    let miners = (0..if weighted { pools.len() } else { 1 })
+        .map(|i| annminer::with_sender(cfg.miner_id, cfg.workers, send_ann.clone(), i))
+        .collect();
     let (send_anns_per_second, recv_anns_per_second) = mpsc::channel(32);
     Ok(Arc::new(AnnMineS {
         m: tokio::sync::Mutex::new(AnnMineM {
@@ -117,10 +226,13 @@ pub async fn new(cfg: AnnMineCfg) -> Result<AnnMine> {
             send_anns_per_second: Some(send_anns_per_second),
             recv_anns_per_second: Some(recv_anns_per_second),
         }),
-        miner,
+        miners,
+        weighted,
+        slice: Mutex::new(None),
         pools,
         cfg,
         upload_num: AtomicUsize::new(0),
+        hashrate: Gauge::default(),
     }))
 }
 
@@ -143,6 +255,7 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
         update.conf.mine_old_anns as i32
     };
     pm.currently_mining = max(pm.currently_mining, top - mine_old);
+    pm.oldest_accepted = update.conf.current_height - 1 - update.conf.mine_old_anns as i32;
 
     // We're synced to the tip, begin mining (or start mining new anns)
     let job = if let Some(x) = pm.recent_work[(pm.currently_mining as usize) % RECENT_WORK_BUF] {
@@ -182,6 +295,7 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
                 create_time: util::now_ms(),
                 parent_block_height: job.header.height,
                 anns: Vec::new(),
+                rerouted: false,
             }),
             url: Arc::new(url.clone()),
             shutdown: AtomicBool::new(false),
@@ -215,7 +329,14 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
         pm.handlers = new_handlers;
     }
 
-    if !p.primary {
+    // Now that we have the latest list of handlers, anns which went to the wrong one
+    // can go to the right one
+    let misrouted = p.misrouted.lock().unwrap().drain(..).collect::<Vec<_>>();
+    for (from, batch) in misrouted {
+        route(p, &pm.handlers, batch, Some(&from));
+    }
+
+    if !p.primary && !am.weighted {
         // got an update from a secondary pool
         return out;
     }
@@ -228,24 +349,90 @@ fn update_work_cycle(am: &AnnMine, p: &Arc<Pool>, update: PoolUpdate) -> Vec<Arc
     };
 
     info!(
-        "Start mining with parent_block_height: [{} @ {}] old: [{}]",
+        "Start mining with parent_block_height: [{} @ {}] old: [{}]{}",
         hex::encode(job.header.hash),
         job.header.height,
-        mine_old
+        mine_old,
+        if am.weighted { format!(" for {}", p.pcli.url) } else { String::new() }
     );
     // Reverse the parent block hash because hashes in bitcoin are always expressed backward
     let mut rev_hash = job.header.hash;
     rev_hash.reverse();
+    let aj = AnnJob {
+        parent_block_hash: rev_hash,
+        parent_block_height: job.header.height,
+        target: ann_target,
+        signing_key: job.sig_key,
+    };
+    if am.weighted {
+        *p.job.lock().unwrap() = Some(aj);
+        // If it's not this pool's turn, slice_loop will start it when it is
+        let slice = am.slice.lock().unwrap();
+        if *slice == Some(p.index) {
+            start_job(&am.miners[p.index], &aj);
+        }
+    } else {
+        start_job(&am.miners[0], &aj);
+    }
+    out
+}
+
+fn start_job(miner: &annminer::AnnMiner, aj: &AnnJob) {
     if let Err(e) = annminer::start(
-        &am.miner,
-        rev_hash,
-        job.header.height,
-        ann_target,
-        job.sig_key,
+        miner,
+        aj.parent_block_hash,
+        aj.parent_block_height,
+        aj.target,
+        aj.signing_key,
     ) {
         warn!("Error starting annminer {}", e);
     }
-    out
+}
+
+// In weighted mode, the pools which have something to mine and how many ms each
+// one gets of the next SLICE_CYCLE_MS
+fn slices(am: &AnnMine) -> Vec<(&Arc<Pool>, u64)> {
+    let ready = am
+        .pools
+        .iter()
+        .filter(|p| p.weight.unwrap_or(0.0) > 0.0 && p.job.lock().unwrap().is_some())
+        .collect::<Vec<_>>();
+    let total = ready.iter().map(|p| p.weight.unwrap()).sum::<f64>();
+    ready
+        .into_iter()
+        .map(|p| (p, (SLICE_CYCLE_MS as f64 * p.weight.unwrap() / total) as u64))
+        .collect()
+}
+
+// Pause whichever miner is running and start this pool's one, which must have a job
+fn switch_slice(am: &AnnMine, p: &Pool) {
+    let mut slice = am.slice.lock().unwrap();
+    if *slice != Some(p.index) {
+        if let Some(prev) = *slice {
+            annminer::stop(&am.miners[prev]);
+        }
+        let aj = p.job.lock().unwrap().unwrap();
+        start_job(&am.miners[p.index], &aj);
+        *slice = Some(p.index);
+    }
+}
+
+// In weighted mode, switch the workers between the pools' miners so that each one
+// gets its share of the time. A miner which is paused and started again with the
+// same job carries on where it was.
+async fn slice_loop(am: &AnnMine) {
+    loop {
+        let slices = slices(am);
+        if slices.is_empty() {
+            // Nothing to mine yet
+            util::sleep_ms(1_000).await;
+            continue;
+        }
+        for (p, ms) in slices {
+            switch_slice(am, p);
+            util::sleep_ms(ms).await;
+        }
+    }
 }
 async fn update_work_loop(am: &AnnMine, p: Arc<Pool>) {
     let mut chan = poolclient::update_chan(&p.pcli).await;
@@ -273,6 +460,7 @@ fn submit_anns(
         create_time: util::now_ms(),
         parent_block_height: next_parent_block_height,
         anns: Vec::new(),
+        rerouted: false,
     };
     std::mem::swap(to_submit, &mut tip);
     if tip.anns.len() == 0 {
@@ -283,13 +471,135 @@ fn submit_anns(
     if queue.len() >= UPLOAD_CHANNEL_LEN {
         let front = queue.pop_front();
         if let Some(lost_batch) = front {
-            p.lost_anns.fetch_add(lost_batch.anns.len(), Ordering::Relaxed);
             debug!("Dropping {} anns @ {} for {}", lost_batch.anns.len(), lost_batch.parent_block_height, h.url);
+            spool_batch(p, lost_batch);
         }
     }
     queue.push_back(tip);
 }
 
+// Keep a batch which cannot be sent right now for later, if we have a spool.
+// Anns which the spool can not keep are counted in spool.dropped.
+fn spool_batch(p: &Pool, batch: AnnBatch) {
+    if let Some(spool) = &p.spool {
+        let spool = Arc::clone(spool);
+        // Writing a file has no business on the async executor
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = spool.put(batch.parent_block_height, batch.create_time, &batch.anns) {
+                warn!("Unable to spool anns: {}", e);
+            }
+        });
+    } else {
+        p.lost_anns.add(batch.anns.len() as u64);
+    }
+}
+
+// Send spooled anns to the handlers while they are taking them
+async fn spool_loop(p: Arc<Pool>) {
+    let spool = Arc::clone(p.spool.as_ref().unwrap());
+    loop {
+        util::sleep_ms(SPOOL_CHECK_MS).await;
+        p.lost_anns.add(spool.dropped.take());
+        let (handlers, oldest_accepted) = {
+            let pm = p.m.lock().unwrap();
+            (pm.handlers.clone(), pm.oldest_accepted)
+        };
+        if handlers.is_empty()
+            || oldest_accepted < 0
+            || p.last_upload_ok.load(Ordering::Relaxed) + SPOOL_UPLOAD_OK_MS < util::now_ms()
+        {
+            continue;
+        }
+        while handlers
+            .iter()
+            .all(|h| h.queue.lock().unwrap().len() < UPLOAD_CHANNEL_LEN / 2)
+        {
+            let spool = Arc::clone(&spool);
+            let res = tokio::task::spawn_blocking(move || spool.take(oldest_accepted)).await;
+            match res.map_err(anyhow::Error::from).and_then(|r| r) {
+                Ok(Some(s)) => {
+                    debug!("Unspooling {} anns @ {}", s.anns.len(), s.parent_block_height);
+                    let batch = AnnBatch {
+                        parent_block_height: s.parent_block_height,
+                        create_time: s.create_time,
+                        anns: s.anns,
+                        rerouted: false,
+                    };
+                    route(&p, &handlers, batch, None);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Unable to read spooled anns: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Split a batch up between the handlers which the anns belong to. If handler
+// `from` would not take it, anns which still belong to `from` were either
+// accepted or we have nowhere better to send them.
+fn route(p: &Pool, handlers: &[Arc<Handler>], batch: AnnBatch, from: Option<&str>) {
+    if handlers.is_empty() {
+        spool_batch(p, batch);
+        return;
+    }
+    let mut per_handler = vec![Vec::new(); handlers.len()];
+    for ann in batch.anns {
+        let h = hash::compress32(&ann.bytes[..]);
+        let dedup_hash = u64::from_le_bytes(h[..8].try_into().unwrap());
+        let i = (dedup_hash % handlers.len() as u64) as usize;
+        if Some(handlers[i].url.as_str()) != from {
+            per_handler[i].push(ann);
+        }
+    }
+    for (h, anns) in handlers.iter().zip(per_handler) {
+        if anns.is_empty() {
+            continue;
+        }
+        if let Some(from) = from {
+            debug!("Rerouting {} anns from {} to {}", anns.len(), from, h.url);
+        }
+        let b = AnnBatch {
+            parent_block_height: batch.parent_block_height,
+            create_time: batch.create_time,
+            anns,
+            rerouted: from.is_some(),
+        };
+        let mut queue = h.queue.lock().unwrap();
+        if queue.len() < UPLOAD_CHANNEL_LEN {
+            queue.push_back(b);
+        } else {
+            drop(queue);
+            debug!("Queue for {} is full, spooling {} anns", h.url, b.anns.len());
+            spool_batch(p, b);
+        }
+    }
+}
+
+// Whether this reason for rejecting anns means our copy of the pool config is stale
+fn needs_refresh(r: AnnRejectReason) -> bool {
+    matches!(
+        r,
+        AnnRejectReason::WrongSigningKey
+            | AnnRejectReason::UnexpectedSignature
+            | AnnRejectReason::WrongParentHeight
+            | AnnRejectReason::NotEnoughWork
+            | AnnRejectReason::SubmitElsewhere
+            | AnnRejectReason::UnsupportedVersion
+    )
+}
+
+fn add_rejected(p: &Pool, reason: AnnRejectReason, n: u64) {
+    p.rejected_reasons
+        .lock()
+        .unwrap()
+        .entry(reason)
+        .or_default()
+        .add(n);
+}
+
 fn submit_to_pool(p: &Pool, ann_struct: &AnnResult, now: u64) {
     let parent_block_height = ann_struct.ann.parent_block_height();
     let handler = {
@@ -338,6 +648,18 @@ fn submit_to_pool(p: &Pool, ann_struct: &AnnResult, now: u64) {
     }
 }
 
+// Pass a newly mined ann to the pool(s) which it was mined for
+fn submit_ann(am: &AnnMine, ann_struct: &AnnResult, now: u64) {
+    if am.weighted {
+        // Mined on that pool's job, so nobody else wants it
+        submit_to_pool(&am.pools[ann_struct.job], ann_struct, now);
+    } else {
+        for p in &am.pools {
+            submit_to_pool(p, ann_struct, now);
+        }
+    }
+}
+
 async fn handle_ann_loop(am: &AnnMine) {
     debug!("receive_ann begin0");
     let (mut recv_ann, mut send_anns_per_second) = {
@@ -377,16 +699,25 @@ async fn handle_ann_loop(am: &AnnMine) {
             }
         }
 
-        for p in &am.pools {
-            submit_to_pool(p, &ann_struct, now);
-        }
+        submit_ann(am, &ann_struct, now);
+    }
+}
+
+// The handler did not answer, or something else answered for it, so the anns are
+// worth trying again later
+#[derive(Debug)]
+struct NoAnswer(String);
+impl std::fmt::Display for NoAnswer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
+impl std::error::Error for NoAnswer {}
 
 async fn upload_batch(
     am: &AnnMine,
     client: &reqwest::Client,
-    mut batch: AnnBatch,
+    batch: &AnnBatch,
     url: &str,
     upload_n: usize,
     p: &Arc<Pool>,
@@ -400,8 +731,8 @@ async fn upload_batch(
     let count = batch.anns.len();
     let v: Vec<Result<bytes::Bytes>> = batch
         .anns
-        .drain(..)
-        .map(|a| Ok(a.bytes) as Result<bytes::Bytes>)
+        .iter()
+        .map(|a| Ok(a.bytes.clone()) as Result<bytes::Bytes>)
         .collect();
     let stream = tokio::stream::iter(v);
     let body = reqwest::Body::wrap_stream(stream);
@@ -420,30 +751,49 @@ async fn upload_batch(
     let status = res.status();
     let resbytes = res.bytes().await?;
     let reply = if let Ok(x) = serde_json::from_slice::<AnnPostReply>(&resbytes) {
+        p.last_upload_ok.store(util::now_ms(), Ordering::Relaxed);
         x
     } else {
-        bail!(
+        // Probably not the handler at all but a proxy in front of it
+        return Err(NoAnswer(format!(
             "[{}] handler [{}] replied [{}]: [{}] which cannot be parsed",
             upload_n,
             url,
             status,
             String::from_utf8_lossy(&resbytes[..])
-        );
+        ))
+        .into());
     };
     let result = if let Some(x) = reply.result {
         x
     } else {
         if reply.error.iter().any(|x| x == "overloaded") {
             //am.overload_anns
-            p.overload_anns.fetch_add(count, Ordering::Relaxed);
+            p.overload_anns.add(count as u64);
             return Ok(());
         }
-        bail!(
+        if !reply.rejected.is_empty() {
+            // The whole post was rejected, e.g. the handler is on a different block
+            debug!("[{}] handler [{}] replied [{:?}]", upload_n, url, reply.error);
+            p.rejected_anns.add(count as u64);
+            for (&r, &n) in &reply.rejected {
+                add_rejected(p, r, n as u64);
+            }
+            if reply.rejected.keys().any(|&r| needs_refresh(r)) {
+                poolclient::refresh(&p.pcli);
+            }
+            return Ok(());
+        }
+        let msg = format!(
             "[{}] handler [{}] replied with no result [{}]",
             upload_n,
             url,
             String::from_utf8_lossy(&resbytes[..])
         );
+        if status.is_server_error() {
+            return Err(NoAnswer(msg).into());
+        }
+        bail!(msg);
     };
     debug!(
         "[{}] handler [{}] replied: OK [{}]{}",
@@ -468,11 +818,24 @@ async fn upload_batch(
         );
     }
     //Ok(result.accepted as usize)
-    p.accepted_anns
-        .fetch_add(result.accepted as usize, Ordering::Relaxed);
+    p.accepted_anns.add(result.accepted as u64);
     let rejected = count - (result.accepted as usize);
     if rejected > 0 {
-        p.rejected_anns.fetch_add(rejected, Ordering::Relaxed);
+        p.rejected_anns.add(rejected as u64);
+    }
+    for (&r, &n) in &reply.rejected {
+        add_rejected(p, r, n as u64);
+    }
+    if reply.rejected.keys().any(|&r| needs_refresh(r)) {
+        poolclient::refresh(&p.pcli);
+    }
+    if reply.rejected.contains_key(&AnnRejectReason::SubmitElsewhere) && !batch.rerouted {
+        // Hold on to them until we know which handler they should go to
+        let mut misrouted = p.misrouted.lock().unwrap();
+        if misrouted.len() >= UPLOAD_CHANNEL_LEN {
+            misrouted.pop_front();
+        }
+        misrouted.push_back((url.to_owned(), batch.clone()));
     }
     Ok(())
 }
@@ -495,21 +858,38 @@ async fn stats_loop(am: &AnnMine) {
             let aps = raps[..].iter().map(|a| a.count).sum::<usize>() / (STATS_SECONDS_TO_KEEP - 1);
             let diff = packetcrypt_sys::difficulty::tar_to_diff(raps[0].target);
             let estimated_eps = diff * aps as f64;
-            let kbps = (aps * am.pools.len()) as f64 * 8.0;
+            am.hashrate.set(estimated_eps);
+            let uploads = if am.weighted { 1 } else { am.pools.len() };
+            let kbps = (aps * uploads) as f64 * 8.0;
 
             let mut lost_anns = Vec::new();
             let mut inflight_anns = Vec::new();
             let mut accepted_rejected_over_anns = Vec::new();
+            let mut reasons = Vec::new();
             let mut rate = Vec::new();
             for p in &am.pools {
-                let lost = p.lost_anns.swap(0, Ordering::Relaxed);
+                let lost = p.lost_anns.take();
                 lost_anns.push(format!("{}", lost));
                 let inflight = p.inflight_anns.load(Ordering::Relaxed);
                 inflight_anns.push(format!("{}", inflight));
-                let accepted = p.accepted_anns.swap(0, Ordering::Relaxed);
-                let rejected = p.rejected_anns.swap(0, Ordering::Relaxed);
-                let over = p.overload_anns.swap(0, Ordering::Relaxed);
+                let accepted = p.accepted_anns.take();
+                let rejected = p.rejected_anns.take();
+                let over = p.overload_anns.take();
                 accepted_rejected_over_anns.push(format!("{}/{}/{}", accepted, rejected, over));
+                let pool_reasons = p
+                    .rejected_reasons
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(r, c)| (r.name(), c.take()))
+                    .filter(|(_, n)| *n > 0)
+                    .map(|(r, n)| format!("{}:{}", r, n))
+                    .collect::<Vec<_>>();
+                if !pool_reasons.is_empty() {
+                    reasons.push(format!("[{}]", pool_reasons.join(" ")));
+                } else {
+                    reasons.push("-".to_owned());
+                }
                 let total = lost + over + rejected + accepted;
                 rate.push(format!(
                     "{}%",
@@ -537,6 +917,9 @@ async fn stats_loop(am: &AnnMine) {
                     ),
                     format!("[{}]", rate.join(", "))
                 );
+                if reasons.iter().any(|r| r != "-") {
+                    info!("rejected: [{}]", reasons.join(", "));
+                }
             }
             time_of_last_msg = now;
         }
@@ -563,14 +946,21 @@ async fn uploader_loop(am: &AnnMine, p: Arc<Pool>, h: Arc<Handler>) {
                     .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 let count = batch.anns.len();
                 p.inflight_anns.fetch_add(count, Ordering::Relaxed);
-                match upload_batch(am, &client, batch, &h.url, upload_n, &p).await {
+                match upload_batch(am, &client, &batch, &h.url, upload_n, &p).await {
                     Ok(_) => (),
                     Err(e) => {
                         warn!(
                             "[{}] Error uploading ann batch to {}: {}",
                             upload_n, h.url, e
                         );
-                        p.lost_anns.fetch_add(count, Ordering::Relaxed);
+                        if e.downcast_ref::<reqwest::Error>().is_some()
+                            || e.downcast_ref::<NoAnswer>().is_some()
+                        {
+                            // Could not reach the handler, try again later
+                            spool_batch(&p, batch);
+                        } else {
+                            p.lost_anns.add(count as u64);
+                        }
                     }
                 };
                 p.inflight_anns.fetch_sub(count, Ordering::Relaxed);
@@ -583,6 +973,54 @@ async fn uploader_loop(am: &AnnMine, p: Arc<Pool>, h: Arc<Handler>) {
     debug!("Uploader for {} shutting down", h.url);
 }
 
+pub fn metrics(am: &AnnMine, out: &mut MetricsOut) {
+    out.gauge(
+        "annmine_encryptions_per_second",
+        "Estimated announcement mining rate",
+        &[],
+        am.hashrate.get(),
+    );
+    for p in &am.pools {
+        let l = [("pool", p.pcli.url.as_str())];
+        let anns = [
+            ("accepted", &p.accepted_anns),
+            ("rejected", &p.rejected_anns),
+            ("overload", &p.overload_anns),
+            ("lost", &p.lost_anns),
+        ];
+        for (result, c) in &anns {
+            out.counter(
+                "annmine_anns_total",
+                "Announcements uploaded to each pool, by result",
+                &[l[0], ("result", result)],
+                c.get() as f64,
+            );
+        }
+        for (reason, c) in p.rejected_reasons.lock().unwrap().iter() {
+            out.counter(
+                "annmine_anns_rejected_total",
+                "Announcements rejected by the pool, by reason",
+                &[l[0], ("reason", reason.name())],
+                c.get() as f64,
+            );
+        }
+        if let Some(spool) = &p.spool {
+            out.gauge(
+                "annmine_spooled_anns",
+                "Announcements waiting on disk to be uploaded",
+                &l,
+                spool.anns() as f64,
+            );
+        }
+        out.gauge(
+            "annmine_inflight_anns",
+            "Announcements currently being uploaded",
+            &l,
+            p.inflight_anns.load(Ordering::Relaxed) as f64,
+        );
+    }
+}
+
 pub async fn start(am: &AnnMine) -> Result<()> {
     packetcrypt_util::async_spawn!(am, {
         handle_ann_loop(&am).await;
@@ -590,12 +1028,132 @@ pub async fn start(am: &AnnMine) -> Result<()> {
     packetcrypt_util::async_spawn!(am, {
         stats_loop(&am).await;
     });
+    if am.weighted {
+        packetcrypt_util::async_spawn!(am, {
+            slice_loop(&am).await;
+        });
+    }
     for p in &am.pools {
         poolclient::start(&p.pcli).await;
         let p1 = Arc::clone(p);
         packetcrypt_util::async_spawn!(am, {
             update_work_loop(&am, p1).await;
         });
+        if p.spool.is_some() {
+            let p1 = Arc::clone(p);
+            tokio::spawn(async move {
+                spool_loop(p1).await;
+            });
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pool() {
+        assert_eq!(
+            parse_pool("http://pool.example@0.7").unwrap(),
+            ("http://pool.example", Some(0.7))
+        );
+        assert_eq!(
+            parse_pool("http://x@pool.example").unwrap(),
+            ("http://x@pool.example", None)
+        );
+        assert!(parse_pool("http://pool.example@-1").is_err());
+    }
+
+    async fn mk_am(pools: &[&str]) -> AnnMine {
+        new(AnnMineCfg {
+            pools: pools.iter().map(|p| p.to_string()).collect(),
+            miner_id: 0,
+            workers: 1,
+            uploaders: 0,
+            pay_to: String::new(),
+            upload_timeout: 30,
+            mine_old_anns: 0,
+            spool_dir: None,
+            spool_max_mb: 0,
+            spool_max_age: 0,
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_slices() {
+        let am = mk_am(&["http://a@3", "http://b@1", "http://c@0"]).await;
+        // Nothing to mine until there is a job
+        assert!(slices(&am).is_empty());
+        *am.pools[1].job.lock().unwrap() = Some(AnnJob {
+            parent_block_hash: [0; 32],
+            parent_block_height: 10,
+            target: 0x207fffff,
+            signing_key: None,
+        });
+        let s = |am: &AnnMine| {
+            slices(am)
+                .into_iter()
+                .map(|(p, ms)| (p.index, ms))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(s(&am), vec![(1, SLICE_CYCLE_MS)]);
+        let job = *am.pools[1].job.lock().unwrap();
+        for p in &am.pools {
+            *p.job.lock().unwrap() = job;
+        }
+        // No weight, no time
+        assert_eq!(s(&am), vec![(0, 7500), (1, 2500)]);
+
+        switch_slice(&am, &am.pools[0]);
+        assert_eq!(*am.slice.lock().unwrap(), Some(0));
+        switch_slice(&am, &am.pools[1]);
+        assert_eq!(*am.slice.lock().unwrap(), Some(1));
+        annminer::stop(&am.miners[1]);
+    }
+
+    #[tokio::test]
+    async fn test_submit_ann() {
+        let mut bytes = [0_u8; 1024];
+        bytes[12..16].copy_from_slice(&10_i32.to_le_bytes());
+        let ann = |job| AnnResult {
+            ann: PacketCryptAnn {
+                bytes: util::aligned_bytes(&bytes, 4),
+            },
+            dedup_hash: 0,
+            job,
+        };
+        // The number of anns waiting to be uploaded to each pool
+        let tips = |am: &AnnMine| {
+            am.pools
+                .iter()
+                .map(|p| p.m.lock().unwrap().handlers[0].tip.lock().unwrap().anns.len())
+                .collect::<Vec<_>>()
+        };
+        for (pools, expect) in &[
+            (&["http://a@1", "http://b@1"], vec![0, 1]),
+            (&["http://a", "http://b"], vec![1, 1]),
+        ] {
+            let am = mk_am(&pools[..]).await;
+            for p in &am.pools {
+                p.m.lock().unwrap().handlers.push(Arc::new(Handler {
+                    tip: Mutex::new(AnnBatch {
+                        parent_block_height: 10,
+                        create_time: util::now_ms(),
+                        anns: Vec::new(),
+                        rerouted: false,
+                    }),
+                    url: Arc::new(format!("{}/submit", p.pcli.url)),
+                    queue: Arc::new(Mutex::new(VecDeque::new())),
+                    shutdown: AtomicBool::new(false),
+                }));
+            }
+            // Mined by the second pool's miner
+            submit_ann(&am, &ann(1), util::now_ms());
+            assert_eq!(&tips(&am), expect);
+        }
+    }
+}
@@ -9,15 +9,18 @@ use std::os::raw::c_void;
 use std::sync::atomic::AtomicPtr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 pub struct AnnResult {
     pub ann: PacketCryptAnn,
     pub dedup_hash: u64,
+    // Which miner found it, when more than one is sending to the same channel
+    pub job: usize,
 }
 
 pub struct CallbackCtx {
-    send_ann: tokio::sync::mpsc::UnboundedSender<AnnResult>,
+    send_ann: UnboundedSender<AnnResult>,
+    job: usize,
 }
 
 pub struct AnnMinerS {
@@ -37,25 +40,37 @@ pub unsafe extern "C" fn on_ann_found(vctx: *mut c_void, ann: *mut u8) {
     };
     let dedup_hash = (&hash::compress32(&ann.bytes[..])[..]).get_u64_le();
     let ctx = vctx as *const CallbackCtx;
-    if let Err(e) = (*ctx).send_ann.send(AnnResult { ann, dedup_hash }) {
+    let job = (*ctx).job;
+    if let Err(e) = (*ctx).send_ann.send(AnnResult {
+        ann,
+        dedup_hash,
+        job,
+    }) {
         warn!("Unable to send announcement to channel because [{}]", e);
     }
 }
 
 pub fn new(miner_id: u32, workers: usize) -> (AnnMiner, UnboundedReceiver<AnnResult>) {
     let (send_ann, recv_ann) = tokio::sync::mpsc::unbounded_channel();
-    let mut cbc = Box::new(CallbackCtx { send_ann });
+    (with_sender(miner_id, workers, send_ann, 0), recv_ann)
+}
+
+/// Create a miner which sends what it finds to an existing channel, tagged with `job`.
+pub fn with_sender(
+    miner_id: u32,
+    workers: usize,
+    send_ann: UnboundedSender<AnnResult>,
+    job: usize,
+) -> AnnMiner {
+    let mut cbc = Box::new(CallbackCtx { send_ann, job });
     let ptr = (&mut *cbc as *mut CallbackCtx) as *mut c_void;
     let miner = unsafe {
         packetcrypt_sys::AnnMiner_create(miner_id, workers as c_int, ptr, Some(on_ann_found))
     };
-    (
-        Arc::new(AnnMinerS {
-            _cbc: cbc,
-            miner: Mutex::new(AtomicPtr::new(miner)),
-        }),
-        recv_ann,
-    )
+    Arc::new(AnnMinerS {
+        _cbc: cbc,
+        miner: Mutex::new(AtomicPtr::new(miner)),
+    })
 }
 
 const ANN_VERSION: c_int = 1;
@@ -86,6 +101,11 @@ pub fn start(
     Ok(())
 }
 
+/// Pause mining, starting again with the same request carries on where it left off.
+pub fn stop(miner: &AnnMiner) {
+    unsafe { packetcrypt_sys::AnnMiner_stop(*miner.miner.lock().unwrap().get_mut()) };
+}
+
 impl AnnMinerS {
     pub fn new(miner_id: u32, workers: usize) -> (AnnMiner, UnboundedReceiver<AnnResult>) {
         new(miner_id, workers)
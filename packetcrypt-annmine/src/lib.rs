@@ -1,2 +1,4 @@
 pub mod annmine;
 pub mod annminer;
+pub mod solo;
+mod spool;
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Solo ann mining, for testnets and research: the parent block comes from pktd (or
+// anything which speaks its JSON-RPC) rather than from a pool, and the anns are written
+// to ann files and/or sprayed to block miners rather than uploaded to handlers.
+//
+// The ann files are written the same way as an ann handler writes them, see annfiles,
+// so the directory can be served to block miners as it is.
+use crate::annminer::{self, AnnResult};
+use anyhow::{bail, Result};
+use core::time::Duration;
+use log::{info, warn};
+use packetcrypt_sprayer::Sprayer;
+use packetcrypt_util::annfiles::{self, AnnFiles};
+use packetcrypt_util::rpcclient::{self, RpcClient};
+use packetcrypt_util::util;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+// How often to ask the node for its tip
+const POLL_MS: u64 = 1_000;
+
+// Same as the ann handler
+const MAX_ANNS_PER_FILE: usize = 1024;
+const MAX_MS_BETWEEN_FILES: u64 = 10_000;
+
+const STATS_MS: u64 = 10_000;
+
+// Field names in the config file are the same as the command line flags
+#[derive(Deserialize)]
+pub struct SoloCfg {
+    #[serde(rename = "rpcurl")]
+    pub rpc_url: String,
+    #[serde(rename = "rpcuser", default)]
+    pub rpc_user: String,
+    #[serde(rename = "rpcpass", default)]
+    pub rpc_pass: String,
+    #[serde(skip)]
+    pub miner_id: u32,
+    #[serde(rename = "threads")]
+    pub workers: usize,
+    // Compact target to mine anns at
+    pub target: u32,
+    #[serde(rename = "outdir", default)]
+    pub out_dir: Option<String>,
+    // Delete the oldest ann files in out_dir past this many
+    #[serde(rename = "filestokeep")]
+    pub files_to_keep: usize,
+    #[serde(skip)]
+    pub spray_cfg: Option<packetcrypt_sprayer::Config>,
+}
+
+// Take the anns from the miner and send them wherever they are going
+async fn output_loop(
+    mut recv_ann: UnboundedReceiver<AnnResult>,
+    mut files: Option<AnnFiles>,
+    spray: Option<Sprayer>,
+) {
+    let mut buf = Vec::new();
+    let mut found = 0;
+    let mut last_stats_ms = util::now_ms();
+    loop {
+        // Wake up now and then so that files get written even if anns are slow
+        let first = tokio::time::timeout(Duration::from_millis(POLL_MS), recv_ann.recv()).await;
+        let mut batch = match first {
+            Ok(Some(a)) => vec![a.ann.bytes],
+            Ok(None) => return,
+            Err(_) => Vec::new(),
+        };
+        while let Ok(a) = recv_ann.try_recv() {
+            batch.push(a.ann.bytes);
+        }
+        found += batch.len();
+        if let Some(spray) = &spray {
+            spray.push_anns(&batch.iter().map(|a| &a[..]).collect::<Vec<_>>());
+        }
+
+        let now = util::now_ms();
+        if let Some(af) = &mut files {
+            buf.extend(batch);
+            if buf.len() >= MAX_ANNS_PER_FILE
+                || (!buf.is_empty() && af.last_write_ms + MAX_MS_BETWEEN_FILES < now)
+            {
+                for chunk in std::mem::take(&mut buf).chunks(MAX_ANNS_PER_FILE) {
+                    if let Err(e) = annfiles::write(af, chunk).await {
+                        warn!("Unable to write ann file: {}", e);
+                    }
+                }
+                if let Err(e) = annfiles::write_index(af).await {
+                    warn!("Unable to write ann file index: {}", e);
+                }
+                af.last_write_ms = now;
+            }
+        }
+        if last_stats_ms + STATS_MS < now {
+            info!(
+                "{} anns/s{}",
+                found as u64 * 1000 / (now - last_stats_ms),
+                if let Some(af) = &files {
+                    format!(", {} ann files written", af.next_file_num - 1)
+                } else {
+                    String::new()
+                }
+            );
+            found = 0;
+            last_stats_ms = now;
+        }
+    }
+}
+
+// Start mining on the node's tip if it has moved, `tip` is what we are mining on
+async fn poll_tip(
+    cfg: &SoloCfg,
+    rpc: &RpcClient,
+    miner: &annminer::AnnMiner,
+    tip: &mut [u8; 32],
+) -> Result<()> {
+    let hash = rpcclient::get_best_block_hash(rpc).await?;
+    if hash == *tip {
+        return Ok(());
+    }
+    let header = rpcclient::get_block_header(rpc, &hash).await?;
+    info!(
+        "Start mining with parent_block_height: [{} @ {}]",
+        hex::encode(header.hash),
+        header.height
+    );
+    // Reverse the parent block hash because hashes in bitcoin are always expressed backward
+    let mut rev_hash = header.hash;
+    rev_hash.reverse();
+    annminer::start(miner, rev_hash, header.height, cfg.target, None)?;
+    *tip = hash;
+    Ok(())
+}
+
+/// Mine anns on the tip of the node at rpc_url, forever.
+pub async fn run(cfg: SoloCfg) -> Result<()> {
+    if cfg.out_dir.is_none() && cfg.spray_cfg.is_none() {
+        bail!("Solo mining needs somewhere to put the anns, an outdir and/or a sprayer");
+    }
+    let rpc = rpcclient::new(&cfg.rpc_url, &cfg.rpc_user, &cfg.rpc_pass)?;
+    let files = if let Some(dir) = &cfg.out_dir {
+        let af = annfiles::open(dir, &format!("{}/tmp", dir), cfg.files_to_keep).await?;
+        info!(
+            "Writing anns to {} starting from anns_{}.bin",
+            dir, af.next_file_num
+        );
+        Some(af)
+    } else {
+        None
+    };
+    let spray = if let Some(sc) = &cfg.spray_cfg {
+        let spray = Sprayer::new(sc)?;
+        spray.start();
+        Some(spray)
+    } else {
+        None
+    };
+    let (miner, recv_ann) = annminer::new(cfg.miner_id, cfg.workers);
+    tokio::spawn(async move {
+        output_loop(recv_ann, files, spray).await;
+    });
+    let mut tip = [0_u8; 32];
+    loop {
+        if let Err(e) = poll_tip(&cfg, &rpc, &miner, &mut tip).await {
+            warn!("Unable to get the tip from {}: {}", cfg.rpc_url, e);
+        }
+        util::sleep_ms(POLL_MS).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packetcrypt_sys::PacketCryptAnn;
+    use packetcrypt_util::protocol::IndexFile;
+
+    #[tokio::test]
+    async fn test_output_loop() {
+        let dir = util::TempDir::new("solo");
+        let dir = dir.path();
+        let af = annfiles::open(dir, &format!("{}/tmp", dir), 1)
+            .await
+            .unwrap();
+        let (send_ann, recv_ann) = tokio::sync::mpsc::unbounded_channel();
+        // One more than fits in a file
+        for i in 0..=MAX_ANNS_PER_FILE {
+            let mut bytes = [0_u8; 1024];
+            bytes[..8].copy_from_slice(&i.to_le_bytes());
+            let ann = AnnResult {
+                ann: PacketCryptAnn {
+                    bytes: util::aligned_bytes(&bytes, 4),
+                },
+                dedup_hash: 0,
+                job: 0,
+            };
+            assert!(send_ann.send(ann).is_ok());
+        }
+        drop(send_ann);
+        output_loop(recv_ann, Some(af), None).await;
+
+        // Only the newest file is kept
+        let index = std::fs::read(format!("{}/index.json", dir)).unwrap();
+        let index: IndexFile = serde_json::from_slice(&index).unwrap();
+        assert_eq!(index.highest_ann_file, 2);
+        assert_eq!(index.files, vec!["anns_2.bin"]);
+        assert!(!std::path::Path::new(&format!("{}/anns_1.bin", dir)).exists());
+        let last = std::fs::read(format!("{}/anns_2.bin", dir)).unwrap();
+        assert_eq!(last.len(), 1024);
+        assert_eq!(last[..8], MAX_ANNS_PER_FILE.to_le_bytes());
+    }
+}
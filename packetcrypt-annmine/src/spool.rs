@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Batches of anns which could not be uploaded to a pool, kept on disk so that they
+// can be sent once the pool's handlers are reachable again, even after a restart.
+//
+// Each batch is one file of anns, named after the block it is for and when it was
+// made so that nothing needs to be read to decide which ones are still worth sending:
+//   <parent block height>_<create time ms>_<seq>.anns
+use anyhow::{Context, Result};
+use packetcrypt_sys::PacketCryptAnn;
+use packetcrypt_util::metrics::Counter;
+use packetcrypt_util::util;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const ANN_LEN: u64 = 1024;
+
+pub struct Spooled {
+    pub parent_block_height: i32,
+    pub create_time: u64,
+    pub anns: Vec<PacketCryptAnn>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Entry {
+    parent_block_height: i32,
+    create_time: u64,
+    seq: u64,
+    len: u64,
+}
+
+struct SpoolMut {
+    entries: Vec<Entry>,
+    bytes: u64,
+    seq: u64,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age_ms: u64,
+    m: Mutex<SpoolMut>,
+    // Anns which were deleted, or could not be stored, without being sent
+    pub dropped: Counter,
+}
+
+impl Spool {
+    pub fn open(dir: &str, max_bytes: u64, max_age_ms: u64) -> Result<Spool> {
+        fs::create_dir_all(dir).with_context(|| format!("fs::create_dir_all({})", dir))?;
+        let mut entries = Vec::new();
+        for f in fs::read_dir(dir).with_context(|| format!("fs::read_dir({})", dir))? {
+            let f = f?;
+            let name = f.file_name();
+            if let Some(mut e) = name.to_str().and_then(parse_name) {
+                e.len = f.metadata()?.len();
+                entries.push(e);
+            }
+        }
+        entries.sort();
+        let bytes = entries.iter().map(|e| e.len).sum();
+        let seq = entries.iter().map(|e| e.seq + 1).max().unwrap_or(0);
+        Ok(Spool {
+            dir: PathBuf::from(dir),
+            max_bytes,
+            max_age_ms,
+            m: Mutex::new(SpoolMut {
+                entries,
+                bytes,
+                seq,
+            }),
+            dropped: Counter::default(),
+        })
+    }
+
+    fn path(&self, e: &Entry) -> PathBuf {
+        self.dir.join(format!(
+            "{}_{}_{}.anns",
+            e.parent_block_height, e.create_time, e.seq
+        ))
+    }
+
+    fn remove(&self, m: &mut SpoolMut, i: usize, sent: bool) {
+        let e = m.entries.remove(i);
+        m.bytes -= e.len;
+        if !sent {
+            self.dropped.add(e.len / ANN_LEN);
+        }
+        let _ = fs::remove_file(self.path(&e));
+    }
+
+    /// Number of anns which are waiting to be sent.
+    pub fn anns(&self) -> u64 {
+        self.m.lock().unwrap().bytes / ANN_LEN
+    }
+
+    /// Store a batch, making room by dropping the batches for the oldest blocks.
+    pub fn put(
+        &self,
+        parent_block_height: i32,
+        create_time: u64,
+        anns: &[PacketCryptAnn],
+    ) -> Result<()> {
+        let len = anns.len() as u64 * ANN_LEN;
+        let mut m = self.m.lock().unwrap();
+        while !m.entries.is_empty() && m.bytes + len > self.max_bytes {
+            self.remove(&mut m, 0, false);
+        }
+        if m.bytes + len > self.max_bytes {
+            self.dropped.add(anns.len() as u64);
+            return Ok(());
+        }
+        let e = Entry {
+            parent_block_height,
+            create_time,
+            seq: m.seq,
+            len,
+        };
+        m.seq += 1;
+        let mut buf = Vec::with_capacity(len as usize);
+        for ann in anns {
+            buf.extend_from_slice(&ann.bytes[..]);
+        }
+        let path = self.path(&e);
+        if let Err(err) = fs::write(&path, &buf) {
+            self.dropped.add(anns.len() as u64);
+            return Err(err).with_context(|| format!("fs::write({:?})", path));
+        }
+        let i = m.entries.binary_search(&e).unwrap_or_else(|i| i);
+        m.entries.insert(i, e);
+        m.bytes += len;
+        Ok(())
+    }
+
+    /// Take the batch for the most recent block out of the spool, batches which are
+    /// too old or for a block before `min_height` are dropped.
+    pub fn take(&self, min_height: i32) -> Result<Option<Spooled>> {
+        let now = util::now_ms();
+        let mut m = self.m.lock().unwrap();
+        let mut i = 0;
+        while i < m.entries.len() {
+            let e = m.entries[i];
+            if e.parent_block_height < min_height || e.create_time + self.max_age_ms < now {
+                self.remove(&mut m, i, false);
+            } else {
+                i += 1;
+            }
+        }
+        let e = if let Some(e) = m.entries.last() {
+            *e
+        } else {
+            return Ok(None);
+        };
+        let path = self.path(&e);
+        let read = fs::read(&path).with_context(|| format!("fs::read({:?})", path));
+        let last = m.entries.len() - 1;
+        self.remove(&mut m, last, true);
+        let anns = read?
+            .chunks_exact(ANN_LEN as usize)
+            .map(|a| PacketCryptAnn {
+                bytes: util::aligned_bytes(a, 4),
+            })
+            .collect();
+        Ok(Some(Spooled {
+            parent_block_height: e.parent_block_height,
+            create_time: e.create_time,
+            anns,
+        }))
+    }
+}
+
+fn parse_name(name: &str) -> Option<Entry> {
+    let mut parts = name.strip_suffix(".anns")?.split('_');
+    let e = Entry {
+        parent_block_height: parts.next()?.parse().ok()?,
+        create_time: parts.next()?.parse().ok()?,
+        seq: parts.next()?.parse().ok()?,
+        len: 0,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(n: usize, b: u8) -> Vec<PacketCryptAnn> {
+        (0..n)
+            .map(|_| PacketCryptAnn {
+                bytes: util::aligned_bytes(&[b; ANN_LEN as usize], 4),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = util::TempDir::new("spool");
+        let dir = dir.path();
+        let now = util::now_ms();
+        let s = Spool::open(dir, 4 * ANN_LEN, 60_000).unwrap();
+        s.put(10, now, &batch(2, 1)).unwrap();
+        s.put(11, now, &batch(2, 2)).unwrap();
+        // Full, so the batch for block 10 has to go
+        s.put(12, now, &batch(1, 3)).unwrap();
+        assert_eq!((s.anns(), s.dropped.get()), (3, 2));
+        s.put(9, now - 120_000, &batch(1, 4)).unwrap();
+
+        // Everything is still there after a restart
+        let s = Spool::open(dir, 4 * ANN_LEN, 60_000).unwrap();
+        assert_eq!(s.anns(), 4);
+        let b = s.take(11).unwrap().unwrap();
+        assert_eq!((b.parent_block_height, b.anns.len()), (12, 1));
+        assert_eq!(b.anns[0].bytes[0], 3);
+        // The one for block 9 is too old anyway
+        assert_eq!(s.take(11).unwrap().unwrap().parent_block_height, 11);
+        assert!(s.take(11).unwrap().is_none());
+        assert_eq!((s.anns(), s.dropped.get()), (0, 1));
+        // Nothing is left behind
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+    }
+}
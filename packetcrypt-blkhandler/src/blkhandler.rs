@@ -0,0 +1,558 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use core::time::Duration;
+use log::{debug, error, info, warn};
+use packetcrypt_pool::master;
+use packetcrypt_pool::paymakerclient::{self, PaymakerClient};
+use packetcrypt_pool::poolcfg::BlkHandlerCfg;
+use packetcrypt_util::metrics::{Counter, MetricsOut};
+use packetcrypt_util::poolclient::{self, PoolClient};
+use packetcrypt_util::protocol::{
+    self, BlkShare, BlkShareEvent, BlkShareReply, BlockSubmitReply, MasterConf, MaybeBlkShareEvent,
+    Work,
+};
+use packetcrypt_util::{hash, util};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+// Share protocol version which we understand, sent by the miner as x-pc-sver
+const SHARE_VERSION: u32 = 1;
+
+// PacketCrypt proof entity containing the low nonce, anns and merkle proof
+const PC_TYPE_PROOF: u64 = 1;
+
+const SUBMIT_TIMEOUT_SECS: u64 = 30;
+const STATS_EVERY_MS: u64 = 30_000;
+
+// Number of heights to remember accepted shares for, in case the chain rolls back
+const SEEN_HISTORY: i32 = 3;
+
+struct CurrentWork {
+    conf: MasterConf,
+    work: Work,
+}
+
+#[derive(Debug, PartialEq)]
+enum Seen {
+    New,
+    Dup,
+    // The work was replaced while the share was being checked
+    Stale,
+}
+
+pub struct BlkHandlerS {
+    cfg: BlkHandlerCfg,
+    pc: PoolClient,
+    pmc: PaymakerClient,
+    sockaddr: SocketAddr,
+    client: reqwest::Client,
+    current: Mutex<Option<CurrentWork>>,
+    // Work hashes of the shares which have been accepted, by height
+    seen: Mutex<BTreeMap<i32, HashSet<[u8; 32]>>>,
+
+    accepted: Counter,
+    stale: Counter,
+    invalid: Counter,
+    dup: Counter,
+    blocks: Counter,
+}
+pub type BlkHandler = Arc<BlkHandlerS>;
+
+pub fn new(pc: &PoolClient, pmc: &PaymakerClient, cfg: BlkHandlerCfg) -> Result<BlkHandler> {
+    if cfg.block_submit_url.is_empty() {
+        bail!("block_submit_url is required, blocks would be lost");
+    }
+    Ok(Arc::new(BlkHandlerS {
+        sockaddr: cfg.bind.parse()?,
+        client: reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(SUBMIT_TIMEOUT_SECS))
+            .build()?,
+        cfg,
+        pc: pc.clone(),
+        pmc: pmc.clone(),
+        current: Mutex::new(None),
+        seen: Mutex::new(BTreeMap::new()),
+        accepted: Counter::default(),
+        stale: Counter::default(),
+        invalid: Counter::default(),
+        dup: Counter::default(),
+        blocks: Counter::default(),
+    }))
+}
+
+struct ShareProof {
+    low_nonce: u32,
+    anns: Vec<[u8; 1024]>,
+    proof: Bytes,
+}
+
+fn parse_proof(header_and_proof: &Bytes) -> Result<ShareProof> {
+    if header_and_proof.len() < 80 {
+        bail!("runt header_and_proof");
+    }
+    let mut b = header_and_proof.slice(80..);
+    loop {
+        let t = protocol::get_varint(&mut b)?;
+        let len = protocol::get_varint(&mut b)? as usize;
+        if b.remaining() < len {
+            bail!("runt proof entity");
+        }
+        if t != PC_TYPE_PROOF {
+            b.advance(len);
+            continue;
+        }
+        if len < 4 + 1024 * 4 {
+            bail!("runt proof");
+        }
+        let mut p = b.slice(0..len);
+        let low_nonce = p.get_u32_le();
+        let mut anns = Vec::with_capacity(4);
+        for _ in 0..4 {
+            let mut ann = [0_u8; 1024];
+            p.copy_to_slice(&mut ann);
+            anns.push(ann);
+        }
+        return Ok(ShareProof {
+            low_nonce,
+            anns,
+            proof: p,
+        });
+    }
+}
+
+// Compute the first 76 bytes of the header (everything but the nonce) which the
+// miner should have produced from this work and this coinbase commit.
+fn expected_header(work: &Work, commit: &[u8]) -> Result<BytesMut> {
+    let cb = master::patch_coinbase(&work.coinbase_no_witness[..], commit)?;
+    let mut buf = [0_u8; 64];
+    buf[0..32].copy_from_slice(&hash::compress_dsha256(&cb[..]));
+    for h in &work.coinbase_merkle {
+        buf[32..].copy_from_slice(&h);
+        let h = hash::compress_dsha256(&buf[..]);
+        buf[0..32].copy_from_slice(&h[..]);
+    }
+    let mut out = BytesMut::with_capacity(80);
+    out.put_u32_le(work.header.version);
+    out.put(&work.header.hash_prev_block[..]);
+    out.put(&buf[0..32]);
+    out.put_i32_le(work.header.time_seconds);
+    out.put_u32_le(work.header.work_bits);
+    Ok(out)
+}
+
+// Hex of the block hash in the byte order which pktd displays
+fn block_hash_hex(header: &[u8]) -> String {
+    let mut h = hash::compress_dsha256(header);
+    h.reverse();
+    hex::encode(h)
+}
+
+async fn forward_block(bh: &BlkHandler, share: &BlkShare) -> Result<String> {
+    let res = bh
+        .client
+        .post(&bh.cfg.block_submit_url)
+        .header("content-type", "application/json")
+        .body(serde_json::to_vec(share)?)
+        .send()
+        .await?;
+    let status = res.status();
+    let resbytes = res.bytes().await?;
+    let reply = if let Ok(x) = serde_json::from_slice::<BlockSubmitReply>(&resbytes) {
+        x
+    } else {
+        bail!(
+            "[{}] replied [{}]: [{}] which cannot be parsed",
+            bh.cfg.block_submit_url,
+            status,
+            String::from_utf8_lossy(&resbytes[..])
+        );
+    };
+    if !reply.error.is_empty() {
+        bail!("Block was rejected: {}", reply.error.join(", "));
+    }
+    if let Some(hash) = reply.result {
+        Ok(hash)
+    } else {
+        bail!("No result from [{}]", bh.cfg.block_submit_url);
+    }
+}
+
+// Remember a share's work hash, unless the same share was already accepted at this
+// height or the work for it has since been replaced.
+fn mark_seen(bh: &BlkHandler, height: i32, work_hash: [u8; 32]) -> Seen {
+    match &*bh.current.lock().unwrap() {
+        Some(cw) if cw.work.height == height => (),
+        _ => return Seen::Stale,
+    }
+    if bh
+        .seen
+        .lock()
+        .unwrap()
+        .entry(height)
+        .or_default()
+        .insert(work_hash)
+    {
+        Seen::New
+    } else {
+        Seen::Dup
+    }
+}
+
+// Switch to new work, the shares seen at the same height are still dups after a
+// change of config.
+fn replace_work(bh: &BlkHandler, conf: MasterConf, work: Work) {
+    let height = work.height;
+    let mut current = bh.current.lock().unwrap();
+    current.replace(CurrentWork { conf, work });
+    bh.seen
+        .lock()
+        .unwrap()
+        .retain(|h, _| *h > height - SEEN_HISTORY && *h <= height);
+}
+
+async fn check_share(
+    bh: &BlkHandler,
+    share: &BlkShare,
+    pay_to: &str,
+) -> Result<BlkShareEvent, String> {
+    let (work, tip_height) = match &*bh.current.lock().unwrap() {
+        Some(cw) => (cw.work.clone(), cw.conf.current_height),
+        None => return Err("No work yet, try again later".to_owned()),
+    };
+    let hap = &share.header_and_proof;
+    if hap.len() < 80 {
+        bh.invalid.add(1);
+        return Err("runt header_and_proof".to_owned());
+    }
+    if hap[4..36] != work.header.hash_prev_block {
+        bh.stale.add(1);
+        let mut prev = work.header.hash_prev_block;
+        prev.reverse();
+        return Err(format!(
+            "Share is for wrong work, expecting previous hash [{}] height [{}]",
+            hex::encode(prev),
+            tip_height
+        ));
+    }
+    let sp = match parse_proof(hap) {
+        Ok(sp) => sp,
+        Err(e) => {
+            bh.invalid.add(1);
+            return Err(format!("Invalid proof: {}", e));
+        }
+    };
+    match expected_header(&work, &share.coinbase_commit[..]) {
+        Ok(h) if h[..] == hap[0..76] => (),
+        Ok(_) => {
+            bh.invalid.add(1);
+            return Err("Block header does not match the work".to_owned());
+        }
+        Err(e) => {
+            bh.invalid.add(1);
+            return Err(format!("Invalid coinbase commit: {}", e));
+        }
+    }
+
+    // Validation is cpu-intensive so it must not be run on the async executor
+    let (header, commit, height, target) = (
+        hap.slice(0..80),
+        share.coinbase_commit.clone(),
+        work.height,
+        work.share_target,
+    );
+    let res = tokio::task::spawn_blocking(move || {
+        packetcrypt_sys::check_block_share(
+            &header[..],
+            sp.low_nonce,
+            target,
+            &sp.anns[..],
+            &commit[..],
+            height,
+            &sp.proof[..],
+        )
+    })
+    .await
+    .map_err(|e| format!("Internal error: {}", e))?;
+    let (work_hash, is_block) = match res {
+        Ok(x) => x,
+        Err(e) => {
+            bh.invalid.add(1);
+            return Err(format!("Invalid share: {}", e));
+        }
+    };
+
+    match mark_seen(bh, work.height, work_hash) {
+        Seen::New => (),
+        Seen::Dup => {
+            bh.dup.add(1);
+            return Err("Duplicate share".to_owned());
+        }
+        Seen::Stale => {
+            bh.stale.add(1);
+            return Err("Share is for work which was replaced".to_owned());
+        }
+    }
+    bh.accepted.add(1);
+
+    Ok(BlkShareEvent {
+        blk_type: "share".to_owned(),
+        pay_to: pay_to.to_owned(),
+        block: is_block,
+        time: util::now_ms(),
+        event_id: hex::encode(&hash::compress_sha256(&hap[..])[..16]),
+        header_hash: if is_block {
+            Some(block_hash_hex(&hap[0..80]))
+        } else {
+            None
+        },
+        target,
+    })
+}
+
+fn mk_reply(result: Result<BlkShareEvent, String>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let (reply, status) = match result {
+        Ok(bse) => (
+            BlkShareReply {
+                warn: Vec::new(),
+                error: Vec::new(),
+                result: MaybeBlkShareEvent::Bse(bse),
+            },
+            warp::http::StatusCode::OK,
+        ),
+        Err(e) => (
+            BlkShareReply {
+                warn: Vec::new(),
+                error: vec![e],
+                result: MaybeBlkShareEvent::Str(String::new()),
+            },
+            warp::http::StatusCode::BAD_REQUEST,
+        ),
+    };
+    warp::reply::with_status(warp::reply::json(&reply), status)
+}
+
+async fn handle_submit(
+    bh: BlkHandler,
+    bytes: Bytes,
+    sver: u32,
+    pay_to: String,
+) -> Result<impl warp::Reply, Infallible> {
+    if sver != SHARE_VERSION {
+        return Ok(mk_reply(Err(format!(
+            "Unsupported share version {}, please update your miner",
+            sver
+        ))));
+    }
+    if pay_to.is_empty() {
+        return Ok(mk_reply(Err("x-pc-payto is required".to_owned())));
+    }
+    let share = match serde_json::from_slice::<BlkShare>(&bytes[..]) {
+        Ok(s) => s,
+        Err(e) => return Ok(mk_reply(Err(format!("Unable to parse share: {}", e)))),
+    };
+    let res = check_share(&bh, &share, &pay_to).await;
+    if let Ok(bse) = &res {
+        if let Err(e) = paymakerclient::handle_paylog(&bh.pmc, bse).await {
+            error!("Unable to send paylog {}", e);
+        }
+        if bse.block {
+            bh.blocks.add(1);
+            info!(
+                "BLOCK [{}] from [{}]",
+                bse.header_hash.as_deref().unwrap_or(""),
+                pay_to
+            );
+            let bh = Arc::clone(&bh);
+            tokio::spawn(async move {
+                match forward_block(&bh, &share).await {
+                    Ok(hash) => info!("Block [{}] submitted", hash),
+                    Err(e) => error!("Unable to submit block: {}", e),
+                }
+            });
+        }
+    } else if let Err(e) = &res {
+        debug!("Rejected share from [{}]: {}", pay_to, e);
+    }
+    Ok(mk_reply(res))
+}
+
+async fn update_work_loop(bh: &BlkHandler) {
+    let mut chan = poolclient::update_chan(&bh.pc).await;
+    loop {
+        let update = if let Ok(x) = chan.recv().await {
+            x
+        } else {
+            continue;
+        };
+        let work_url = format!("{}/work_{}.bin", bh.pc.url, update.conf.current_height);
+        let mut work_bin = match util::get_url_bin(&work_url).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Unable to download {}: {}", work_url, e);
+                continue;
+            }
+        };
+        let mut work = Work::default();
+        if let Err(e) = protocol::work_decode(&mut work, &mut work_bin) {
+            warn!("Failed to deserialize work {} {:?}", work_url, e);
+            continue;
+        }
+        debug!("Got work {}", work_url);
+        replace_work(bh, update.conf, work);
+    }
+}
+
+async fn stats_loop(bh: &BlkHandler) {
+    loop {
+        util::sleep_ms(STATS_EVERY_MS).await;
+        info!(
+            "Shares: {} accepted, {} stale, {} invalid, {} dup - {} blocks",
+            bh.accepted.take(),
+            bh.stale.take(),
+            bh.invalid.take(),
+            bh.dup.take(),
+            bh.blocks.take(),
+        );
+    }
+}
+
+pub fn metrics(bh: &BlkHandler, out: &mut MetricsOut) {
+    let shares = [
+        ("accepted", &bh.accepted),
+        ("stale", &bh.stale),
+        ("invalid", &bh.invalid),
+        ("dup", &bh.dup),
+    ];
+    for (result, c) in &shares {
+        out.counter(
+            "blkhandler_shares_total",
+            "Block shares received, by result",
+            &[("result", result)],
+            c.get() as f64,
+        );
+    }
+    out.counter(
+        "blkhandler_blocks_total",
+        "Shares which were also blocks",
+        &[],
+        bh.blocks.get() as f64,
+    );
+}
+
+pub async fn start(bh: &BlkHandler) {
+    let sub = warp::post()
+        .and(warp::path("submit"))
+        .and(warp::path::end())
+        .and((|bh: BlkHandler| warp::any().map(move || bh.clone()))(
+            bh.clone(),
+        ))
+        .and(warp::body::bytes())
+        .and(warp::header::<u32>("x-pc-sver"))
+        .and(warp::header::<String>("x-pc-payto"))
+        .and_then(handle_submit);
+
+    packetcrypt_util::async_spawn!(bh, { warp::serve(sub).run(bh.sockaddr).await });
+
+    packetcrypt_util::async_spawn!(bh, {
+        update_work_loop(&bh).await;
+    });
+
+    packetcrypt_util::async_spawn!(bh, {
+        stats_loop(&bh).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packetcrypt_pool::paymakerclient::PaymakerClientCfg;
+
+    async fn mk_bh(dir: &str, height: i32) -> BlkHandler {
+        let pc = poolclient::new("http://127.0.0.1:1", 1, 1);
+        let pmc = paymakerclient::new(
+            &pc,
+            PaymakerClientCfg {
+                paylogdir: dir.to_owned(),
+                password: String::new(),
+                paylog_submit_every_ms: 10_000,
+            },
+        )
+        .await
+        .unwrap();
+        let bh = new(
+            &pc,
+            &pmc,
+            BlkHandlerCfg {
+                bind: "127.0.0.1:0".to_owned(),
+                block_submit_url: "http://127.0.0.1:1".to_owned(),
+            },
+        )
+        .unwrap();
+        set_work(&bh, height);
+        bh
+    }
+
+    fn set_work(bh: &BlkHandler, height: i32) {
+        let mut work = Work::default();
+        work.header.hash_prev_block = [height as u8; 32];
+        work.height = height;
+        replace_work(bh, MasterConf::default(), work);
+    }
+
+    #[tokio::test]
+    async fn test_stale_share() {
+        let dir = util::TempDir::new("bh-stale");
+        let bh = mk_bh(dir.path(), 10).await;
+        let mut hap = vec![0_u8; 80];
+        hap[4..36].copy_from_slice(&[9_u8; 32]);
+        let share = BlkShare {
+            coinbase_commit: Bytes::new(),
+            header_and_proof: Bytes::from(hap),
+        };
+        let e = check_share(&bh, &share, "pkt1x").await.unwrap_err();
+        assert!(e.starts_with("Share is for wrong work"), "{}", e);
+        assert_eq!((bh.stale.get(), bh.invalid.get()), (1, 0));
+
+        // Right parent block, so it gets as far as the proof
+        let mut hap = vec![0_u8; 80];
+        hap[4..36].copy_from_slice(&[10_u8; 32]);
+        let share = BlkShare {
+            coinbase_commit: Bytes::new(),
+            header_and_proof: Bytes::from(hap),
+        };
+        let e = check_share(&bh, &share, "pkt1x").await.unwrap_err();
+        assert!(e.starts_with("Invalid proof"), "{}", e);
+        assert_eq!((bh.stale.get(), bh.invalid.get()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_seen() {
+        let dir = util::TempDir::new("bh-seen");
+        let bh = mk_bh(dir.path(), 10).await;
+        assert_eq!(mark_seen(&bh, 10, [1; 32]), Seen::New);
+        assert_eq!(mark_seen(&bh, 10, [2; 32]), Seen::New);
+        assert_eq!(mark_seen(&bh, 10, [1; 32]), Seen::Dup);
+        // Work which was replaced while the share was being validated
+        assert_eq!(mark_seen(&bh, 9, [3; 32]), Seen::Stale);
+
+        // A change of config at the same height
+        set_work(&bh, 10);
+        assert_eq!(mark_seen(&bh, 10, [1; 32]), Seen::Dup);
+
+        // New work, nothing has been seen yet
+        set_work(&bh, 11);
+        assert_eq!(mark_seen(&bh, 11, [1; 32]), Seen::New);
+        assert_eq!(mark_seen(&bh, 11, [1; 32]), Seen::Dup);
+        assert_eq!(mark_seen(&bh, 10, [3; 32]), Seen::Stale);
+
+        // Rolled back, what was seen at that height is remembered
+        set_work(&bh, 10);
+        assert_eq!(mark_seen(&bh, 10, [2; 32]), Seen::Dup);
+        set_work(&bh, 10 + SEEN_HISTORY);
+        assert_eq!(bh.seen.lock().unwrap().len(), 0);
+    }
+}
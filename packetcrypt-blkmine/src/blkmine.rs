@@ -3,35 +3,52 @@ use crate::ann_store::AnnStore;
 use crate::blkminer::{BlkMiner, BlkResult, OnShare};
 use crate::prooftree::ProofTree;
 use crate::databuf::DataBuf;
+use crate::downloader::{self, Downloader};
 use crate::types::{HeightWork,ClassSet};
 use anyhow::{bail, Result};
 use bytes::BufMut;
 use log::{debug, info, trace, warn};
 use packetcrypt_sys::difficulty::pc_degrade_announcement_target;
 use packetcrypt_sys::difficulty::pc_get_effective_target;
+use packetcrypt_util::metrics::MetricsOut;
 use packetcrypt_util::poolclient::{self, PoolClient, PoolUpdate};
 use packetcrypt_util::protocol;
 use packetcrypt_util::{hash, util};
 use rayon::prelude::*;
+use serde::{Deserialize, Deserializer};
 use std::iter;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+// Field names in the config file are the same as the command line flags
+#[derive(Deserialize)]
 pub struct BlkArgs {
+    #[serde(rename = "paymentaddr")]
     pub payment_addr: String,
     pub threads: usize,
+    #[serde(rename = "downloaders")]
     pub downloader_count: usize,
+    #[serde(rename = "pool")]
     pub pool_master: String,
+    #[serde(rename = "memorysizemb", deserialize_with = "mb_to_bytes")]
     pub max_mem: usize,
+    #[serde(rename = "minfree")]
     pub min_free_space: f64,
+    #[serde(rename = "uploadtimeout")]
     pub upload_timeout: usize,
     pub uploaders: usize,
+    #[serde(rename = "handlerpass")]
     pub handler_pass: String,
+    #[serde(skip)]
     pub spray_cfg: Option<packetcrypt_sprayer::Config>,
 }
 
+fn mb_to_bytes<'de, D: Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    Ok(usize::deserialize(d)? * 1024 * 1024)
+}
+
 #[derive(Default, Clone)]
 struct CurrentMining {
     count: u32,
@@ -74,6 +91,9 @@ pub struct BlkMineS {
 
     spray: Option<packetcrypt_sprayer::Sprayer>,
 
+    // Http ann downloaders, one per handler in download_ann_urls
+    downloaders: Mutex<Vec<Downloader>>,
+
     share_channel_send: Mutex<tokio::sync::mpsc::UnboundedSender<Share>>,
     share_channel_recv: tokio::sync::Mutex<tokio::sync::mpsc::UnboundedReceiver<Share>>,
 
@@ -492,6 +512,7 @@ pub async fn new(ba: BlkArgs) -> Result<BlkMine> {
         pcli,
         ba,
         spray,
+        downloaders: Mutex::new(Vec::new()),
         share_channel_recv: tokio::sync::Mutex::new(recv),
         share_channel_send: Mutex::new(send),
         share_num: AtomicUsize::new(0),
@@ -539,28 +560,131 @@ async fn update_work_loop(bm: &BlkMine) {
     }
 }
 
-async fn stats_loop(bm: &BlkMine) {
+fn update_downloaders(bm: &BlkMine, conf: &protocol::MasterConf) {
+    let mut dls = bm.downloaders.lock().unwrap();
+    dls.retain(|dl| {
+        if conf.download_ann_urls.contains(&dl.url) {
+            true
+        } else {
+            info!("Dropping downloader {} because it is nolonger in the pool", dl.url);
+            downloader::stop(dl);
+            false
+        }
+    });
+    for url in &conf.download_ann_urls {
+        if dls.iter().any(|dl| &dl.url == url) {
+            continue;
+        }
+        info!("Adding downloader {}", url);
+        let dl = match downloader::new(
+            url,
+            bm.ba.downloader_count,
+            &bm.ba.handler_pass,
+            Arc::new(bm.clone()),
+        ) {
+            Ok(dl) => dl,
+            Err(e) => {
+                warn!("Unable to create downloader for {}: {}", url, e);
+                continue;
+            }
+        };
+        downloader::start(&dl);
+        dls.push(dl);
+    }
+}
+
+async fn downloader_loop(bm: &BlkMine) {
+    let mut chan = poolclient::update_chan(&bm.pcli).await;
     loop {
-        let (rdy, spare, cls, imm) = {
-            let cw_l = bm.current_work.lock().unwrap();
-            if let Some(w) = &*cw_l {
-                let classes = bm.ann_store.classes(w.work.height);
-                let (mut rdy, mut spr, mut cls, mut imm) = (0_isize, 0_isize, 0_isize, 0_isize);
-                for c in classes {
-                    cls += 1;
-                    if c.can_mine() {
-                        rdy += c.ann_count as isize;
-                    } else if c.immature {
-                        imm += c.ann_count as isize;
-                    } else {
-                        spr += crate::ann_class::ANNBUF_SZ as isize;
-                    }
-                }
-                (rdy, spr, cls, imm)
+        let update = if let Ok(x) = chan.recv().await {
+            x
+        } else {
+            info!("Unable to get data from chan");
+            util::sleep_ms(5_000).await;
+            continue;
+        };
+        update_downloaders(bm, &update.conf);
+    }
+}
+
+// Returns (ready anns, spare space, number of classes, immature anns) or -1s if there is no work
+fn class_stats(bm: &BlkMine) -> (isize, isize, isize, isize) {
+    let cw_l = bm.current_work.lock().unwrap();
+    if let Some(w) = &*cw_l {
+        let classes = bm.ann_store.classes(w.work.height);
+        let (mut rdy, mut spr, mut cls, mut imm) = (0_isize, 0_isize, 0_isize, 0_isize);
+        for c in classes {
+            cls += 1;
+            if c.can_mine() {
+                rdy += c.ann_count as isize;
+            } else if c.immature {
+                imm += c.ann_count as isize;
             } else {
-                (-1, -1, -1, -1)
+                spr += crate::ann_class::ANNBUF_SZ as isize;
             }
-        };
+        }
+        (rdy, spr, cls, imm)
+    } else {
+        (-1, -1, -1, -1)
+    }
+}
+
+pub fn metrics(bm: &BlkMine, out: &mut MetricsOut) {
+    let hashrate = bm.block_miner.hashes_per_second() as f64;
+    out.gauge(
+        "blkmine_hashes_per_second",
+        "Real block mining hashrate",
+        &[],
+        hashrate,
+    );
+    let mining = { bm.current_mining.lock().unwrap().as_ref().map(|cm| (cm.ann_min_work, cm.count)) };
+    if let Some((ann_min_work, count)) = mining {
+        let hrm = packetcrypt_sys::difficulty::pc_get_hashrate_multiplier(ann_min_work, count as u64);
+        out.gauge(
+            "blkmine_effective_hashes_per_second",
+            "Hashrate multiplied by the benefit of the announcements being mined",
+            &[],
+            hashrate * hrm as f64,
+        );
+        out.gauge("blkmine_mining_anns", "Announcements currently being mined", &[], count as f64);
+    }
+    out.counter(
+        "blkmine_shares_total",
+        "Shares found",
+        &[],
+        bm.share_num.load(Ordering::Relaxed) as f64,
+    );
+    let (rdy, spare, cls, imm) = class_stats(bm);
+    if cls >= 0 {
+        let h = "Announcements in the ann store by state";
+        out.gauge("blkmine_store_anns", h, &[("state", "ready")], rdy as f64);
+        out.gauge("blkmine_store_anns", h, &[("state", "immature")], imm as f64);
+        out.gauge("blkmine_store_anns", h, &[("state", "spare")], spare as f64);
+        out.gauge("blkmine_store_classes", "Number of ann classes in the store", &[], cls as f64);
+    }
+    if let Some(spray) = &bm.spray {
+        spray.metrics(out);
+    }
+    for dl in bm.downloaders.lock().unwrap().iter() {
+        let l = [("handler", dl.url.as_str())];
+        out.counter(
+            "blkmine_downloaded_anns_total",
+            "Announcements downloaded from each handler",
+            &l,
+            dl.downloaded_anns.get() as f64,
+        );
+        out.counter(
+            "blkmine_skipped_files_total",
+            "Ann files skipped because we fell behind",
+            &l,
+            dl.skipped_files.get() as f64,
+        );
+    }
+}
+
+async fn stats_loop(bm: &BlkMine) {
+    loop {
+        let (rdy, spare, cls, imm) = class_stats(bm);
         let spr = util::pad_to(
             27,
             format!("rdy: {} spr: {} imm: {} cls: {}", rdy, spare, imm, cls),
@@ -569,12 +693,34 @@ async fn stats_loop(bm: &BlkMine) {
             let st = spray.get_peer_stats();
             let v = st
                 .iter()
-                .map(|s| format!("{}", s.packets_in / 1024))
+                .map(|s| {
+                    if s.loss > 0.0 {
+                        format!("{} ({:.1}% lost)", s.packets_in / 1024, s.loss * 100.0)
+                    } else {
+                        format!("{}", s.packets_in / 1024)
+                    }
+                })
                 .collect::<Vec<_>>()
                 .join(", ");
             format!(" {} <- [ {} ]", spr, v)
         } else {
-            format!(" {} <- <sprayer disabled>", spr)
+            let dls = bm.downloaders.lock().unwrap();
+            if dls.is_empty() {
+                format!(" {} <- <no downloaders>", spr)
+            } else {
+                let v = dls
+                    .iter()
+                    .map(|dl| {
+                        let skipped = dl.skipped_files.take();
+                        if skipped > 0 {
+                            warn!("Skipped {} ann files from {}, falling behind", skipped, dl.url);
+                        }
+                        format!("{}", dl.downloaded_anns.take())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(" {} <- http [ {} ]", spr, v)
+            }
         };
         let start_mining = match get_current_mining(bm) {
             None => {
@@ -904,8 +1050,12 @@ impl BlkMine {
         if let Some(spray) = &self.spray {
             spray.set_handler(self.clone());
             spray.start();
+        } else if self.ba.downloader_count > 0 {
+            info!("Sprayer disabled, downloading anns over http");
+            let a = self.clone();
+            tokio::spawn(async move { downloader_loop(&a).await });
         } else {
-            warn!("Sprayer disabled.")
+            warn!("Sprayer disabled and --downloaders is zero, no anns will be received.")
         }
         {
             let a = self.clone();
@@ -0,0 +1,289 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use anyhow::{bail, Result};
+use core::time::Duration;
+use log::{debug, info, trace, warn};
+use packetcrypt_sprayer::OnAnns;
+use packetcrypt_util::metrics::Counter;
+use packetcrypt_util::protocol::AnnIndex;
+use packetcrypt_util::util;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// How often to re-fetch the index.json of each handler
+const POLL_INDEX_MS: u64 = 5_000;
+
+// How long to wait for a single ann file or index download
+const DOWNLOAD_TIMEOUT_SECS: u64 = 60;
+
+// Never queue more than this many files from one handler, if we fall further
+// behind than this, the oldest files are skipped because they're least valuable.
+const MAX_QUEUED_FILES: usize = 256;
+
+// Times to try downloading a file before it is skipped, and how long a worker waits
+// after a failed download.
+const MAX_DOWNLOAD_TRIES: u32 = 3;
+const RETRY_DOWNLOAD_MS: u64 = 1_000;
+
+struct DownloaderM {
+    // Highest file number which has been queued for download, -1 = none yet
+    highest_queued: i64,
+    // File number, url and the number of failed tries
+    queue: VecDeque<(i64, String, u32)>,
+}
+
+pub struct DownloaderS {
+    pub url: String,
+    m: Mutex<DownloaderM>,
+    handler: Arc<dyn OnAnns>,
+    client: reqwest::Client,
+    passwd: String,
+    workers: usize,
+    shutdown: AtomicBool,
+
+    pub downloaded_anns: Counter,
+    pub skipped_files: Counter,
+}
+pub type Downloader = Arc<DownloaderS>;
+
+pub fn new(
+    url: &str,
+    workers: usize,
+    passwd: &str,
+    handler: Arc<dyn OnAnns>,
+) -> Result<Downloader> {
+    let client = reqwest::ClientBuilder::new()
+        .timeout(Duration::from_secs(DOWNLOAD_TIMEOUT_SECS))
+        .build()?;
+    Ok(Arc::new(DownloaderS {
+        url: String::from(url),
+        m: Mutex::new(DownloaderM {
+            highest_queued: -1,
+            queue: VecDeque::new(),
+        }),
+        handler,
+        client,
+        passwd: String::from(passwd),
+        workers,
+        shutdown: AtomicBool::new(false),
+        downloaded_anns: Counter::default(),
+        skipped_files: Counter::default(),
+    }))
+}
+
+/// Parse the number out of a file name such as anns_123.bin
+pub fn ann_file_num(name: &str) -> Option<i64> {
+    name.strip_prefix("anns_")?
+        .strip_suffix(".bin")?
+        .parse::<i64>()
+        .ok()
+}
+
+async fn get_bin(dl: &Downloader, url: &str) -> Result<Option<bytes::Bytes>> {
+    let mut req = dl.client.get(url);
+    if !dl.passwd.is_empty() {
+        req = req.basic_auth("x", Some(&dl.passwd));
+    }
+    let res = req.send().await?;
+    match res.status() {
+        reqwest::StatusCode::OK => Ok(Some(res.bytes().await?)),
+        // File was deleted by the handler before we got to it
+        reqwest::StatusCode::NOT_FOUND => Ok(None),
+        st => bail!("Status code was {:?}", st),
+    }
+}
+
+fn queue_files(dl: &Downloader, idx: AnnIndex) {
+    let mut m = dl.m.lock().unwrap();
+    if idx.highest_ann_file < m.highest_queued {
+        info!(
+            "Handler [{}] went back from file {} to {}, probably restarted",
+            dl.url, m.highest_queued, idx.highest_ann_file
+        );
+        m.highest_queued = -1;
+        m.queue.clear();
+    }
+    let mut files = idx
+        .files
+        .iter()
+        .filter_map(|f| ann_file_num(f).map(|n| (n, f)))
+        .filter(|(n, _)| *n > m.highest_queued)
+        .collect::<Vec<_>>();
+    files.sort_unstable_by_key(|(n, _)| *n);
+    for (n, f) in files {
+        m.queue.push_back((n, format!("{}/{}", dl.url, f), 0));
+        m.highest_queued = n;
+    }
+    while m.queue.len() > MAX_QUEUED_FILES {
+        m.queue.pop_front();
+        dl.skipped_files.add(1);
+    }
+}
+
+// Put a file back in the queue after a failed download, unless it has been tried too
+// many times or the handler restarted in the meantime. Returns true if it was queued.
+fn retry_file(dl: &Downloader, num: i64, url: String, tries: u32) -> bool {
+    let mut m = dl.m.lock().unwrap();
+    if tries + 1 >= MAX_DOWNLOAD_TRIES || num > m.highest_queued {
+        dl.skipped_files.add(1);
+        return false;
+    }
+    m.queue.push_back((num, url, tries + 1));
+    true
+}
+
+async fn index_loop(dl: &Downloader) {
+    let url = format!("{}/index.json", dl.url);
+    loop {
+        if dl.shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        match get_bin(dl, &url).await {
+            Ok(Some(bin)) => match serde_json::from_slice::<AnnIndex>(&bin[..]) {
+                Ok(idx) => queue_files(dl, idx),
+                Err(e) => warn!("Unable to parse index [{}] {:?}", url, e),
+            },
+            Ok(None) => warn!("Index [{}] does not exist", url),
+            Err(e) => warn!("Unable to get index [{}] {}", url, e),
+        }
+        util::sleep_ms(POLL_INDEX_MS).await;
+    }
+    debug!("Index poller for {} shutting down", dl.url);
+}
+
+async fn download_file(dl: &Downloader, num: i64, url: &str) -> Result<()> {
+    let bin = if let Some(bin) = get_bin(dl, url).await? {
+        bin
+    } else {
+        debug!("Ann file {} was deleted before we could get it", url);
+        dl.skipped_files.add(1);
+        return Ok(());
+    };
+    if bin.len() % 1024 != 0 {
+        bail!(
+            "Ann file {} has length {} which is not a multiple of 1024",
+            url,
+            bin.len()
+        );
+    }
+    let count = bin.len() / 1024;
+    trace!("Got {} anns from file {} of {}", count, num, dl.url);
+    let dl1 = Arc::clone(dl);
+    // on_anns is cpu-intensive so it must not be run on the async executor
+    tokio::task::spawn_blocking(move || {
+        let anns = bin.chunks(1024).collect::<Vec<_>>();
+        dl1.handler.on_anns(&anns[..]);
+    })
+    .await?;
+    dl.downloaded_anns.add(count as u64);
+    Ok(())
+}
+
+async fn download_loop(dl: &Downloader) {
+    loop {
+        if dl.shutdown.load(Ordering::Relaxed) {
+            break;
+        }
+        let next = { dl.m.lock().unwrap().queue.pop_front() };
+        match next {
+            Some((num, url, tries)) => {
+                if let Err(e) = download_file(dl, num, &url).await {
+                    warn!("Error downloading ann file {}: {}", url, e);
+                    if !retry_file(dl, num, url, tries) {
+                        warn!("Giving up on ann file {} of {}", num, dl.url);
+                    }
+                    util::sleep_ms(RETRY_DOWNLOAD_MS).await;
+                }
+            }
+            None => util::sleep_ms(100).await,
+        }
+    }
+    debug!("Downloader for {} shutting down", dl.url);
+}
+
+pub fn start(dl: &Downloader) {
+    packetcrypt_util::async_spawn!(dl, {
+        index_loop(&dl).await;
+    });
+    for _ in 0..dl.workers {
+        packetcrypt_util::async_spawn!(dl, {
+            download_loop(&dl).await;
+        });
+    }
+}
+
+pub fn stop(dl: &Downloader) {
+    dl.shutdown.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(highest_ann_file: i64, nums: &[i64]) -> AnnIndex {
+        AnnIndex {
+            highest_ann_file,
+            files: nums.iter().map(|n| format!("anns_{}.bin", n)).collect(),
+        }
+    }
+
+    fn queued(dl: &Downloader) -> Vec<(i64, u32)> {
+        let m = dl.m.lock().unwrap();
+        m.queue.iter().map(|(n, _, tries)| (*n, *tries)).collect()
+    }
+
+    #[test]
+    fn test_ann_file_num() {
+        assert_eq!(ann_file_num("anns_0.bin"), Some(0));
+        assert_eq!(ann_file_num("anns_12345.bin"), Some(12345));
+        assert_eq!(ann_file_num("anns_.bin"), None);
+        assert_eq!(ann_file_num("index.json"), None);
+        assert_eq!(ann_file_num("anns_1.bin.tmp"), None);
+    }
+
+    #[test]
+    fn test_queue_files() {
+        let dl = new("http://handler", 1, "", Arc::new(None::<()>)).unwrap();
+        let mut idx = index(3, &[3, 1, 2]);
+        idx.files.push("index.json".to_owned());
+        queue_files(&dl, idx);
+        assert_eq!(queued(&dl), vec![(1, 0), (2, 0), (3, 0)]);
+        assert_eq!(dl.m.lock().unwrap().queue[0].1, "http://handler/anns_1.bin");
+
+        // Only the new ones are added
+        dl.m.lock().unwrap().queue.pop_front();
+        queue_files(&dl, index(4, &[2, 3, 4]));
+        assert_eq!(queued(&dl), vec![(2, 0), (3, 0), (4, 0)]);
+
+        // The handler restarted and is numbering from the beginning
+        queue_files(&dl, index(1, &[1]));
+        assert_eq!(queued(&dl), vec![(1, 0)]);
+
+        // Too far behind, the oldest are skipped
+        let nums = (2..(MAX_QUEUED_FILES as i64 + 12)).collect::<Vec<_>>();
+        queue_files(&dl, index(*nums.last().unwrap(), &nums));
+        let q = queued(&dl);
+        assert_eq!((q.len(), q[0].0), (MAX_QUEUED_FILES, 12));
+        assert_eq!(dl.skipped_files.get(), 11);
+    }
+
+    #[test]
+    fn test_retry_file() {
+        let dl = new("http://handler", 1, "", Arc::new(None::<()>)).unwrap();
+        queue_files(&dl, index(5, &[5]));
+        let (num, url, tries) = dl.m.lock().unwrap().queue.pop_front().unwrap();
+        assert!(retry_file(&dl, num, url.clone(), tries));
+        assert_eq!(queued(&dl), vec![(5, 1)]);
+        dl.m.lock().unwrap().queue.clear();
+        assert!(retry_file(&dl, num, url.clone(), 1));
+        dl.m.lock().unwrap().queue.clear();
+        assert!(!retry_file(&dl, num, url.clone(), MAX_DOWNLOAD_TRIES - 1));
+        assert_eq!(dl.skipped_files.get(), 1);
+
+        // Not after the handler restarted
+        queue_files(&dl, index(2, &[2]));
+        assert!(!retry_file(&dl, num, url, 0));
+        assert_eq!(queued(&dl), vec![(2, 0)]);
+        assert_eq!(dl.skipped_files.get(), 2);
+    }
+}
@@ -5,6 +5,7 @@ mod blkminer;
 mod prooftree;
 mod types;
 mod databuf;
+mod downloader;
 
 pub mod bench;
 pub mod blkmine;
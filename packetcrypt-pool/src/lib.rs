@@ -1,3 +1,5 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+pub mod master;
+pub mod paymaker;
 pub mod paymakerclient;
 pub mod poolcfg;
@@ -0,0 +1,647 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::poolcfg::MasterCfg;
+use anyhow::{bail, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{info, warn};
+use packetcrypt_util::metrics::{Counter, MetricsOut};
+use packetcrypt_util::protocol::{
+    self, BlkShare, BlockHeader, BlockInfo, BlockSubmitReply, MasterConf, Work,
+};
+use packetcrypt_util::rpcclient::{self, BlockTemplate, RpcClient};
+use packetcrypt_util::{hash, util};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::Filter;
+
+// How often to ask the node whether there is a new tip
+const POLL_TIP_MS: u64 = 1_000;
+
+// Number of old work files to continue serving, miners who are a few blocks behind
+// will still be able to get the work that they are asking for.
+const WORK_HISTORY: usize = 10;
+
+// Number of blocks back from the tip which block infos are served for, this needs to
+// be more than the history which any miner or handler back-fills.
+const BLKINFO_HISTORY: i32 = 16;
+
+// Master conf version numbers, these are understood by the miners and handlers
+const MASTER_VERSION: u32 = 2;
+const MASTER_SOFT_VERSION: u32 = 1;
+
+pub const COINBASE_COMMIT_LEN: usize = 50;
+
+// OP_RETURN, push 48 bytes, then the commitment magic followed by 44 bytes of 0xfc
+// which the block miner will replace with the real commitment.
+const COINBASE_COMMIT_PREFIX: [u8; 6] = [0x6a, 0x30, 0x09, 0xf9, 0x11, 0x02];
+
+// Tag in the coinbase scriptSig so that the blocks are recognizable
+const COINBASE_TAG: &[u8] = b"/packetcrypt-rs/";
+
+// PacketCrypt proof entity which terminates the list
+const PC_TYPE_END: u64 = 0;
+
+// The template and the work which was made from it, this is kept so that blocks can
+// be reassembled once a solution is found.
+struct Template {
+    template: BlockTemplate,
+    work: Work,
+    work_bin: Bytes,
+}
+
+struct MasterM {
+    conf: Option<MasterConf>,
+    templates: BTreeMap<i32, Arc<Template>>,
+    // Only the recent blocks, so that nobody can make us ask the node about others
+    blkinfo: HashMap<[u8; 32], BlockInfo>,
+}
+
+pub struct MasterS {
+    cfg: MasterCfg,
+    master_url: String,
+    pay_to_script: Vec<u8>,
+    rpc: RpcClient,
+    sockaddr: SocketAddr,
+    m: Mutex<MasterM>,
+    blocks_accepted: Counter,
+    blocks_failed: Counter,
+}
+pub type Master = Arc<MasterS>;
+
+pub fn new(cfg: MasterCfg, master_url: &str) -> Result<Master> {
+    let pay_to_script = match hex::decode(&cfg.pay_to_script) {
+        Ok(x) if !x.is_empty() => x,
+        _ => bail!(
+            "pay_to_script [{}] is not a valid hex script",
+            cfg.pay_to_script
+        ),
+    };
+    if cfg.submit_ann_urls.is_empty() {
+        bail!("submit_ann_urls must contain at least one ann handler");
+    }
+    if cfg.submit_block_urls.is_empty() {
+        bail!("submit_block_urls must contain at least one block handler");
+    }
+    let sockaddr = cfg.bind.parse()?;
+    let rpc = rpcclient::new(&cfg.rpc_url, &cfg.rpc_user, &cfg.rpc_pass)?;
+    Ok(Arc::new(MasterS {
+        master_url: String::from(master_url),
+        pay_to_script,
+        rpc,
+        sockaddr,
+        blocks_accepted: Counter::default(),
+        blocks_failed: Counter::default(),
+        m: Mutex::new(MasterM {
+            conf: None,
+            templates: BTreeMap::new(),
+            blkinfo: HashMap::new(),
+        }),
+        cfg,
+    }))
+}
+
+// Push the block height into a script the way BIP-34 wants it
+fn put_script_height(height: i32, b: &mut Vec<u8>) {
+    if height == 0 {
+        b.push(0x00);
+        return;
+    } else if (1..=16).contains(&height) {
+        b.push(0x50 + height as u8);
+        return;
+    }
+    let mut num = Vec::new();
+    let mut h = height as u32;
+    while h > 0 {
+        num.push((h & 0xff) as u8);
+        h >>= 8;
+    }
+    // If the top bit is set, it would be read as negative
+    if num[num.len() - 1] & 0x80 != 0 {
+        num.push(0);
+    }
+    b.push(num.len() as u8);
+    b.extend_from_slice(&num[..]);
+}
+
+fn put_output(value: u64, script: &[u8], b: &mut BytesMut) {
+    b.put_u64_le(value);
+    protocol::put_varint(script.len() as u64, b);
+    b.put(script);
+}
+
+/// Build the coinbase transaction (without witness) containing the commit pattern
+/// which the block miner will fill in.
+pub fn make_coinbase(
+    height: i32,
+    value: u64,
+    pay_to_script: &[u8],
+    witness_commitment: Option<&[u8]>,
+) -> Bytes {
+    let mut script_sig = Vec::new();
+    put_script_height(height, &mut script_sig);
+    script_sig.push(COINBASE_TAG.len() as u8);
+    script_sig.extend_from_slice(COINBASE_TAG);
+
+    let mut commit = [0xfc_u8; COINBASE_COMMIT_LEN];
+    commit[0..COINBASE_COMMIT_PREFIX.len()].copy_from_slice(&COINBASE_COMMIT_PREFIX);
+
+    let mut b = BytesMut::with_capacity(256);
+    b.put_u32_le(1);
+    // Inputs
+    protocol::put_varint(1, &mut b);
+    b.put(&[0_u8; 32][..]);
+    b.put_u32_le(0xffff_ffff);
+    protocol::put_varint(script_sig.len() as u64, &mut b);
+    b.put(&script_sig[..]);
+    b.put_u32_le(0xffff_ffff);
+    // Outputs
+    protocol::put_varint(if witness_commitment.is_some() { 3 } else { 2 }, &mut b);
+    put_output(value, pay_to_script, &mut b);
+    put_output(0, &commit[..], &mut b);
+    if let Some(wc) = witness_commitment {
+        put_output(0, wc, &mut b);
+    }
+    // Locktime
+    b.put_u32_le(0);
+    b.freeze()
+}
+
+/// Compute the merkle branch which is needed to get from the coinbase to the merkle root.
+/// Hashes are in internal byte order, the coinbase is assumed to be at index 0.
+pub fn merkle_branch(txids: &[[u8; 32]]) -> Vec<Bytes> {
+    let mut out = Vec::new();
+    // First entry is a placeholder for the coinbase
+    let mut level: Vec<[u8; 32]> = Vec::with_capacity(txids.len() + 1);
+    level.push([0_u8; 32]);
+    level.extend_from_slice(txids);
+    let mut buf = [0_u8; 64];
+    while level.len() > 1 {
+        if level.len() % 2 != 0 {
+            level.push(level[level.len() - 1]);
+        }
+        out.push(Bytes::copy_from_slice(&level[1][..]));
+        level = level
+            .chunks(2)
+            .map(|pair| {
+                buf[0..32].copy_from_slice(&pair[0]);
+                buf[32..].copy_from_slice(&pair[1]);
+                hash::compress_dsha256(&buf[..])
+            })
+            .collect();
+    }
+    out
+}
+
+// Hashes which come from the RPC are byte-swapped
+fn rpc_hash(s: &[u8; 32]) -> [u8; 32] {
+    let mut out = *s;
+    out.reverse();
+    out
+}
+
+fn parse_rpc_hash(s: &str) -> Result<[u8; 32]> {
+    let mut out = [0_u8; 32];
+    hex::decode_to_slice(s, &mut out)?;
+    Ok(rpc_hash(&out))
+}
+
+fn make_work(m: &Master, template: &BlockTemplate) -> Result<Work> {
+    let witness_commitment = if let Some(wc) = &template.default_witness_commitment {
+        Some(hex::decode(wc)?)
+    } else {
+        None
+    };
+    let txids = template
+        .transactions
+        .iter()
+        .map(|tx| parse_rpc_hash(&tx.txid))
+        .collect::<Result<Vec<_>>>()?;
+    let mut bits = [0_u8; 4];
+    hex::decode_to_slice(&template.bits, &mut bits)?;
+    Ok(Work {
+        header: BlockHeader {
+            version: template.version,
+            hash_prev_block: rpc_hash(&template.previousblockhash),
+            hash_merkle_root: [0_u8; 32],
+            time_seconds: template.curtime as i32,
+            work_bits: u32::from_be_bytes(bits),
+            nonce: 0,
+        },
+        signing_key: [0_u8; 32],
+        share_target: m.cfg.share_target,
+        ann_target: m.cfg.ann_target,
+        height: template.height,
+        coinbase_no_witness: make_coinbase(
+            template.height,
+            template.coinbasevalue,
+            &m.pay_to_script[..],
+            witness_commitment.as_deref(),
+        ),
+        coinbase_merkle: merkle_branch(&txids[..]),
+    })
+}
+
+async fn get_blkinfo(m: &Master, hash: &[u8; 32]) -> Result<BlockInfo> {
+    if let Some(bi) = m.m.lock().await.blkinfo.get(hash) {
+        return Ok(*bi);
+    }
+    let header = rpcclient::get_block_header(&m.rpc, hash).await?;
+    Ok(BlockInfo {
+        header,
+        sig_key: None,
+    })
+}
+
+// Keep the infos for a new tip and the blocks behind it, forgetting any which are
+// more than BLKINFO_HISTORY blocks back.
+fn keep_blkinfo(mm: &mut MasterM, infos: &[BlockInfo]) {
+    let top = if let Some(tip) = infos.first() {
+        tip.header.height
+    } else {
+        return;
+    };
+    for bi in infos {
+        mm.blkinfo.insert(bi.header.hash, *bi);
+    }
+    mm.blkinfo
+        .retain(|_, bi| bi.header.height > top - BLKINFO_HISTORY);
+}
+
+// Get the info for the tip along with the blocks behind it which are needed to serve
+// anyone back-filling their chain, normally only the tip is not already known.
+async fn update_blkinfo(m: &Master, tip: &[u8; 32]) -> Result<BlockInfo> {
+    let bi = get_blkinfo(m, tip).await?;
+    let mut infos = vec![bi];
+    let mut parent = bi;
+    while infos.len() < BLKINFO_HISTORY as usize && parent.header.height > 0 {
+        parent = get_blkinfo(m, &parent.header.previousblockhash).await?;
+        infos.push(parent);
+    }
+    keep_blkinfo(&mut *m.m.lock().await, &infos);
+    Ok(bi)
+}
+
+async fn poll_tip(m: &Master) -> Result<()> {
+    let tip = rpcclient::get_best_block_hash(&m.rpc).await?;
+    if let Some(conf) = &m.m.lock().await.conf {
+        if conf.tip_hash == Some(tip) {
+            return Ok(());
+        }
+    }
+    let bi = update_blkinfo(m, &tip).await?;
+    let template = rpcclient::get_block_template(&m.rpc).await?;
+    if template.previousblockhash != tip {
+        bail!(
+            "Template parent [{}] is not the tip [{}], node is still syncing",
+            hex::encode(template.previousblockhash),
+            hex::encode(tip)
+        );
+    }
+    let work = make_work(m, &template)?;
+    let mut work_bin = BytesMut::new();
+    protocol::work_encode(&work, &mut work_bin);
+    let conf = MasterConf {
+        tip_hash: Some(tip),
+        current_height: bi.header.height + 1,
+        master_url: m.master_url.clone(),
+        submit_ann_urls: m.cfg.submit_ann_urls.clone(),
+        download_ann_urls: m.cfg.download_ann_urls.clone(),
+        submit_block_urls: m.cfg.submit_block_urls.clone(),
+        paymaker_url: m.cfg.paymaker_url.clone(),
+        version: MASTER_VERSION,
+        soft_version: MASTER_SOFT_VERSION,
+        ann_versions: vec![1],
+        mine_old_anns: m.cfg.mine_old_anns,
+        ann_target: Some(m.cfg.ann_target),
+    };
+    info!(
+        "New tip [{} @ {}] {} transactions",
+        hex::encode(tip),
+        bi.header.height,
+        template.transactions.len()
+    );
+    let mut mm = m.m.lock().await;
+    mm.templates.insert(
+        conf.current_height,
+        Arc::new(Template {
+            template,
+            work,
+            work_bin: work_bin.freeze(),
+        }),
+    );
+    while mm.templates.len() > WORK_HISTORY {
+        let oldest = *mm.templates.keys().next().unwrap();
+        mm.templates.remove(&oldest);
+    }
+    mm.conf = Some(conf);
+    Ok(())
+}
+
+async fn poll_loop(m: &Master) {
+    loop {
+        if let Err(e) = poll_tip(m).await {
+            warn!("Unable to get work from node: {}", e);
+            util::sleep_ms(5_000).await;
+        }
+        util::sleep_ms(POLL_TIP_MS).await;
+    }
+}
+
+/// Replace the commit pattern in the coinbase with the real commitment from the miner.
+pub fn patch_coinbase(coinbase_no_witness: &[u8], commit: &[u8]) -> Result<BytesMut> {
+    if commit.len() != COINBASE_COMMIT_LEN - 2 {
+        bail!("Coinbase commit has wrong length {}", commit.len());
+    }
+    let mut pattern = [0xfc_u8; COINBASE_COMMIT_LEN];
+    pattern[0..COINBASE_COMMIT_PREFIX.len()].copy_from_slice(&COINBASE_COMMIT_PREFIX);
+    let pos = if let Some(pos) = coinbase_no_witness
+        .windows(COINBASE_COMMIT_LEN)
+        .position(|w| w == pattern)
+    {
+        pos
+    } else {
+        bail!("Coinbase does not contain the commit pattern");
+    };
+    let mut cb = BytesMut::from(coinbase_no_witness);
+    cb[(pos + 2)..(pos + COINBASE_COMMIT_LEN)].copy_from_slice(commit);
+    Ok(cb)
+}
+
+// Serialize a whole block out of the template and the header and proof from the miner.
+fn make_block(t: &Template, share: &BlkShare) -> Result<BytesMut> {
+    if share.header_and_proof.len() < 80 {
+        bail!("runt header_and_proof");
+    }
+    let cb = patch_coinbase(&t.work.coinbase_no_witness[..], &share.coinbase_commit[..])?;
+    let mut out = BytesMut::with_capacity(share.header_and_proof.len() + cb.len() + 1024);
+    out.put(&share.header_and_proof[..]);
+    protocol::put_varint(PC_TYPE_END, &mut out);
+    protocol::put_varint(0, &mut out);
+    protocol::put_varint(t.template.transactions.len() as u64 + 1, &mut out);
+    if t.template.default_witness_commitment.is_some() {
+        // Segwit block, the coinbase needs to carry the witness reserved value
+        out.put(&cb[0..4]);
+        out.put_u8(0x00); // marker
+        out.put_u8(0x01); // flag
+        out.put(&cb[4..(cb.len() - 4)]);
+        protocol::put_varint(1, &mut out);
+        protocol::put_varint(32, &mut out);
+        out.put(&[0_u8; 32][..]);
+        out.put(&cb[(cb.len() - 4)..]);
+    } else {
+        out.put(&cb[..]);
+    }
+    for tx in &t.template.transactions {
+        out.put(&hex::decode(&tx.data)?[..]);
+    }
+    Ok(out)
+}
+
+async fn submit_block(m: &Master, share: &BlkShare) -> Result<String> {
+    if share.header_and_proof.len() < 80 {
+        bail!("runt header_and_proof");
+    }
+    let mut prev_hash = [0_u8; 32];
+    prev_hash.copy_from_slice(&share.header_and_proof[4..36]);
+    let t = {
+        let mm = m.m.lock().await;
+        if let Some(t) = mm
+            .templates
+            .values()
+            .find(|t| t.work.header.hash_prev_block == prev_hash)
+        {
+            Arc::clone(t)
+        } else {
+            bail!(
+                "No template for block with parent [{}]",
+                hex::encode(rpc_hash(&prev_hash))
+            );
+        }
+    };
+    let block = make_block(&t, share)?;
+    let hash = hex::encode(rpc_hash(&hash::compress_dsha256(
+        &share.header_and_proof[0..80],
+    )));
+    info!("Submitting block [{}] @ {}", hash, t.work.height);
+    if let Some(reason) = rpcclient::submit_block(&m.rpc, &block[..]).await? {
+        bail!("Node rejected block [{}]: {}", hash, reason);
+    }
+    info!("Block [{}] accepted", hash);
+    Ok(hash)
+}
+
+async fn handle_submit(m: Master, share: BlkShare) -> Result<impl warp::Reply, Infallible> {
+    let (reply, status) = match submit_block(&m, &share).await {
+        Ok(hash) => {
+            m.blocks_accepted.add(1);
+            (
+                BlockSubmitReply {
+                    warn: Vec::new(),
+                    error: Vec::new(),
+                    result: Some(hash),
+                },
+                warp::http::StatusCode::OK,
+            )
+        }
+        Err(e) => {
+            m.blocks_failed.add(1);
+            warn!("Unable to submit block: {}", e);
+            (
+                BlockSubmitReply {
+                    warn: Vec::new(),
+                    error: vec![format!("{}", e)],
+                    result: None,
+                },
+                warp::http::StatusCode::BAD_REQUEST,
+            )
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&reply), status))
+}
+
+fn not_found() -> Box<dyn warp::Reply> {
+    Box::new(warp::reply::with_status(
+        "not found",
+        warp::http::StatusCode::NOT_FOUND,
+    ))
+}
+
+async fn handle_get(m: Master, tail: warp::path::Tail) -> Result<Box<dyn warp::Reply>, Infallible> {
+    // Only the last path segment is considered, so that the master may be served
+    // behind any prefix, e.g. http://my.pool/master/config.json
+    let file = tail.as_str().rsplit('/').next().unwrap_or("");
+    if file == "config.json" {
+        return Ok(match &m.m.lock().await.conf {
+            Some(conf) => Box::new(warp::reply::json(conf)),
+            None => not_found(),
+        });
+    }
+    if let Some(height) = file
+        .strip_prefix("work_")
+        .and_then(|x| x.strip_suffix(".bin"))
+        .and_then(|x| x.parse::<i32>().ok())
+    {
+        return Ok(match m.m.lock().await.templates.get(&height) {
+            Some(t) => Box::new(t.work_bin.to_vec()),
+            None => not_found(),
+        });
+    }
+    let hash = file
+        .strip_prefix("blkinfo_")
+        .and_then(|x| x.strip_suffix(".json"))
+        .and_then(|x| {
+            let mut hash = [0_u8; 32];
+            hex::decode_to_slice(x, &mut hash).ok().map(|_| hash)
+        });
+    if let Some(hash) = hash {
+        return Ok(match m.m.lock().await.blkinfo.get(&hash) {
+            Some(bi) => Box::new(warp::reply::json(bi)),
+            None => not_found(),
+        });
+    }
+    Ok(not_found())
+}
+
+pub fn metrics(m: &Master, out: &mut MetricsOut) {
+    let blocks = [
+        ("accepted", &m.blocks_accepted),
+        ("failed", &m.blocks_failed),
+    ];
+    for (result, c) in &blocks {
+        out.counter(
+            "master_blocks_submitted_total",
+            "Blocks submitted to the node, by result",
+            &[("result", result)],
+            c.get() as f64,
+        );
+    }
+    // Skip rather than block if the poller is busy
+    if let Ok(mm) = m.m.try_lock() {
+        if let Some(conf) = &mm.conf {
+            out.gauge(
+                "master_height",
+                "Height of the block currently being mined",
+                &[],
+                conf.current_height as f64,
+            );
+        }
+    }
+}
+
+pub async fn start(m: &Master) {
+    let get = warp::get()
+        .and((|m: Master| warp::any().map(move || m.clone()))(m.clone()))
+        .and(warp::path::tail())
+        .and_then(handle_get);
+
+    // Block handlers post here when a share is good enough to be a block
+    let submit = warp::post()
+        .and(warp::path("submitblock"))
+        .and(warp::path::end())
+        .and((|m: Master| warp::any().map(move || m.clone()))(m.clone()))
+        .and(warp::body::json())
+        .and_then(handle_submit);
+
+    packetcrypt_util::async_spawn!(m, { warp::serve(submit.or(get)).run(m.sockaddr).await });
+
+    packetcrypt_util::async_spawn!(m, {
+        poll_loop(&m).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merkle_root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+        let mut buf = [0_u8; 64];
+        while level.len() > 1 {
+            if level.len() % 2 != 0 {
+                level.push(level[level.len() - 1]);
+            }
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    buf[0..32].copy_from_slice(&pair[0]);
+                    buf[32..].copy_from_slice(&pair[1]);
+                    hash::compress_dsha256(&buf[..])
+                })
+                .collect();
+        }
+        level[0]
+    }
+
+    #[test]
+    fn test_merkle_branch() {
+        for count in 0..9 {
+            let txids = (1..=count).map(|i| [i as u8; 32]).collect::<Vec<_>>();
+            let coinbase = [0xcc_u8; 32];
+            let mut all = vec![coinbase];
+            all.extend_from_slice(&txids[..]);
+
+            // This is how the block miner computes the root
+            let mut buf = [0_u8; 64];
+            buf[0..32].copy_from_slice(&coinbase);
+            for h in merkle_branch(&txids[..]) {
+                buf[32..].copy_from_slice(&h);
+                let h = hash::compress_dsha256(&buf[..]);
+                buf[0..32].copy_from_slice(&h);
+            }
+            assert_eq!(buf[0..32], merkle_root(all)[..], "{} txns", count);
+        }
+    }
+
+    #[test]
+    fn test_script_height() {
+        let mut s = Vec::new();
+        put_script_height(5, &mut s);
+        assert_eq!(s, vec![0x55]);
+        s.clear();
+        put_script_height(128, &mut s);
+        assert_eq!(s, vec![0x02, 0x80, 0x00]);
+        s.clear();
+        put_script_height(1_000_000, &mut s);
+        assert_eq!(s, vec![0x03, 0x40, 0x42, 0x0f]);
+    }
+
+    #[test]
+    fn test_coinbase_has_commit() {
+        let cb = make_coinbase(1234, 1000, &[0x51], None);
+        let mut pattern = [0xfc_u8; COINBASE_COMMIT_LEN];
+        pattern[0..COINBASE_COMMIT_PREFIX.len()].copy_from_slice(&COINBASE_COMMIT_PREFIX);
+        assert_eq!(
+            cb.windows(COINBASE_COMMIT_LEN)
+                .filter(|w| *w == &pattern[..])
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_keep_blkinfo() {
+        let mut mm = MasterM {
+            conf: None,
+            templates: BTreeMap::new(),
+            blkinfo: HashMap::new(),
+        };
+        let info = |height: i32| {
+            let mut bi = BlockInfo::default();
+            bi.header.height = height;
+            bi.header.hash = [height as u8; 32];
+            bi
+        };
+        let infos = (0..=20).rev().map(info).collect::<Vec<_>>();
+        keep_blkinfo(&mut mm, &infos);
+        assert_eq!(mm.blkinfo.len(), BLKINFO_HISTORY as usize);
+        assert!(mm.blkinfo.get(&[4_u8; 32]).is_none());
+        assert_eq!(mm.blkinfo.get(&[5_u8; 32]).unwrap().header.height, 5);
+
+        // Only the new tip has to be added
+        keep_blkinfo(&mut mm, &[info(21)]);
+        assert_eq!(mm.blkinfo.len(), BLKINFO_HISTORY as usize);
+        assert!(mm.blkinfo.get(&[5_u8; 32]).is_none());
+        assert!(mm.blkinfo.get(&[21_u8; 32]).is_some());
+    }
+}
@@ -0,0 +1,489 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::poolcfg::PaymakerCfg;
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use packetcrypt_sys::sodiumoxide::utils::memcmp;
+use packetcrypt_util::metrics::{Counter, MetricsOut};
+use packetcrypt_util::protocol::{AnnsEvent, BlkShareEvent, PaymakerReply, PaymakerResult};
+use packetcrypt_util::{hash, util};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use warp::Filter;
+
+// How often to drop credits which have fallen out of the window
+const PRUNE_EVERY_MS: u64 = 60_000;
+
+const CREDITS_FILE: &str = "credits.ndjson";
+
+// How far ahead of our clock an event may be, anything later would stay in the window
+// and be paid for long after it should have fallen out.
+const MAX_FUTURE_MS: u64 = 60_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum CreditKind {
+    Ann,
+    Blk,
+}
+
+// One paylog event reduced to the amount of work which it represents
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Credit {
+    event_id: String,
+    kind: CreditKind,
+    pay_to: String,
+    time: u64,
+    work: f64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PayoutTable {
+    pub window_start_ms: u64,
+    pub window_end_ms: u64,
+    pub ann_fraction: f64,
+    pub ann_work: BTreeMap<String, f64>,
+    pub blk_work: BTreeMap<String, f64>,
+
+    // Fraction of the block reward which should be paid to each address, sums to 1
+    pub payouts: BTreeMap<String, f64>,
+}
+
+struct PaymakerM {
+    seen: HashSet<String>,
+    credits: Vec<Credit>,
+    credits_file: File,
+}
+
+pub struct PaymakerS {
+    cfg: PaymakerCfg,
+    password: String,
+    workdir: String,
+    tmpdir: String,
+    sockaddr: SocketAddr,
+    m: Mutex<PaymakerM>,
+    events_credited: Counter,
+}
+pub type Paymaker = Arc<PaymakerS>;
+
+/// Approximate number of hashes needed to meet a compact target.
+pub fn work_for_compact(compact: u32) -> f64 {
+    let exp = (compact >> 24) as i32;
+    let mantissa = (compact & 0x007fffff) as f64;
+    if mantissa == 0.0 {
+        return 0.0;
+    }
+    let target = mantissa * 256_f64.powi(exp - 3);
+    2_f64.powi(256) / (target + 1.0)
+}
+
+fn window_start(pm: &Paymaker) -> u64 {
+    util::now_ms().saturating_sub(pm.cfg.history_depth_secs * 1000)
+}
+
+pub async fn new(cfg: PaymakerCfg, password: &str, workdir: &str) -> Result<Paymaker> {
+    if password.is_empty() {
+        bail!("paymaker_http_password must be set");
+    }
+    if !(0.0..=1.0).contains(&cfg.ann_fraction) {
+        bail!("ann_fraction must be between 0 and 1");
+    }
+    let tmpdir = format!("{}/tmp", workdir);
+    util::ensure_exists_dir(workdir).await?;
+    util::ensure_exists_dir(&tmpdir).await?;
+    let oldest = util::now_ms().saturating_sub(cfg.history_depth_secs * 1000);
+    let credits = load_credits(workdir, oldest).await?;
+    Ok(Arc::new(PaymakerS {
+        sockaddr: cfg.bind.parse()?,
+        cfg,
+        password: String::from(password),
+        workdir: String::from(workdir),
+        tmpdir,
+        events_credited: Counter::default(),
+        m: Mutex::new(PaymakerM {
+            seen: credits.iter().map(|c| c.event_id.clone()).collect(),
+            credits,
+            credits_file: open_credits_file(workdir).await?,
+        }),
+    }))
+}
+
+async fn open_credits_file(workdir: &str) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(format!("{}/{}", workdir, CREDITS_FILE))
+        .await?)
+}
+
+// Read back whatever was credited before we restarted, so that a paylog which is
+// posted again will not be counted twice.
+async fn load_credits(workdir: &str, oldest: u64) -> Result<Vec<Credit>> {
+    let path = format!("{}/{}", workdir, CREDITS_FILE);
+    let content = match tokio::fs::read(&path).await {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => bail!("Unable to read {}: {}", path, e),
+    };
+    let mut out = Vec::new();
+    for line in content.split(|c| *c == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_slice::<Credit>(line) {
+            Ok(c) if c.time >= oldest => out.push(c),
+            Ok(_) => (),
+            Err(e) => warn!("Skipping unparsable credit in {}: {}", path, e),
+        }
+    }
+    info!("Loaded {} credits from {}", out.len(), path);
+    Ok(out)
+}
+
+fn parse_credit(line: &[u8]) -> Result<Credit> {
+    #[derive(Deserialize)]
+    struct Typed {
+        #[serde(rename = "type")]
+        t: String,
+    }
+    let t = serde_json::from_slice::<Typed>(line)?.t;
+    Ok(match t.as_str() {
+        "anns" => {
+            let ae = serde_json::from_slice::<AnnsEvent>(line)?;
+            Credit {
+                event_id: ae.event_id,
+                kind: CreditKind::Ann,
+                pay_to: ae.pay_to,
+                time: ae.time,
+                work: work_for_compact(ae.target) * ae.accepted as f64,
+            }
+        }
+        "share" => {
+            let bse = serde_json::from_slice::<BlkShareEvent>(line)?;
+            Credit {
+                event_id: bse.event_id,
+                kind: CreditKind::Blk,
+                pay_to: bse.pay_to,
+                time: bse.time,
+                work: work_for_compact(bse.target),
+            }
+        }
+        _ => bail!("Unknown event type [{}]", t),
+    })
+}
+
+// Parse one line of a paylog, None if the event is too old to be paid for anymore and
+// an error if it can not be credited at all.
+fn take_credit(line: &[u8], oldest: u64, now_ms: u64) -> Result<Option<Credit>> {
+    let c = parse_credit(line)?;
+    if c.event_id.is_empty() {
+        bail!("no eventId");
+    } else if c.pay_to.is_empty() {
+        bail!("no payTo");
+    } else if c.time > now_ms + MAX_FUTURE_MS {
+        bail!("event {} time {} is in the future", c.event_id, c.time);
+    } else if c.time < oldest {
+        debug!("event {} too old", c.event_id);
+        return Ok(None);
+    }
+    Ok(Some(c))
+}
+
+async fn handle_events(pm: &Paymaker, body: &Bytes) -> Result<PaymakerReply> {
+    let oldest = window_start(pm);
+    let now_ms = util::now_ms();
+    let mut warn = Vec::new();
+    let mut new_credits = Vec::new();
+    for (line, i) in body.split(|c| *c == b'\n').zip(1..) {
+        if line.is_empty() {
+            continue;
+        }
+        match take_credit(line, oldest, now_ms) {
+            Ok(Some(c)) => new_credits.push(c),
+            Ok(None) => (),
+            Err(e) => warn.push(format!("line {}: {}", i, e)),
+        }
+    }
+    let mut m = pm.m.lock().await;
+    let mut out = Vec::new();
+    for c in new_credits {
+        if !m.seen.insert(c.event_id.clone()) {
+            // Already have it, the handler is re-posting a paylog we accepted before
+            continue;
+        }
+        out.extend_from_slice(serde_json::to_string(&c)?.as_bytes());
+        out.push(b'\n');
+        m.credits.push(c);
+        pm.events_credited.add(1);
+    }
+    m.credits_file.write_all(&out[..]).await?;
+    m.credits_file.flush().await?;
+    Ok(PaymakerReply {
+        warn,
+        error: Vec::new(),
+        result: Some(PaymakerResult {
+            event_id: hex::encode(&hash::compress_sha256(&body[..])[..16]),
+        }),
+    })
+}
+
+/// Check an Authorization header for Basic auth with user x and this password, the
+/// password is compared in constant time.
+pub fn check_basic_auth(auth: Option<&str>, passwd: &str) -> bool {
+    let decoded = if let Some(d) = auth
+        .and_then(|a| a.strip_prefix("Basic "))
+        .and_then(|b| base64::decode(b.trim()).ok())
+    {
+        d
+    } else {
+        return false;
+    };
+    decoded.starts_with(b"x:") && memcmp(&decoded[2..], passwd.as_bytes())
+}
+
+async fn handle_post(
+    pm: Paymaker,
+    auth: Option<String>,
+    body: Bytes,
+) -> Result<impl warp::Reply, Infallible> {
+    let (reply, status) = if !check_basic_auth(auth.as_deref(), &pm.password) {
+        (
+            PaymakerReply {
+                warn: Vec::new(),
+                error: vec!["Incorrect password".to_owned()],
+                result: None,
+            },
+            warp::http::StatusCode::UNAUTHORIZED,
+        )
+    } else {
+        match handle_events(&pm, &body).await {
+            Ok(r) => (r, warp::http::StatusCode::OK),
+            Err(e) => {
+                warn!("Unable to handle events: {}", e);
+                (
+                    PaymakerReply {
+                        warn: Vec::new(),
+                        error: vec![format!("{}", e)],
+                        result: None,
+                    },
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                )
+            }
+        }
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&reply), status))
+}
+
+fn sum_by_pay_to(credits: &[Credit], kind: CreditKind) -> BTreeMap<String, f64> {
+    let mut out = BTreeMap::new();
+    for c in credits.iter().filter(|c| c.kind == kind) {
+        *out.entry(c.pay_to.clone()).or_insert(0.0) += c.work;
+    }
+    out
+}
+
+fn payout_table(credits: &[Credit], ann_fraction: f64, start: u64, end: u64) -> PayoutTable {
+    let ann_work = sum_by_pay_to(credits, CreditKind::Ann);
+    let blk_work = sum_by_pay_to(credits, CreditKind::Blk);
+    let ann_total: f64 = ann_work.values().sum();
+    let blk_total: f64 = blk_work.values().sum();
+
+    // If one side has no work at all then the other side gets everything
+    let (ann_frac, blk_frac) = match (ann_total > 0.0, blk_total > 0.0) {
+        (true, true) => (ann_fraction, 1.0 - ann_fraction),
+        (true, false) => (1.0, 0.0),
+        (false, true) => (0.0, 1.0),
+        (false, false) => (0.0, 0.0),
+    };
+    let mut payouts = BTreeMap::new();
+    for (addr, w) in &ann_work {
+        *payouts.entry(addr.clone()).or_insert(0.0) += ann_frac * w / ann_total;
+    }
+    for (addr, w) in &blk_work {
+        *payouts.entry(addr.clone()).or_insert(0.0) += blk_frac * w / blk_total;
+    }
+    PayoutTable {
+        window_start_ms: start,
+        window_end_ms: end,
+        ann_fraction,
+        ann_work,
+        blk_work,
+        payouts,
+    }
+}
+
+async fn handle_payouts(pm: Paymaker) -> Result<impl warp::Reply, Infallible> {
+    let start = window_start(&pm);
+    let m = pm.m.lock().await;
+    let table = payout_table(&m.credits[..], pm.cfg.ann_fraction, start, util::now_ms());
+    Ok(warp::reply::json(&table))
+}
+
+async fn prune(pm: &Paymaker) -> Result<()> {
+    let oldest = window_start(pm);
+    let mut m = pm.m.lock().await;
+    let before = m.credits.len();
+    let (keep, drop): (Vec<Credit>, Vec<Credit>) =
+        m.credits.drain(..).partition(|c| c.time >= oldest);
+    m.credits = keep;
+    if drop.is_empty() {
+        return Ok(());
+    }
+    for c in &drop {
+        m.seen.remove(&c.event_id);
+    }
+    // Rewrite the credits file so that it does not grow forever
+    let mut content = Vec::new();
+    for c in &m.credits {
+        content.extend_from_slice(serde_json::to_string(c)?.as_bytes());
+        content.push(b'\n');
+    }
+    let content = Bytes::from(content);
+    util::write_file(
+        CREDITS_FILE,
+        &pm.tmpdir,
+        &pm.workdir,
+        std::iter::once(&content),
+    )
+    .await?;
+    m.credits_file = open_credits_file(&pm.workdir).await?;
+    debug!("Pruned credits {} -> {}", before, m.credits.len());
+    Ok(())
+}
+
+async fn prune_loop(pm: &Paymaker) {
+    loop {
+        util::sleep_ms(PRUNE_EVERY_MS).await;
+        if let Err(e) = prune(pm).await {
+            warn!("Unable to prune credits: {}", e);
+        }
+    }
+}
+
+pub fn metrics(pm: &Paymaker, out: &mut MetricsOut) {
+    out.counter(
+        "paymaker_events_total",
+        "Paylog events which were credited",
+        &[],
+        pm.events_credited.get() as f64,
+    );
+    if let Ok(m) = pm.m.try_lock() {
+        out.gauge(
+            "paymaker_credits",
+            "Credits inside of the payout window",
+            &[],
+            m.credits.len() as f64,
+        );
+    }
+}
+
+pub async fn start(pm: &Paymaker) {
+    let events = warp::post()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and((|pm: Paymaker| warp::any().map(move || pm.clone()))(
+            pm.clone(),
+        ))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::bytes())
+        .and_then(handle_post);
+
+    let payouts = warp::get()
+        .and(warp::path("payouts"))
+        .and(warp::path::end())
+        .and((|pm: Paymaker| warp::any().map(move || pm.clone()))(
+            pm.clone(),
+        ))
+        .and_then(handle_payouts);
+
+    packetcrypt_util::async_spawn!(pm, {
+        warp::serve(events.or(payouts)).run(pm.sockaddr).await
+    });
+
+    packetcrypt_util::async_spawn!(pm, {
+        prune_loop(&pm).await;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_basic_auth() {
+        let auth = format!("Basic {}", base64::encode("x:secret"));
+        assert!(check_basic_auth(Some(&auth), "secret"));
+        assert!(!check_basic_auth(Some(&auth), "secreT"));
+        assert!(!check_basic_auth(Some(&auth), "secret2"));
+        assert!(!check_basic_auth(Some(&auth), ""));
+        let auth = format!("Basic {}", base64::encode("y:secret"));
+        assert!(!check_basic_auth(Some(&auth), "secret"));
+        assert!(!check_basic_auth(Some("Basic !!"), "secret"));
+        assert!(!check_basic_auth(Some("secret"), "secret"));
+        assert!(!check_basic_auth(None, "secret"));
+    }
+
+    fn credit(id: &str, kind: CreditKind, pay_to: &str, work: f64) -> Credit {
+        Credit {
+            event_id: id.to_owned(),
+            kind,
+            pay_to: pay_to.to_owned(),
+            time: 0,
+            work,
+        }
+    }
+
+    #[test]
+    fn test_payout_table() {
+        let credits = vec![
+            credit("1", CreditKind::Ann, "a", 3.0),
+            credit("2", CreditKind::Ann, "b", 1.0),
+            credit("3", CreditKind::Blk, "b", 10.0),
+        ];
+        let t = payout_table(&credits[..], 0.5, 0, 1);
+        assert_eq!(t.payouts["a"], 0.375);
+        assert_eq!(t.payouts["b"], 0.625);
+
+        // Only ann miners, they get everything
+        let t = payout_table(&credits[0..2], 0.5, 0, 1);
+        assert_eq!(t.payouts["a"], 0.75);
+        assert_eq!(t.payouts["b"], 0.25);
+    }
+
+    #[test]
+    fn test_parse_credit() {
+        let line = br#"{"type":"anns","accepted":10,"dup":0,"inval":0,"badHash":0,"runt":0,
+            "internalErr":0,"payTo":"pkt1xyz","unsigned":0,"totalLen":10240,
+            "target":537921535,"time":1000,"eventId":"abcd"}"#;
+        let c = parse_credit(&line[..]).unwrap();
+        assert_eq!(c.kind, CreditKind::Ann);
+        assert_eq!(c.pay_to, "pkt1xyz");
+        assert_eq!(c.event_id, "abcd");
+        assert_eq!(c.work, work_for_compact(537921535) * 10.0);
+        assert!(parse_credit(br#"{"type":"bogus"}"#).is_err());
+
+        // Events may be a little ahead of our clock, but no more
+        let line = String::from_utf8(line.to_vec())
+            .unwrap()
+            .replace(r#""time":1000,"#, r#""time":100000,"#);
+        let now = 100_000 - MAX_FUTURE_MS;
+        assert!(take_credit(line.as_bytes(), 100_000, now)
+            .unwrap()
+            .is_some());
+        assert!(take_credit(line.as_bytes(), 100_001, now)
+            .unwrap()
+            .is_none());
+        let e = take_credit(line.as_bytes(), 0, now - 1).unwrap_err();
+        assert!(e.to_string().contains("in the future"), "{}", e);
+        let e = take_credit(line.replace("pkt1xyz", "").as_bytes(), 0, now).unwrap_err();
+        assert_eq!(e.to_string(), "no payTo");
+    }
+}
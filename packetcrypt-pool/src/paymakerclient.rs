@@ -2,12 +2,14 @@
 use anyhow::{bail, Result};
 use core::time::Duration;
 use log::{debug, error, trace, warn};
+use packetcrypt_util::metrics::{Counter, Gauge, MetricsOut};
 use packetcrypt_util::poolclient::{self, PoolClient};
 use packetcrypt_util::protocol::PaymakerReply;
 use packetcrypt_util::{hash, util};
 use regex::Regex;
 use serde::Serialize;
 use std::ops::Add;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::fs::{read_dir, File};
 use tokio::io::AsyncWriteExt;
@@ -26,6 +28,8 @@ pub struct _PaymakerClient {
     payfile_regex: Regex,
     pc: PoolClient,
     cfg: PaymakerClientCfg,
+    paylogs_submitted: Counter,
+    paylogs_pending: Gauge,
 }
 pub type PaymakerClient = Arc<_PaymakerClient>;
 
@@ -51,6 +55,8 @@ pub async fn new(pc: &PoolClient, cfg: PaymakerClientCfg) -> Result<PaymakerClie
         payfile_regex,
         cfg,
         pc: pc.clone(),
+        paylogs_submitted: Counter::default(),
+        paylogs_pending: Gauge::default(),
     }))
 }
 
@@ -118,6 +124,21 @@ pub async fn start(pmc: &PaymakerClient) {
     });
 }
 
+pub fn metrics(pmc: &PaymakerClient, out: &mut MetricsOut) {
+    out.counter(
+        "paylogs_submitted_total",
+        "Paylog files accepted by the paymaker",
+        &[],
+        pmc.paylogs_submitted.get() as f64,
+    );
+    out.gauge(
+        "paylogs_pending",
+        "Paylog files waiting to be submitted to the paymaker",
+        &[],
+        pmc.paylogs_pending.get(),
+    );
+}
+
 pub async fn handle_paylog<T>(pmc: &PaymakerClient, log: &T) -> Result<()>
 where
     T: ?Sized + Serialize,
@@ -130,22 +151,14 @@ where
         .await?;
     Ok(())
 }
-async fn submit_paylogs(pmc: &PaymakerClient) -> Result<u64> {
-    let (maybe_paymaker_url, current_file_name) = {
-        let pmcm = pmc.pmcm.lock().await;
-        (
-            pmcm.maybe_paymaker_url.clone(),
-            pmcm.current_file_name.clone(),
-        )
-    };
-    let paymaker_url = if let Some(x) = maybe_paymaker_url {
-        x
-    } else {
-        //debug!("No paymaker_url yet");
-        return Ok(10000);
-    };
+
+// Paylog files which are ready to be submitted, empty ones are deleted
+async fn pending_paylogs(
+    pmc: &PaymakerClient,
+    current_file_name: &str,
+) -> Result<Vec<(PathBuf, String)>> {
+    let mut out = Vec::new();
     let mut dir = read_dir(&pmc.cfg.paylogdir).await?;
-    let mut uploaded = false;
     while let Some(f) = dir.next_entry().await? {
         let filename = if let Ok(s) = f.file_name().into_string() {
             s
@@ -171,12 +184,38 @@ async fn submit_paylogs(pmc: &PaymakerClient) -> Result<u64> {
             // Don't try to publish the file we currently have open
             continue;
         }
-        let file = tokio::fs::read(f.path()).await?;
-        if file.is_empty() {
+        if f.metadata().await?.len() == 0 {
             debug!("{} empty ({})", filename, current_file_name);
             tokio::fs::remove_file(f.path()).await?;
             continue;
         }
+        out.push((f.path(), filename));
+    }
+    Ok(out)
+}
+
+async fn submit_paylogs(pmc: &PaymakerClient) -> Result<u64> {
+    let (maybe_paymaker_url, current_file_name) = {
+        let pmcm = pmc.pmcm.lock().await;
+        (
+            pmcm.maybe_paymaker_url.clone(),
+            pmcm.current_file_name.clone(),
+        )
+    };
+    // Counted before anything is sent so that the gauge is right even if the
+    // paymaker is unreachable
+    let files = pending_paylogs(pmc, &current_file_name).await?;
+    let mut pending = files.len();
+    pmc.paylogs_pending.set(pending as f64);
+    let paymaker_url = if let Some(x) = maybe_paymaker_url {
+        x
+    } else {
+        //debug!("No paymaker_url yet");
+        return Ok(10000);
+    };
+    let mut uploaded = false;
+    for (path, filename) in files {
+        let file = tokio::fs::read(&path).await?;
         let event_id = hex::encode(&hash::compress_sha256(&file)[..16]);
         uploaded = true;
         let res = reqwest::ClientBuilder::new()
@@ -233,7 +272,10 @@ async fn submit_paylogs(pmc: &PaymakerClient) -> Result<u64> {
             continue;
         }
         debug!("{} ok", filename);
-        tokio::fs::remove_file(f.path()).await?;
+        tokio::fs::remove_file(&path).await?;
+        pmc.paylogs_submitted.add(1);
+        pending -= 1;
+        pmc.paylogs_pending.set(pending as f64);
     }
     Ok(if uploaded { 10_000 } else { 30_000 })
 }
@@ -17,6 +17,48 @@ pub struct AnnHandlerCfg {
     pub subscribe_to: Vec<String>,
     pub mss: Option<usize>,
     pub spray_at: Option<Vec<String>>,
+    pub mcast: Option<String>,
+    pub spray_max_kbps: Option<u64>,
+    pub spray_adaptive: Option<bool>,
+    pub spray_tcp_bind: Option<String>,
+
+    pub ip_anns_per_sec: Option<f64>,
+    pub payto_anns_per_sec: Option<f64>,
+    pub ban_bad_ratio: Option<f64>,
+    pub ban_secs: Option<u64>,
+
+    pub max_anns_per_post: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlkHandlerCfg {
+    pub bind: String,
+    pub block_submit_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PaymakerCfg {
+    pub bind: String,
+    pub history_depth_secs: u64,
+    pub ann_fraction: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MasterCfg {
+    pub bind: String,
+    pub rpc_url: String,
+    pub rpc_user: String,
+    pub rpc_pass: String,
+    pub pay_to_script: String,
+
+    pub submit_ann_urls: Vec<String>,
+    pub download_ann_urls: Vec<String>,
+    pub submit_block_urls: Vec<String>,
+    pub paymaker_url: String,
+
+    pub ann_target: u32,
+    pub share_target: u32,
+    pub mine_old_anns: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
@@ -25,4 +67,8 @@ pub struct Config {
     pub master_url: String,
     pub root_workdir: String,
     pub ann_handler: HashMap<String, AnnHandlerCfg>,
+    #[serde(default)]
+    pub block_handler: HashMap<String, BlkHandlerCfg>,
+    pub master: Option<MasterCfg>,
+    pub paymaker: Option<PaymakerCfg>,
 }
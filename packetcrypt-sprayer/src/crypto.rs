@@ -0,0 +1,414 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Sprayer protocol version 1
+//
+// Everything is keyed from the shared password, which is stretched with argon2id
+// once at startup so that a captured packet cannot be used to cheaply guess it.
+//
+// Subscriptions are a SprayerReq sealed with xchacha20poly1305 using a random nonce:
+//   [ "PCS" ][ version ][ nonce (24) ][ sealed json ][ tag (16) ]
+// The SprayerReq carries the sender's clock, and subscriptions which are too old or
+// not newer than the last one from the same peer are dropped as replays.
+//
+// A subscription is only taken if it carries a cookie which proves that the subscriber
+// can receive at the address it is sending from, otherwise anyone who captured one could
+// send it from a forged address and have us flood that address with anns. The cookie is
+// a hash of the address with a secret which never leaves the process, and it is sent
+// back sealed the same way as a subscription but smaller than one:
+//   [ "PCK" ][ version ][ nonce (24) ][ sealed cookie (16) ][ tag (16) ]
+//
+// Ann packets are sent on a stream, every send queue has its own stream with a random
+// id and a key derived from it, the packet number is the nonce:
+//   [ stream id (16) ][ packet number (8) ][ encrypted ann (1024) ][ tag (16) ]
+// The first 8 bytes of the stream id are the time when it was created so a receiver
+// only moves forward to a newer stream, unless the current one has gone silent (the
+// peer may have restarted with its clock set back), within a stream a sliding window
+// drops any packet number which was already seen. Every stream starts from packet
+// number zero so the same window also tells us which packets went missing.
+use log::info;
+use packetcrypt_sys::sodiumoxide::crypto::aead::chacha20poly1305_ietf as chacha;
+use packetcrypt_sys::sodiumoxide::crypto::aead::xchacha20poly1305_ietf as xchacha;
+use packetcrypt_sys::sodiumoxide::crypto::generichash;
+use packetcrypt_sys::sodiumoxide::crypto::pwhash::argon2id13;
+use packetcrypt_sys::sodiumoxide::randombytes::randombytes_into;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+
+pub const VERSION: u8 = 1;
+const MAGIC: &[u8; 3] = b"PCS";
+const COOKIE_MAGIC: &[u8; 3] = b"PCK";
+
+pub const STREAM_ID_LEN: usize = 16;
+pub const HEADER_LEN: usize = STREAM_ID_LEN + 8;
+pub const TAG_LEN: usize = 16;
+pub const COOKIE_LEN: usize = 16;
+
+// A cookie is good for this epoch and the next, a peer which sends one from the
+// previous epoch is given a new one.
+const COOKIE_EPOCH_MS: u64 = 60_000;
+
+const SUB_HEADER_LEN: usize = MAGIC.len() + 1 + xchacha::NONCEBYTES;
+
+// Fixed because both sides must derive the same key from nothing but the password
+const SALT: &[u8; argon2id13::SALTBYTES] = b"packetcrypt-spr1";
+
+// Size of the replay window, in packets, this must be larger than the number of
+// anns which a sender can queue for one peer because queues are sent newest first.
+const WINDOW_BITS: u64 = 1 << 18;
+
+// A stream which is not newer than the current one is taken once nothing has come in
+// on the current one for this long.
+const STREAM_SILENCE_MS: u64 = 5_000;
+
+fn subkey(master: &[u8], purpose: &[u8]) -> [u8; 32] {
+    let d = generichash::hash(purpose, Some(32), Some(master)).unwrap();
+    d.as_ref().try_into().unwrap()
+}
+
+pub struct Keys {
+    sub: xchacha::Key,
+    data: [u8; 32],
+    cookie: [u8; 32],
+}
+impl Keys {
+    pub fn new(passwd: &str) -> Keys {
+        let mut master = [0_u8; 32];
+        argon2id13::derive_key(
+            &mut master,
+            passwd.as_bytes(),
+            &argon2id13::Salt(*SALT),
+            argon2id13::OPSLIMIT_INTERACTIVE,
+            argon2id13::MEMLIMIT_INTERACTIVE,
+        )
+        .unwrap();
+        let mut cookie = [0_u8; 32];
+        randombytes_into(&mut cookie);
+        Keys {
+            sub: xchacha::Key(subkey(&master, b"subscribe")),
+            data: subkey(&master, b"data"),
+            cookie,
+        }
+    }
+
+    fn stream_key(&self, id: &[u8]) -> chacha::Key {
+        chacha::Key(subkey(&self.data, id))
+    }
+
+    /// Create a new stream for sending anns to one peer.
+    pub fn new_stream(&self, now_ms: u64) -> Stream {
+        let mut id = [0_u8; STREAM_ID_LEN];
+        id[..8].copy_from_slice(&now_ms.to_le_bytes());
+        randombytes_into(&mut id[8..]);
+        Stream {
+            id,
+            key: self.stream_key(&id),
+        }
+    }
+
+    fn seal(&self, magic: &[u8; 3], content: &[u8]) -> Vec<u8> {
+        let nonce = xchacha::gen_nonce();
+        let mut out = Vec::with_capacity(SUB_HEADER_LEN + content.len() + TAG_LEN);
+        out.extend_from_slice(magic);
+        out.push(VERSION);
+        out.extend_from_slice(&nonce.0);
+        out.extend_from_slice(content);
+        let tag = xchacha::seal_detached(&mut out[SUB_HEADER_LEN..], None, &nonce, &self.sub);
+        out.extend_from_slice(&tag.0);
+        out
+    }
+
+    fn open(&self, magic: &[u8; 3], msg: &[u8]) -> Option<Vec<u8>> {
+        if msg.len() < SUB_HEADER_LEN + TAG_LEN || &msg[..3] != magic || msg[3] != VERSION {
+            return None;
+        }
+        let nonce = xchacha::Nonce::from_slice(&msg[4..SUB_HEADER_LEN])?;
+        let tag = xchacha::Tag::from_slice(&msg[msg.len() - TAG_LEN..])?;
+        let mut content = Vec::from(&msg[SUB_HEADER_LEN..msg.len() - TAG_LEN]);
+        xchacha::open_detached(&mut content, None, &tag, &nonce, &self.sub).ok()?;
+        Some(content)
+    }
+
+    pub fn seal_sub(&self, json: &[u8]) -> Vec<u8> {
+        self.seal(MAGIC, json)
+    }
+
+    /// Returns the json content of a subscription, or None if it is not one of ours.
+    pub fn open_sub(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        self.open(MAGIC, msg)
+    }
+
+    fn cookie_at(&self, addr: &SocketAddr, epoch: u64) -> [u8; COOKIE_LEN] {
+        let mut state = generichash::State::new(Some(COOKIE_LEN), Some(&self.cookie)).unwrap();
+        state.update(format!("{}", addr).as_bytes()).unwrap();
+        state.update(&epoch.to_le_bytes()).unwrap();
+        state.finalize().unwrap().as_ref().try_into().unwrap()
+    }
+
+    /// The message which gives a peer its cookie for the address it is sending from.
+    pub fn seal_cookie(&self, addr: &SocketAddr, now_ms: u64) -> Vec<u8> {
+        self.seal(
+            COOKIE_MAGIC,
+            &self.cookie_at(addr, now_ms / COOKIE_EPOCH_MS),
+        )
+    }
+
+    /// Returns the cookie from a cookie message, or None if it is not one of ours.
+    pub fn open_cookie(&self, msg: &[u8]) -> Option<[u8; COOKIE_LEN]> {
+        self.open(COOKIE_MAGIC, msg)?.as_slice().try_into().ok()
+    }
+
+    /// How many epochs old a cookie from this address is, None if it is not valid.
+    pub fn cookie_age(&self, addr: &SocketAddr, now_ms: u64, cookie: &[u8]) -> Option<u64> {
+        let epoch = now_ms / COOKIE_EPOCH_MS;
+        (0..2).find(|age| epoch >= *age && self.cookie_at(addr, epoch - age)[..] == *cookie)
+    }
+}
+
+/// Is this message using a known version of the subscription protocol.
+pub fn is_sub(msg: &[u8]) -> bool {
+    msg.len() > 3 && &msg[..3] == MAGIC
+}
+
+/// Is this message a cookie sent in reply to a subscription.
+pub fn is_cookie(msg: &[u8]) -> bool {
+    msg.len() > 3 && &msg[..3] == COOKIE_MAGIC
+}
+
+fn packet_nonce(num: u64) -> chacha::Nonce {
+    let mut n = [0_u8; chacha::NONCEBYTES];
+    n[..8].copy_from_slice(&num.to_le_bytes());
+    chacha::Nonce(n)
+}
+
+pub struct Stream {
+    id: [u8; STREAM_ID_LEN],
+    key: chacha::Key,
+}
+impl Stream {
+    /// Was this sealed packet sent on this stream.
+    pub fn owns(&self, pkt: &[u8]) -> bool {
+        pkt[..STREAM_ID_LEN] == self.id
+    }
+
+    /// Fill in the header, encrypt the ann and write the tag.
+    pub fn seal(&self, pkt: &mut [u8], num: u64) {
+        let (head, rest) = pkt.split_at_mut(HEADER_LEN);
+        head[..STREAM_ID_LEN].copy_from_slice(&self.id);
+        head[STREAM_ID_LEN..].copy_from_slice(&num.to_le_bytes());
+        let (ann, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+        let t = chacha::seal_detached(ann, None, &packet_nonce(num), &self.key);
+        tag.copy_from_slice(&t.0);
+    }
+}
+
+pub fn packet_num(pkt: &[u8]) -> u64 {
+    u64::from_le_bytes(pkt[STREAM_ID_LEN..HEADER_LEN].try_into().unwrap())
+}
+
+fn stream_time(id: &[u8]) -> u64 {
+    u64::from_le_bytes(id[..8].try_into().unwrap())
+}
+
+/// Receive side of the streams from one peer.
+#[derive(Default)]
+pub struct Receiver {
+    id: [u8; STREAM_ID_LEN],
+    key: Option<chacha::Key>,
+    highest: u64,
+    seen: Vec<u64>,
+    expected: u64,
+    // When the last good packet came in on the current stream
+    last_ms: u64,
+    // Last older stream which was turned away, so that it is only logged once
+    rejected: [u8; STREAM_ID_LEN],
+}
+impl Receiver {
+    fn open_with(key: &chacha::Key, pkt: &mut [u8], num: u64) -> bool {
+        let tag_at = pkt.len() - HEADER_LEN - TAG_LEN;
+        let (ann, tag) = pkt[HEADER_LEN..].split_at_mut(tag_at);
+        let tag = chacha::Tag::from_slice(tag).unwrap();
+        chacha::open_detached(ann, None, &tag, &packet_nonce(num), key).is_ok()
+    }
+
+    // Returns false if num was already seen or is too old to tell
+    fn check_replay(&mut self, num: u64) -> bool {
+        if num + WINDOW_BITS <= self.highest {
+            return false;
+        }
+        if num > self.highest {
+            self.expected += num - self.highest;
+        }
+        while self.highest < num {
+            // Sliding forward, forget whatever falls out of the window
+            self.highest += 1;
+            let bit = self.highest % WINDOW_BITS;
+            self.seen[(bit / 64) as usize] &= !(1 << (bit % 64));
+            if num - self.highest >= WINDOW_BITS {
+                // Far ahead, just start over
+                self.seen.iter_mut().for_each(|w| *w = 0);
+                self.highest = num;
+            }
+        }
+        let bit = num % WINDOW_BITS;
+        let (w, b) = ((bit / 64) as usize, 1 << (bit % 64));
+        if self.seen[w] & b != 0 {
+            return false;
+        }
+        self.seen[w] |= b;
+        true
+    }
+
+    /// Authenticate and decrypt an ann packet in place.
+    pub fn open(&mut self, keys: &Keys, pkt: &mut [u8], now_ms: u64) -> bool {
+        let id: [u8; STREAM_ID_LEN] = pkt[..STREAM_ID_LEN].try_into().unwrap();
+        let num = packet_num(pkt);
+        if self.key.is_some() && id == self.id {
+            let key = self.key.as_ref().unwrap();
+            if !(Receiver::open_with(key, pkt, num) && self.check_replay(num)) {
+                return false;
+            }
+            self.last_ms = now_ms;
+            return true;
+        }
+        if self.key.is_some()
+            && stream_time(&id) <= stream_time(&self.id)
+            && now_ms < self.last_ms.saturating_add(STREAM_SILENCE_MS)
+        {
+            // An old stream, or a new one which claims to be from the same millisecond,
+            // either way we are not going back while the current one is alive.
+            if id != self.rejected && Receiver::open_with(&keys.stream_key(&id), pkt, num) {
+                info!(
+                    "Ignoring stream [{}] which is not newer than the current stream [{}]",
+                    hex::encode(id),
+                    hex::encode(self.id)
+                );
+                self.rejected = id;
+            }
+            return false;
+        }
+        let key = keys.stream_key(&id);
+        if !Receiver::open_with(&key, pkt, num) {
+            return false;
+        }
+        // The peer has started a new stream
+        self.id = id;
+        self.key = Some(key);
+        self.highest = num;
+        self.seen = vec![0; (WINDOW_BITS / 64) as usize];
+        self.expected += num + 1;
+        self.last_ms = now_ms;
+        self.check_replay(num)
+    }
+
+    /// Total number of packets which the peer has sent us, received or not.
+    pub fn expected(&self) -> u64 {
+        self.expected
+    }
+
+    /// Ranges of packet numbers which are missing, skipping the newest `skip` packets
+    /// because they may still be on the way and looking back at most `span` packets.
+    pub fn gaps(&self, skip: u64, span: u64, max_ranges: usize) -> Vec<(u64, u64)> {
+        let mut out: Vec<(u64, u64)> = Vec::new();
+        if self.key.is_none() || self.highest < skip {
+            return out;
+        }
+        let end = self.highest - skip;
+        let span = std::cmp::min(span, WINDOW_BITS - 1);
+        for num in end.saturating_sub(span)..=end {
+            let bit = num % WINDOW_BITS;
+            if self.seen[(bit / 64) as usize] & (1 << (bit % 64)) != 0 {
+                continue;
+            }
+            if let Some((_, last)) = out.last_mut() {
+                if *last + 1 == num {
+                    *last = num;
+                    continue;
+                }
+            }
+            if out.len() == max_ranges {
+                break;
+            }
+            out.push((num, num));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PKT_LEN: usize = HEADER_LEN + 1024 + TAG_LEN;
+
+    fn packet(s: &Stream, num: u64) -> Vec<u8> {
+        let mut pkt = vec![7_u8; PKT_LEN];
+        s.seal(&mut pkt, num);
+        pkt
+    }
+
+    #[test]
+    fn test_stream() {
+        let keys = Keys::new("pass");
+        let s = keys.new_stream(1000);
+        let mut rx = Receiver::default();
+        let mut pkt = packet(&s, 5);
+        let replay = pkt.clone();
+        assert!(rx.open(&keys, &mut pkt, 0));
+        assert_eq!(&pkt[HEADER_LEN..PKT_LEN - TAG_LEN], &[7_u8; 1024][..]);
+        assert!(!rx.open(&keys, &mut replay.clone(), 0));
+        assert!(rx.open(&keys, &mut packet(&s, 3), 0));
+        assert!(rx.open(&keys, &mut packet(&s, 6), 0));
+
+        let mut bad = packet(&s, 7);
+        bad[100] ^= 1;
+        assert!(!rx.open(&keys, &mut bad, 0));
+        assert!(!rx.open(
+            &keys,
+            &mut packet(&Keys::new("wrong").new_stream(2000), 1),
+            0
+        ));
+
+        assert_eq!(rx.expected(), 7);
+        assert_eq!(rx.gaps(0, 100, 8), vec![(0, 2), (4, 4)]);
+        assert_eq!(rx.gaps(2, 100, 1), vec![(0, 2)]);
+
+        // Moving to a newer stream is fine but going back is not
+        assert!(rx.open(&keys, &mut packet(&keys.new_stream(2000), 0), 0));
+        assert!(!rx.open(&keys, &mut packet(&s, 8), 0));
+        assert_eq!(rx.rejected, s.id);
+
+        // Unless the current stream has gone quiet
+        assert!(!rx.open(&keys, &mut packet(&s, 8), STREAM_SILENCE_MS - 1));
+        assert!(rx.open(&keys, &mut packet(&s, 8), STREAM_SILENCE_MS));
+        assert!(!rx.open(&keys, &mut packet(&s, 8), STREAM_SILENCE_MS));
+    }
+
+    #[test]
+    fn test_sub() {
+        let keys = Keys::new("pass");
+        let msg = keys.seal_sub(b"{}");
+        assert!(is_sub(&msg));
+        assert_eq!(keys.open_sub(&msg), Some(b"{}".to_vec()));
+        assert_eq!(Keys::new("other").open_sub(&msg), None);
+    }
+
+    #[test]
+    fn test_cookie() {
+        let keys = Keys::new("pass");
+        let a: SocketAddr = "192.0.2.1:6666".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:6666".parse().unwrap();
+        let now = 10 * COOKIE_EPOCH_MS;
+        let msg = keys.seal_cookie(&a, now);
+        assert!(is_cookie(&msg) && !is_sub(&msg));
+        // Must never be an amplifier
+        assert!(msg.len() < keys.seal_sub(br#"{"time_ms":1600000000000}"#).len());
+        assert_eq!(keys.open_sub(&msg), None);
+        let c = keys.open_cookie(&msg).unwrap();
+        assert_eq!(keys.cookie_age(&a, now, &c), Some(0));
+        assert_eq!(keys.cookie_age(&a, now + COOKIE_EPOCH_MS, &c), Some(1));
+        assert_eq!(keys.cookie_age(&a, now + 2 * COOKIE_EPOCH_MS, &c), None);
+        assert_eq!(keys.cookie_age(&b, now, &c), None);
+        // The secret is per process, not from the password
+        assert_eq!(Keys::new("pass").cookie_age(&a, now, &c), None);
+    }
+}
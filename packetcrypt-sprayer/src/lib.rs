@@ -1,19 +1,29 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
 use anyhow::{bail, Context, Result};
 use log::{debug, info, warn};
-use packetcrypt_util::protocol::SprayerReq;
+use packetcrypt_util::metrics::MetricsOut;
+use packetcrypt_util::protocol::{SprayerFilter, SprayerReq};
 use packetcrypt_util::util;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
+use serde::Deserialize;
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::net::TcpListener;
 use std::net::UdpSocket;
-use std::sync::atomic::{self, AtomicUsize};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
 
+mod crypto;
+mod rate;
+pub mod record;
+mod relay;
+mod tcp;
+
 // 1MB per send/recv chunk
 const ANN_PER_CHUNK: usize = 1024;
 
@@ -26,6 +36,21 @@ const SECONDS_UNTIL_SUB_TIMEOUT: usize = 30;
 // How often to resend subscriptions
 const SECONDS_UNTIL_RESUB: usize = 5;
 
+// Subscriptions stamped further than this from our clock are dropped
+const SUB_MAX_SKEW_MS: u64 = SECONDS_UNTIL_SUB_TIMEOUT as u64 * 1000;
+
+// Chunks which were already sent are kept for retransmission, per peer which asks
+const NACK_HISTORY_CHUNKS: usize = 16;
+
+// How often to tell the peers we subscribe to what we got and ask for what is missing
+const REPORT_EVERY_MS: u64 = 1000;
+
+// Don't ask for the newest packets, they are probably still on the way
+const NACK_SKIP: u64 = ANN_PER_CHUNK as u64;
+
+// Keeps a NACK well under the size of one packet
+const NACK_MAX_RANGES: usize = 16;
+
 ///
 
 const STATS_EVERY: usize = 10;
@@ -33,8 +58,8 @@ const STATS_EVERY: usize = 10;
 // 512M incoming buffer
 const RECV_BUF_SZ: usize = 512 * 1024 * 1024;
 
-const MSG_PREFIX: usize = 8;
-const PKT_LENGTH: usize = 1024 + MSG_PREFIX;
+const MSG_PREFIX: usize = crypto::HEADER_LEN;
+const PKT_LENGTH: usize = MSG_PREFIX + 1024 + crypto::TAG_LEN;
 const CHUNK_LEN: usize = ANN_PER_CHUNK * PKT_LENGTH;
 const LOG_CREDITS: usize = 16;
 
@@ -76,7 +101,7 @@ impl Chunk {
     // pub fn cap(&self) -> usize {
     //     ANN_PER_CHUNK
     // }
-    pub fn push_ann(&mut self, ann: &[u8], number: u64) -> bool {
+    pub fn push_ann(&mut self, ann: &[u8], number: u64, stream: &crypto::Stream) -> bool {
         let (c0, c1) = if self.bcur >= PKT_LENGTH {
             // Try pushing to the back
             let c0 = self.bcur;
@@ -90,9 +115,18 @@ impl Chunk {
         } else {
             return false;
         };
-        let num_u8 = number.to_le_bytes();
-        self.bytes[c0..(c0 + 8)].copy_from_slice(&num_u8[..]);
-        self.bytes[(c0 + 8)..c1].copy_from_slice(ann);
+        let pkt = &mut self.bytes[c0..c1];
+        pkt[MSG_PREFIX..(MSG_PREFIX + 1024)].copy_from_slice(ann);
+        stream.seal(pkt, number);
+        true
+    }
+    // Add a packet which is already sealed, for retransmission
+    fn push_sealed(&mut self, pkt: &[u8]) -> bool {
+        if self.ecur + PKT_LENGTH > self.bytes.len() {
+            return false;
+        }
+        self.bytes[self.ecur..(self.ecur + PKT_LENGTH)].copy_from_slice(pkt);
+        self.ecur += PKT_LENGTH;
         true
     }
 }
@@ -119,6 +153,7 @@ impl ChunkPool {
 
 struct SendQueue {
     next_num: u64,
+    stream: crypto::Stream,
     q: VecDeque<Box<Chunk>>,
     chunk_pool: Arc<ChunkPool>,
 }
@@ -128,7 +163,7 @@ impl SendQueue {
         loop {
             let mut done = false;
             if let Some(mut c) = self.q.pop_back() {
-                if c.push_ann(ann, self.next_num) {
+                if c.push_ann(ann, self.next_num, &self.stream) {
                     self.next_num += 1;
                     done = true;
                 }
@@ -149,17 +184,50 @@ impl SendQueue {
 struct Subscriber {
     peer: SocketAddr,
     last_update_sec: AtomicUsize,
+    // Clock of the peer when it sent the last subscription we accepted
+    last_sub_ms: AtomicU64,
     send_queue: Mutex<SendQueue>,
+    // Only kept once the peer has sent a NACK
+    wants_history: AtomicBool,
+    history: Mutex<VecDeque<Box<Chunk>>>,
+    packets_resent: AtomicU64,
+    // Everything actually put on the wire, including resent
+    packets_sent: AtomicU64,
+    rate: Mutex<rate::RateLimit>,
+    // From the peer's latest subscription
+    filter: Mutex<SprayerFilter>,
+    // Signalled when anns are added to the send queue, for tcp subscribers
+    ready: Condvar,
 }
 
 struct Subscription {
     //    peer: SocketAddr,
     last_update_sec: AtomicUsize,
     packets_received: AtomicUsize,
+    rx: Mutex<crypto::Receiver>,
+    last_report_ms: AtomicU64,
+    last_report_received: AtomicUsize,
+    // Latest cookie from the peer, sent back with every subscription
+    cookie: Mutex<Option<[u8; crypto::COOKIE_LEN]>>,
+}
+impl Subscription {
+    fn new() -> Subscription {
+        Subscription {
+            last_update_sec: AtomicUsize::new(0),
+            packets_received: AtomicUsize::new(0),
+            rx: Mutex::new(crypto::Receiver::default()),
+            last_report_ms: AtomicU64::new(0),
+            last_report_received: AtomicUsize::new(0),
+            cookie: Mutex::new(None),
+        }
+    }
 }
 
 struct SprayerMut {
     subscribers: Vec<Subscriber>,
+    force_subscribe: Vec<Subscriber>,
+    // Each one is sent to by its own thread
+    tcp_subscribers: Vec<Arc<Subscriber>>,
 }
 
 pub trait OnAnns: Send + Sync {
@@ -174,6 +242,8 @@ struct PeerCounters {
     last_logged_ms: u64,
     last_packets_recv: usize,
     last_packets_sent: u64,
+    last_packets_expected: u64,
+    last_packets_resent: u64,
 }
 #[derive(Clone)]
 pub struct PeerStats {
@@ -182,16 +252,34 @@ pub struct PeerStats {
     pub kbps_out: f64,
     pub packets_in: u64,
     pub packets_out: u64,
+    // Fraction of the packets sent to us which never arrived (or not yet)
+    pub loss: f64,
+    pub packets_resent: u64,
+    // Current send limit, 0 if unlimited
+    pub kbps_cap: f64,
 }
 
 struct SprayerS {
     m: RwLock<SprayerMut>,
-    subscribe_to: Vec<SocketAddr>,
-    passwd: String,
+    passwd: RwLock<String>,
+    keys: RwLock<crypto::Keys>,
     socket: UdpSocket,
     handler: RwLock<Option<Box<dyn OnAnns>>>,
-    subscribed_to: HashMap<SocketAddr, Subscription>,
-    force_subscribe: Vec<Subscriber>,
+    subscribed_to: RwLock<HashMap<SocketAddr, Subscription>>,
+    // Keyed by host:port, which can be a name
+    tcp_subscribed_to: RwLock<HashMap<String, Arc<tcp::TcpSubscription>>>,
+    tcp_listener: Mutex<Option<TcpListener>>,
+    // Number of open tcp connections from subscribers
+    tcp_conns: AtomicUsize,
+    // Tags of the tcp subscriptions which were accepted, with their time_ms, so that
+    // each can only be used once
+    tcp_subs_seen: Mutex<HashMap<[u8; crypto::TAG_LEN], u64>>,
+    started: AtomicBool,
+    relay: Option<relay::Relay>,
+    // What we ask the peers which we subscribe to for
+    filter: SprayerFilter,
+    // Highest parent block height of any ann we have sent, for the height_window filter
+    top_height: Mutex<relay::TopHeight>,
     workers: usize,
     gso_ok: bool,
     is_mcast: bool,
@@ -199,10 +287,14 @@ struct SprayerS {
     peer_counters: Mutex<HashMap<SocketAddr, PeerCounters>>,
     peer_stats: Mutex<Vec<PeerStats>>,
     log_peer_stats: bool,
+    nack: bool,
+    max_kbps: u64,
+    adaptive: bool,
     chunk_pool: Arc<ChunkPool>,
     pkt_size: usize,
     self_addr: SocketAddr,
 }
+#[derive(Clone)]
 pub struct Sprayer(Arc<SprayerS>);
 
 fn compute_kbps(packets_sent: u64, ms_elapsed: u64) -> f64 {
@@ -212,15 +304,166 @@ fn compute_kbps(packets_sent: u64, ms_elapsed: u64) -> f64 {
     bits_sent as f64 / ms_elapsed as f64
 }
 
+// Field names in the config file are the same as the command line flags
+#[derive(Deserialize)]
 pub struct Config {
     pub passwd: String,
     pub bind: String,
+    #[serde(rename = "threads")]
     pub workers: usize,
+    #[serde(rename = "subscribe", default)]
     pub subscribe_to: Vec<String>,
+    #[serde(skip)]
     pub log_peer_stats: bool,
     pub mss: usize,
+    #[serde(rename = "sprayat", default)]
     pub spray_at: Vec<String>,
+    // Multicast group to join and send from, ipv4 groups use the interface of the
+    // bind address, ipv6 groups can be followed by %<interface name or index>
+    #[serde(default)]
     pub mcast: String,
+    // Ask the peers which we subscribe to for packets which went missing
+    #[serde(default)]
+    pub nack: bool,
+    // Limit on how fast we send to each peer, 0 for no limit
+    #[serde(rename = "maxkbps", default)]
+    pub max_kbps: u64,
+    // Lower the limit for peers which are losing packets and raise it when they are not
+    #[serde(default)]
+    pub adaptive: bool,
+    // Also take subscriptions over tcp on this address, for peers which can't use udp
+    #[serde(rename = "tcpbind", default)]
+    pub tcp_bind: String,
+    // Only pass on anns which we have not already seen and which are for a recent block
+    #[serde(default)]
+    pub relay: bool,
+    // In relay mode, drop anns with less work than this (a compact target), 0 for any
+    #[serde(rename = "minwork", default)]
+    pub min_work: u32,
+    // Ask the peers which we subscribe to for only anns with at least this much work
+    #[serde(rename = "filterwork", default)]
+    pub filter_work: u32,
+    // Ask the peers which we subscribe to for only anns for this many most recent blocks
+    #[serde(rename = "filterheight", default)]
+    pub filter_height: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mcast {
+    V4(Ipv4Addr),
+    V6(Ipv6Addr, u32),
+}
+
+#[cfg(windows)]
+fn if_index(name: &str) -> Result<u32> {
+    name.parse()
+        .with_context(|| format!("Interface [{}] must be an index on windows", name))
+}
+
+#[cfg(not(windows))]
+fn if_index(name: &str) -> Result<u32> {
+    if let Ok(i) = name.parse() {
+        return Ok(i);
+    }
+    let cname = std::ffi::CString::new(name)?;
+    let i = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+    if i == 0 {
+        bail!("No such network interface [{}]", name);
+    }
+    Ok(i)
+}
+
+fn parse_mcast(s: &str) -> Result<Mcast> {
+    let (group, iface) = match s.find('%') {
+        Some(i) => (&s[..i], Some(&s[(i + 1)..])),
+        None => (s, None),
+    };
+    let group: IpAddr = group
+        .parse()
+        .with_context(|| format!("mcast parse({})", s))?;
+    if !group.is_multicast() {
+        bail!("mcast [{}] is not a multicast group", s);
+    }
+    match (group, iface) {
+        (IpAddr::V4(_), Some(_)) => {
+            bail!(
+                "mcast [{}]: ipv4 groups use the interface of the bind address",
+                s
+            )
+        }
+        (IpAddr::V4(g), None) => Ok(Mcast::V4(g)),
+        (IpAddr::V6(g), Some(i)) => Ok(Mcast::V6(g, if_index(i)?)),
+        (IpAddr::V6(g), None) => Ok(Mcast::V6(g, 0)),
+    }
+}
+
+fn bind_socket(addr: SocketAddr, mcast: Option<Mcast>) -> Result<UdpSocket> {
+    let socket = UdpSocket::bind(addr).with_context(|| format!("UdpSocket::bind({})", addr))?;
+    let mcast = if let Some(mcast) = mcast {
+        mcast
+    } else {
+        return Ok(socket);
+    };
+    // Options for the sending side are not in std
+    let socket = socket2::Socket::from(socket);
+    match (mcast, addr) {
+        (Mcast::V4(group), SocketAddr::V4(a)) => {
+            socket.join_multicast_v4(&group, a.ip())?;
+            socket.set_multicast_if_v4(a.ip())?;
+            socket.set_multicast_loop_v4(false)?;
+        }
+        (Mcast::V6(group, iface), SocketAddr::V6(_)) => {
+            socket
+                .join_multicast_v6(&group, iface)
+                .with_context(|| format!("join_multicast_v6({}, {})", group, iface))?;
+            socket.set_multicast_if_v6(iface)?;
+            socket.set_multicast_loop_v6(false)?;
+        }
+        (Mcast::V4(_), _) => bail!("Cannot do ipv4 multicast with ipv6 bind"),
+        (Mcast::V6(..), _) => bail!("Cannot do ipv6 multicast with ipv4 bind"),
+    }
+    Ok(socket.into_udp_socket())
+}
+
+// Peers in subscribe_to are udp addresses or tcp://host:port
+fn split_subscribe_to(peers: &[String]) -> Result<(Vec<SocketAddr>, Vec<String>)> {
+    let mut udp = Vec::new();
+    let mut tcp = Vec::new();
+    for p in peers {
+        if let Some(addr) = p.strip_prefix("tcp://") {
+            if !addr.contains(':') {
+                bail!("Missing port in [{}]", p);
+            }
+            tcp.push(addr.to_owned());
+        } else {
+            udp.push(
+                p.parse()
+                    .with_context(|| format!("SocketAddr parse({})", p))?,
+            );
+        }
+    }
+    Ok((udp, tcp))
+}
+
+// Whether an ann passes a peer's filter, given the highest parent block height we know of
+fn wanted(filter: &SprayerFilter, top_height: i32, ann: &[u8]) -> bool {
+    if filter.min_work != 0 && packetcrypt_sys::work_bits(ann) > filter.min_work {
+        return false;
+    }
+    // height_window comes from the peer so it can be anything
+    filter.height_window == 0
+        || packetcrypt_sys::parent_block_height(ann) as i64
+            > (top_height as i64).saturating_sub(filter.height_window as i64)
+}
+
+fn parse_addrs(addrs: &[String]) -> Result<Vec<SocketAddr>> {
+    addrs
+        .iter()
+        .map(|s| {
+            s.parse()
+                .with_context(|| format!("SocketAddr parse({})", s))
+        })
+        .collect()
 }
 
 #[cfg(windows)]
@@ -245,12 +488,8 @@ impl Sprayer {
             }
         };
 
-        let mcast: Option<Ipv4Addr> = if !cfg.mcast.is_empty() {
-            Some(
-                cfg.mcast
-                    .parse()
-                    .with_context(|| format!("mcast parse({})", cfg.mcast))?,
-            )
+        let mcast = if !cfg.mcast.is_empty() {
+            Some(parse_mcast(&cfg.mcast)?)
         } else {
             None
         };
@@ -259,15 +498,8 @@ impl Sprayer {
             .bind
             .parse()
             .with_context(|| format!("SocketAddr parse({})", cfg.bind))?;
-        let socket = UdpSocket::bind(addr).with_context(|| format!("UdpSocket::bind({})", addr))?;
+        let socket = bind_socket(addr, mcast)?;
         socket.set_nonblocking(true)?;
-        if let Some(mcast) = mcast {
-            if let SocketAddr::V4(addr) = addr {
-                socket.join_multicast_v4(&mcast, addr.ip())?;
-            } else {
-                bail!("Cannot do multicast with ipv6 bind");
-            }
-        }
 
         let fd = raw_fd(&socket);
         let pkt_size = (cfg.mss / PKT_LENGTH) * PKT_LENGTH;
@@ -288,48 +520,45 @@ impl Sprayer {
             );
         }
 
-        let m = RwLock::new(SprayerMut {
-            subscribers: Vec::new(),
-        });
-
-        let mut subscribed_to: HashMap<SocketAddr, Subscription> = HashMap::new();
-        let mut subscribe_to = Vec::new();
-        for s in &cfg.subscribe_to {
-            let peer = s.parse()?;
-            subscribe_to.push(peer);
-            subscribed_to.insert(
-                peer,
-                Subscription {
-                    last_update_sec: AtomicUsize::new(0),
-                    packets_received: AtomicUsize::new(0),
-                },
-            );
-        }
+        let (subscribe_to, tcp_subscribe_to) = split_subscribe_to(&cfg.subscribe_to)?;
+        let spray_at = parse_addrs(&cfg.spray_at)?;
+        let tcp_listener = if !cfg.tcp_bind.is_empty() {
+            Some(
+                TcpListener::bind(&cfg.tcp_bind)
+                    .with_context(|| format!("TcpListener::bind({})", cfg.tcp_bind))?,
+            )
+        } else {
+            None
+        };
 
         let chunk_pool = Arc::new(ChunkPool {
             q: Mutex::new(VecDeque::new()),
         });
 
-        let mut force_subscribe = Vec::new();
-        for s in &cfg.spray_at {
-            let peer = s.parse()?;
-            force_subscribe.push(Subscriber {
-                peer,
-                send_queue: Mutex::new(SendQueue {
-                    next_num: 0,
-                    q: VecDeque::new(),
-                    chunk_pool: Arc::clone(&chunk_pool),
-                }),
-                last_update_sec: AtomicUsize::new(0),
-            });
-        }
-
-        Ok(Sprayer(Arc::new(SprayerS {
-            m,
-            subscribe_to,
-            subscribed_to,
-            force_subscribe,
-            passwd: cfg.passwd.clone(),
+        let sprayer = Sprayer(Arc::new(SprayerS {
+            m: RwLock::new(SprayerMut {
+                subscribers: Vec::new(),
+                force_subscribe: Vec::new(),
+                tcp_subscribers: Vec::new(),
+            }),
+            subscribed_to: RwLock::new(HashMap::new()),
+            tcp_subscribed_to: RwLock::new(HashMap::new()),
+            tcp_listener: Mutex::new(tcp_listener),
+            tcp_conns: AtomicUsize::new(0),
+            tcp_subs_seen: Mutex::new(HashMap::new()),
+            started: AtomicBool::new(false),
+            relay: if cfg.relay {
+                Some(relay::Relay::new(cfg.min_work))
+            } else {
+                None
+            },
+            filter: SprayerFilter {
+                min_work: cfg.filter_work,
+                height_window: cfg.filter_height,
+            },
+            top_height: Mutex::new(relay::TopHeight::default()),
+            passwd: RwLock::new(cfg.passwd.clone()),
+            keys: RwLock::new(crypto::Keys::new(&cfg.passwd)),
             socket,
             gso_ok: gso_err.is_none(),
             is_mcast: mcast.is_some(),
@@ -339,10 +568,110 @@ impl Sprayer {
             peer_counters: Mutex::new(HashMap::new()),
             peer_stats: Mutex::new(Vec::new()),
             log_peer_stats: cfg.log_peer_stats,
+            nack: cfg.nack,
+            max_kbps: cfg.max_kbps,
+            adaptive: cfg.adaptive,
             chunk_pool,
             pkt_size,
             self_addr: addr,
-        })))
+        }));
+        sprayer.set_subscribe_to(&subscribe_to);
+        sprayer.set_tcp_subscribe_to(&tcp_subscribe_to);
+        sprayer.set_spray_at(&spray_at);
+        Ok(sprayer)
+    }
+
+    fn set_subscribe_to(&self, peers: &[SocketAddr]) {
+        let mut subscribed_to = self.0.subscribed_to.write();
+        subscribed_to.retain(|peer, _| peers.contains(peer));
+        for peer in peers {
+            subscribed_to.entry(*peer).or_insert_with(Subscription::new);
+        }
+    }
+
+    fn set_tcp_subscribe_to(&self, peers: &[String]) {
+        {
+            let mut tcp_subscribed_to = self.0.tcp_subscribed_to.write();
+            tcp_subscribed_to.retain(|peer, ts| {
+                let keep = peers.contains(peer);
+                if !keep {
+                    ts.stop.store(true, atomic::Ordering::Relaxed);
+                }
+                keep
+            });
+            for peer in peers {
+                tcp_subscribed_to
+                    .entry(peer.clone())
+                    .or_insert_with(|| Arc::new(tcp::TcpSubscription::new()));
+            }
+        }
+        if self.0.started.load(atomic::Ordering::Relaxed) {
+            self.tcp_subscribe();
+        }
+    }
+
+    fn set_spray_at(&self, peers: &[SocketAddr]) {
+        let mut m = self.0.m.write();
+        m.force_subscribe.retain(|s| peers.contains(&s.peer));
+        for peer in peers {
+            if m.force_subscribe.iter().any(|s| s.peer == *peer) {
+                continue;
+            }
+            let s = self.new_subscriber(*peer, 0);
+            m.force_subscribe.push(s);
+        }
+    }
+
+    fn new_subscriber(&self, peer: SocketAddr, now_sec: usize) -> Subscriber {
+        Subscriber {
+            peer,
+            send_queue: Mutex::new(SendQueue {
+                next_num: 0,
+                stream: self.0.keys.read().new_stream(util::now_ms()),
+                q: VecDeque::new(),
+                chunk_pool: Arc::clone(&self.0.chunk_pool),
+            }),
+            last_update_sec: AtomicUsize::new(now_sec),
+            last_sub_ms: AtomicU64::new(0),
+            wants_history: AtomicBool::new(false),
+            history: Mutex::new(VecDeque::new()),
+            packets_resent: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            rate: Mutex::new(rate::RateLimit::new(
+                self.0.max_kbps,
+                self.0.adaptive,
+                PKT_LENGTH,
+            )),
+            filter: Mutex::new(SprayerFilter::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Change the password and the peers while running, peers which remain keep their
+    /// queues and counters.
+    pub fn reconfigure(
+        &self,
+        passwd: &str,
+        subscribe_to: &[String],
+        spray_at: &[String],
+    ) -> Result<()> {
+        let (subscribe_to, tcp_subscribe_to) = split_subscribe_to(subscribe_to)?;
+        let spray_at = parse_addrs(spray_at)?;
+        if *self.0.passwd.read() != passwd {
+            *self.0.keys.write() = crypto::Keys::new(passwd);
+            *self.0.passwd.write() = passwd.to_owned();
+            // Every stream was keyed from the old password so start them all over
+            self.0.subscribed_to.write().clear();
+            self.set_tcp_subscribe_to(&[]);
+            let mut m = self.0.m.write();
+            m.subscribers.clear();
+            m.force_subscribe.clear();
+            m.tcp_subscribers.clear();
+        }
+        self.set_subscribe_to(&subscribe_to);
+        self.set_tcp_subscribe_to(&tcp_subscribe_to);
+        self.set_spray_at(&spray_at);
+        Ok(())
     }
 
     pub fn set_handler<T: 'static + OnAnns>(&self, handler: T) {
@@ -351,23 +680,86 @@ impl Sprayer {
 
     pub fn push_anns(&self, anns: &[&[u8]]) -> usize {
         let oldest_allowed_time = (util::now_ms() / 1000) as usize - SECONDS_UNTIL_SUB_TIMEOUT;
-        let mut overflow = 0;
-        for s in &self.0.force_subscribe {
-            let mut sq = s.send_queue.lock();
+        if anns.is_empty() {
+            return 0;
+        }
+        let top_height = {
+            let mut top = self.0.top_height.lock();
             for ann in anns {
-                overflow += sq.push_ann(ann);
+                top.see(packetcrypt_sys::parent_block_height(ann), ann);
             }
-        }
+            top.get()
+        };
+        let push = |s: &Subscriber| {
+            let filter = *s.filter.lock();
+            let mut sq = s.send_queue.lock();
+            anns.iter()
+                .filter(|ann| wanted(&filter, top_height, ann))
+                .map(|ann| sq.push_ann(ann))
+                .sum::<usize>()
+        };
+        let mut overflow = 0;
         let m = self.0.m.read();
+        for s in &m.force_subscribe {
+            overflow += push(s);
+        }
         for s in &m.subscribers {
             let lus = s.last_update_sec.load(atomic::Ordering::Relaxed);
             if lus < oldest_allowed_time {
                 continue;
             }
-            let mut sq = s.send_queue.lock();
-            for ann in anns {
-                overflow += sq.push_ann(ann);
-            }
+            overflow += push(s);
+        }
+        for s in &m.tcp_subscribers {
+            overflow += push(s);
+            s.ready.notify_one();
+        }
+        overflow
+    }
+
+    /// Number of peers which anns are being sent to.
+    pub fn peer_count(&self) -> usize {
+        let oldest_allowed_time = (util::now_ms() / 1000) as usize - SECONDS_UNTIL_SUB_TIMEOUT;
+        let m = self.0.m.read();
+        let subscribers = m
+            .subscribers
+            .iter()
+            .filter(|s| s.last_update_sec.load(atomic::Ordering::Relaxed) >= oldest_allowed_time)
+            .count();
+        subscribers + m.force_subscribe.len() + m.tcp_subscribers.len()
+    }
+
+    /// The longest send queue of any peer, in chunks.
+    pub fn queued_chunks(&self) -> usize {
+        let m = self.0.m.read();
+        let tcp_subscribers = m.tcp_subscribers.iter().map(|s| &**s);
+        m.subscribers
+            .iter()
+            .chain(m.force_subscribe.iter())
+            .chain(tcp_subscribers)
+            .map(|s| s.send_queue.lock().q.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Pass received anns to the handler and on to our subscribers
+    fn deliver(&self, chunk: &Chunk) -> usize {
+        let bufs = chunk
+            .ann_iter()
+            .map(|v| &v[MSG_PREFIX..(MSG_PREFIX + 1024)])
+            .collect::<Vec<_>>();
+        let relayed;
+        let bufs = if let Some(relay) = &self.0.relay {
+            relayed = relay.filter(&bufs);
+            relayed.iter().map(|a| &a[..]).collect()
+        } else {
+            bufs
+        };
+        let overflow = self.push_anns(&bufs);
+        let handler = self.0.handler.read();
+        match &*handler {
+            Some(h) => h.on_anns(&bufs),
+            None => (),
         }
         overflow
     }
@@ -387,42 +779,145 @@ impl Sprayer {
                 .run();
             });
         }
+        if let Some(listener) = self.0.tcp_listener.lock().take() {
+            let g = Sprayer(Arc::clone(&self.0));
+            std::thread::spawn(move || g.tcp_listen(listener));
+        }
+        self.0.started.store(true, atomic::Ordering::Relaxed);
+        self.tcp_subscribe();
     }
 
-    fn get_to_send(&self, tid: usize) -> Option<(Box<Chunk>, SocketAddr)> {
-        {
-            let m = self.0.m.read();
-            if !m.subscribers.is_empty() {
-                let start = tid % m.subscribers.len();
-                for sub in &m.subscribers[start..] {
-                    if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                        return Some((chunk, sub.peer));
-                    }
+    // Returns the next chunk to send, with the number of packets of it which the
+    // peer's rate limit allows us to send now.
+    fn get_to_send(&self, tid: usize, now_ms: u64) -> Option<(Box<Chunk>, SocketAddr, usize)> {
+        let take = |sub: &Subscriber| {
+            let mut sq = sub.send_queue.lock();
+            let want = sq.q.back()?.len();
+            let allowed = sub.rate.lock().take(now_ms, want);
+            if allowed == 0 {
+                return None;
+            }
+            Some((sq.q.pop_back().unwrap(), sub.peer, allowed))
+        };
+        let m = self.0.m.read();
+        if !m.subscribers.is_empty() {
+            let start = tid % m.subscribers.len();
+            for sub in &m.subscribers[start..] {
+                if let Some(x) = take(sub) {
+                    return Some(x);
                 }
-                for sub in &m.subscribers[0..start] {
-                    if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                        return Some((chunk, sub.peer));
-                    }
+            }
+            for sub in &m.subscribers[0..start] {
+                if let Some(x) = take(sub) {
+                    return Some(x);
                 }
             }
         }
-        for sub in &self.0.force_subscribe {
-            if let Some(chunk) = sub.send_queue.lock().q.pop_back() {
-                return Some((chunk, sub.peer));
+        for sub in &m.force_subscribe {
+            if let Some(x) = take(sub) {
+                return Some(x);
             }
         }
         None
     }
 
-    fn return_to_send(&self, chunk: Box<Chunk>, addr: SocketAddr) {
-        for sub in &self.0.force_subscribe {
+    // Count what was sent and give back the rate limit for what was not
+    fn sent_packets(&self, addr: SocketAddr, sent: usize, unsent: usize) {
+        let m = self.0.m.read();
+        for sub in m.force_subscribe.iter().chain(m.subscribers.iter()) {
             if sub.peer == addr {
-                sub.send_queue.lock().q.push_back(chunk);
+                sub.packets_sent
+                    .fetch_add(sent as u64, atomic::Ordering::Relaxed);
+                if unsent > 0 {
+                    sub.rate.lock().refund(unsent);
+                }
+                return;
+            }
+        }
+    }
+
+    // The peer told us how many packets it has received from us
+    fn feedback(&self, from: SocketAddr, received: u64) {
+        let m = self.0.m.read();
+        for sub in m.subscribers.iter().chain(m.force_subscribe.iter()) {
+            if sub.peer == from {
+                let sent = sub.packets_sent.load(atomic::Ordering::Relaxed);
+                let backlogged = !sub.send_queue.lock().q.is_empty();
+                sub.rate
+                    .lock()
+                    .feedback(util::now_ms(), sent, received, backlogged);
                 return;
             }
         }
+    }
+
+    // Keep chunks which were sent, if the peer has ever asked for a retransmission
+    fn sent(&self, chunk: Box<Chunk>, addr: SocketAddr) {
         let m = self.0.m.read();
-        for sub in &m.subscribers {
+        let sub = m
+            .subscribers
+            .iter()
+            .chain(m.force_subscribe.iter())
+            .find(|s| s.peer == addr);
+        let sub = match sub {
+            Some(s) if s.wants_history.load(atomic::Ordering::Relaxed) => s,
+            _ => return self.0.chunk_pool.give(chunk),
+        };
+        let mut history = sub.history.lock();
+        history.push_back(chunk);
+        if history.len() > NACK_HISTORY_CHUNKS {
+            self.0.chunk_pool.give(history.pop_front().unwrap());
+        }
+    }
+
+    // Queue the packets which the peer is missing, if we still have them.
+    // Returns the number of packets queued.
+    fn retransmit(&self, to: SocketAddr, nack: &[(u64, u64)]) -> usize {
+        let m = self.0.m.read();
+        let sub = m
+            .subscribers
+            .iter()
+            .chain(m.force_subscribe.iter())
+            .find(|s| s.peer == to);
+        let sub = if let Some(s) = sub {
+            s
+        } else {
+            return 0;
+        };
+        sub.wants_history.store(true, atomic::Ordering::Relaxed);
+        let mut out = self.0.chunk_pool.take();
+        out.reset();
+        let mut sq = sub.send_queue.lock();
+        'outer: for c in sub.history.lock().iter() {
+            // Sent chunks can still hold packets from before they were recycled
+            for pkt in c.bytes[..c.ecur].chunks(PKT_LENGTH) {
+                if !sq.stream.owns(pkt) {
+                    continue;
+                }
+                let num = crypto::packet_num(pkt);
+                if !nack.iter().any(|&(a, b)| a <= num && num <= b) {
+                    continue;
+                }
+                if !out.push_sealed(pkt) {
+                    break 'outer;
+                }
+            }
+        }
+        let count = out.len();
+        if count > 0 {
+            sub.packets_resent
+                .fetch_add(count as u64, atomic::Ordering::Relaxed);
+            // Sent next since queues are sent from the back
+            sq.q.push_back(out);
+        } else {
+            self.0.chunk_pool.give(out);
+        }
+        count
+    }
+
+    fn return_to_send(&self, chunk: Box<Chunk>, addr: SocketAddr) {
+        let m = self.0.m.read();
+        for sub in m.force_subscribe.iter().chain(m.subscribers.iter()) {
             if sub.peer == addr {
                 sub.send_queue.lock().q.push_back(chunk);
                 return;
@@ -435,38 +930,108 @@ impl Sprayer {
     }
 
     fn send_subs(&self) -> Option<(std::io::Error, SocketAddr)> {
-        let now_sec = (util::now_ms() / 1000) as usize;
+        let now_ms = util::now_ms();
+        let now_sec = (now_ms / 1000) as usize;
         let update_time = now_sec - SECONDS_UNTIL_RESUB;
-        for (peer, sub) in self.0.subscribed_to.iter() {
+        for (peer, sub) in self.0.subscribed_to.read().iter() {
             let time_sec = sub.last_update_sec.load(atomic::Ordering::Relaxed);
-            if time_sec > update_time {
+            let received = sub.packets_received.load(atomic::Ordering::Relaxed);
+            let report_due =
+                sub.last_report_ms.load(atomic::Ordering::Relaxed) + REPORT_EVERY_MS <= now_ms;
+            let nack = if report_due && self.0.nack {
+                let span = (NACK_HISTORY_CHUNKS * ANN_PER_CHUNK) as u64;
+                sub.rx.lock().gaps(NACK_SKIP, span, NACK_MAX_RANGES)
+            } else {
+                Vec::new()
+            };
+            let report = report_due
+                && (!nack.is_empty()
+                    || received != sub.last_report_received.load(atomic::Ordering::Relaxed));
+            if time_sec > update_time && !report {
                 continue;
             }
-            let req = serde_json::to_string(&SprayerReq {
-                yes_please_dos_me_passwd: self.0.passwd.clone(),
-                num: Some(0),
-                count: Some(1),
+            let req = serde_json::to_vec(&SprayerReq {
+                time_ms: now_ms,
+                nack,
+                received: received as u64,
+                filter: self.0.filter,
+                cookie: *sub.cookie.lock(),
             })
             .unwrap();
+            let req = self.0.keys.read().seal_sub(&req);
             debug!("subscribing to {}", peer);
-            if let Err(e) = self.0.socket.send_to(&req.as_bytes(), peer) {
+            if let Err(e) = self.0.socket.send_to(&req, peer) {
                 return Some((e, *peer));
             }
             sub.last_update_sec
                 .store(now_sec, atomic::Ordering::Relaxed);
+            if report_due {
+                sub.last_report_ms.store(now_ms, atomic::Ordering::Relaxed);
+                sub.last_report_received
+                    .store(received, atomic::Ordering::Relaxed);
+            }
         }
         None
     }
 
-    fn incoming_subscription(&self, from: SocketAddr) {
+    // Authenticate and decrypt packets in place, moving the good ones to the front.
+    // Returns the number of good packets or None if we are not subscribed to this peer.
+    fn accept_packets(&self, from: &SocketAddr, buf: &mut [u8]) -> Option<usize> {
+        let subscribed_to = self.0.subscribed_to.read();
+        let sub = subscribed_to.get(from)?;
+        Some(self.open_packets(sub, buf))
+    }
+
+    fn open_packets(&self, sub: &Subscription, buf: &mut [u8]) -> usize {
+        let keys = self.0.keys.read();
+        let mut rx = sub.rx.lock();
+        let now_ms = util::now_ms();
+        let mut good = 0;
+        for i in 0..(buf.len() / PKT_LENGTH) {
+            let c0 = i * PKT_LENGTH;
+            if !rx.open(&keys, &mut buf[c0..(c0 + PKT_LENGTH)], now_ms) {
+                continue;
+            }
+            if good != i {
+                buf.copy_within(c0..(c0 + PKT_LENGTH), good * PKT_LENGTH);
+            }
+            good += 1;
+        }
+        sub.packets_received
+            .fetch_add(good, atomic::Ordering::Relaxed);
+        good
+    }
+
+    // A peer which we subscribe to has sent us a cookie, subscribe again right away
+    // because the subscription which it was a reply to was dropped.
+    fn incoming_cookie(&self, from: SocketAddr, cookie: [u8; crypto::COOKIE_LEN]) -> bool {
+        match self.0.subscribed_to.read().get(&from) {
+            Some(sub) => {
+                sub.cookie.lock().replace(cookie);
+                sub.last_update_sec.store(0, atomic::Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns false if the subscription is not newer than the last one from this peer
+    fn incoming_subscription(&self, from: SocketAddr, time_ms: u64, filter: SprayerFilter) -> bool {
         let now_sec = (util::now_ms() / 1000) as usize;
         let oldest_allowed_time = now_sec - SECONDS_UNTIL_SUB_TIMEOUT;
+        let update = |s: &Subscriber| {
+            if s.last_sub_ms.fetch_max(time_ms, atomic::Ordering::Relaxed) >= time_ms {
+                return false;
+            }
+            s.last_update_sec.store(now_sec, atomic::Ordering::Relaxed);
+            *s.filter.lock() = filter;
+            true
+        };
         {
             let m = self.0.m.read();
             for s in &m.subscribers {
                 if s.peer == from {
-                    s.last_update_sec.store(now_sec, atomic::Ordering::Relaxed);
-                    return;
+                    return update(s);
                 }
             }
         }
@@ -476,8 +1041,7 @@ impl Sprayer {
             while i < m.subscribers.len() {
                 let s = &mut m.subscribers[i];
                 if s.peer == from {
-                    s.last_update_sec.store(now_sec, atomic::Ordering::Relaxed);
-                    return;
+                    return update(s);
                 }
                 // Remove entries which are expired at the same time...
                 if s.last_update_sec.load(atomic::Ordering::Relaxed) < oldest_allowed_time {
@@ -486,15 +1050,59 @@ impl Sprayer {
                     i += 1;
                 }
             }
-            m.subscribers.push(Subscriber {
-                peer: from,
-                send_queue: Mutex::new(SendQueue {
-                    next_num: 0,
-                    q: VecDeque::new(),
-                    chunk_pool: Arc::clone(&self.0.chunk_pool),
-                }),
-                last_update_sec: AtomicUsize::new(now_sec),
-            });
+            let s = self.new_subscriber(from, now_sec);
+            s.last_sub_ms.store(time_ms, atomic::Ordering::Relaxed);
+            *s.filter.lock() = filter;
+            m.subscribers.push(s);
+        }
+        true
+    }
+
+    pub fn metrics(&self, out: &mut MetricsOut) {
+        for st in self.get_peer_stats() {
+            let peer = format!("{}", st.peer);
+            let h = "Sprayer traffic with each peer";
+            out.gauge(
+                "sprayer_kbps",
+                h,
+                &[("peer", &peer), ("dir", "in")],
+                st.kbps_in,
+            );
+            out.gauge(
+                "sprayer_kbps",
+                h,
+                &[("peer", &peer), ("dir", "out")],
+                st.kbps_out,
+            );
+            out.gauge(
+                "sprayer_kbps_cap",
+                "Send rate limit for each peer, 0 if unlimited",
+                &[("peer", &peer)],
+                st.kbps_cap,
+            );
+            out.gauge(
+                "sprayer_loss",
+                "Fraction of the packets from each peer which never arrived",
+                &[("peer", &peer)],
+                st.loss,
+            );
+        }
+        if let Some(relay) = &self.0.relay {
+            let anns = [
+                ("fresh", &relay.fresh),
+                ("dup", &relay.dup),
+                ("stale", &relay.stale),
+                ("low_work", &relay.low_work),
+                ("ahead", &relay.ahead),
+            ];
+            for (result, c) in &anns {
+                out.counter(
+                    "sprayer_relay_anns_total",
+                    "Announcements received in relay mode, by result",
+                    &[("result", result)],
+                    c.get() as f64,
+                );
+            }
         }
     }
 
@@ -515,30 +1123,51 @@ impl Sprayer {
         if self.0.log_peer_stats {
             info!("Sprayer links:");
         }
-        for sub in m.subscribers.iter().chain(self.0.force_subscribe.iter()) {
+        let tcp_subscribers = m.tcp_subscribers.iter().map(|s| &**s);
+        for sub in m
+            .subscribers
+            .iter()
+            .chain(m.force_subscribe.iter())
+            .chain(tcp_subscribers)
+        {
             let packets_sent_ever = { sub.send_queue.lock().next_num };
+            let resent_ever = sub.packets_resent.load(atomic::Ordering::Relaxed);
+            let kbps_cap = sub.rate.lock().kbps();
             match ps.get_mut(&sub.peer) {
                 Some(p) => {
-                    let packets = (packets_sent_ever - p.last_packets_sent) as u64;
+                    // Counters start over if the peer goes away and comes back
+                    let packets = packets_sent_ever.saturating_sub(p.last_packets_sent);
+                    let resent = resent_ever.saturating_sub(p.last_packets_resent);
                     let ms = now_ms - p.last_logged_ms;
                     let st = PeerStats {
                         peer: sub.peer,
                         packets_out: packets,
-                        kbps_out: compute_kbps(packets, ms),
+                        kbps_out: compute_kbps(packets + resent, ms),
                         packets_in: 0,
                         kbps_in: 0.0,
+                        loss: 0.0,
+                        packets_resent: resent,
+                        kbps_cap,
                     };
                     if self.0.log_peer_stats {
+                        let cap = if kbps_cap > 0.0 {
+                            format!(" (limit {})", util::format_kbps(kbps_cap))
+                        } else {
+                            String::new()
+                        };
                         info!(
-                            "<- {} sent {} anns {}",
+                            "<- {} sent {} anns {}{} resent {}",
                             sub.peer,
                             packets,
-                            util::format_kbps(st.kbps_out)
+                            util::format_kbps(st.kbps_out),
+                            cap,
+                            resent
                         );
                     }
                     peer_stats.push(st);
                     p.last_logged_ms = now_ms;
                     p.last_packets_sent = packets_sent_ever;
+                    p.last_packets_resent = resent_ever;
                 }
                 None => {
                     ps.insert(
@@ -547,53 +1176,82 @@ impl Sprayer {
                             last_logged_ms: now_ms,
                             last_packets_sent: packets_sent_ever,
                             last_packets_recv: 0,
+                            last_packets_expected: 0,
+                            last_packets_resent: resent_ever,
                         },
                     );
                 }
             }
         }
-        for peer in &self.0.subscribe_to {
-            let sub = if let Some(p) = self.0.subscribed_to.get(peer) {
-                p
-            } else {
-                continue;
-            };
-            match ps.get_mut(peer) {
+        let subscribed_to = self.0.subscribed_to.read();
+        let tcp_subscribed_to = self.0.tcp_subscribed_to.read();
+        let tcp_subs = tcp_subscribed_to
+            .values()
+            .filter_map(|ts| Some(((*ts.peer.read())?, &ts.sub)));
+        for (peer, sub) in subscribed_to
+            .iter()
+            .map(|(peer, sub)| (*peer, sub))
+            .chain(tcp_subs)
+        {
+            let packets_recv_ever = sub.packets_received.load(atomic::Ordering::Relaxed);
+            let expected_ever = sub.rx.lock().expected();
+            match ps.get_mut(&peer) {
                 Some(p) => {
-                    let packets_recv_ever = sub.packets_received.load(atomic::Ordering::Relaxed);
-                    let packets = (packets_recv_ever - p.last_packets_recv) as u64;
+                    let packets = packets_recv_ever.saturating_sub(p.last_packets_recv) as u64;
+                    let expected = expected_ever.saturating_sub(p.last_packets_expected);
                     let ms = now_ms - p.last_logged_ms;
                     let st = PeerStats {
-                        peer: *peer,
+                        peer,
                         kbps_in: compute_kbps(packets, ms),
                         kbps_out: 0.0,
                         packets_in: packets,
                         packets_out: 0,
+                        loss: if expected > packets {
+                            (expected - packets) as f64 / expected as f64
+                        } else {
+                            0.0
+                        },
+                        packets_resent: 0,
+                        kbps_cap: 0.0,
                     };
                     if self.0.log_peer_stats {
                         info!(
-                            "-> {} recv {} anns ({})",
+                            "-> {} recv {} anns ({}) loss {:.2}%",
                             peer,
                             packets,
-                            util::format_kbps(st.kbps_in)
+                            util::format_kbps(st.kbps_in),
+                            st.loss * 100.0
                         );
                     }
                     peer_stats.push(st);
                     p.last_logged_ms = now_ms;
-                    p.last_packets_recv = sub.packets_received.load(atomic::Ordering::Relaxed);
+                    p.last_packets_recv = packets_recv_ever;
+                    p.last_packets_expected = expected_ever;
                 }
                 None => {
                     ps.insert(
-                        *peer,
+                        peer,
                         PeerCounters {
                             last_logged_ms: now_ms,
-                            last_packets_recv: sub.packets_received.load(atomic::Ordering::Relaxed),
+                            last_packets_recv: packets_recv_ever,
                             last_packets_sent: 0,
+                            last_packets_expected: expected_ever,
+                            last_packets_resent: 0,
                         },
                     );
                 }
             }
         }
+        if let (Some(relay), true) = (&self.0.relay, self.0.log_peer_stats) {
+            info!(
+                "Relay: {} fresh, suppressed {} dup {} stale {} low work, held {} too far ahead",
+                relay.fresh.take(),
+                relay.dup.take(),
+                relay.stale.take(),
+                relay.low_work.take(),
+                relay.ahead.take()
+            );
+        }
         *self.0.peer_stats.lock() = peer_stats.clone();
         peer_stats
     }
@@ -714,42 +1372,103 @@ impl SprayWorker {
             }
         }
         let mut did_something = false;
-        while let Some((mut chunk, addr)) = self.g.get_to_send(self.tid) {
+        let now_ms = util::now_ms();
+        while let Some((mut chunk, addr, allowed)) = self.g.get_to_send(self.tid, now_ms) {
             did_something = true;
+            // Only send as much as the rate limit allows
+            let ecur = chunk.ecur;
+            let bcur = chunk.bcur;
+            chunk.ecur = bcur + allowed * PKT_LENGTH;
             if self.g.0.gso_ok {
                 self.send_gso(&mut chunk, addr);
             } else {
                 self.send_slow(&mut chunk, addr);
             }
+            let sent = (chunk.bcur - bcur) / PKT_LENGTH;
+            let blocked = chunk.bcur < chunk.ecur;
+            chunk.ecur = ecur;
+            self.g.sent_packets(addr, sent, allowed - sent);
             if chunk.len() > 0 {
                 self.g.return_to_send(chunk, addr);
-                break;
+                if blocked {
+                    break;
+                }
             } else {
-                self.g.0.chunk_pool.give(chunk);
+                self.g.sent(chunk, addr);
             }
         }
         did_something
     }
 
     fn maybe_subscribe(&mut self, msg: &[u8], from: SocketAddr) {
-        let msg = if let Ok(x) = serde_json::from_slice::<SprayerReq>(msg) {
-            x
-        } else {
+        if crypto::is_cookie(msg) {
+            let cookie = self.g.0.keys.read().open_cookie(msg);
+            if !matches!(cookie, Some(c) if self.g.incoming_cookie(from, c)) {
+                self.log(&|| debug!("Unexpected cookie from {}", from));
+            }
+            return;
+        }
+        if !crypto::is_sub(msg) {
             self.log(&|| {
                 debug!(
-                    "Got packet from {} which could not be decoded {}",
+                    "Got packet from {} which is not a v{} subscription {}",
                     from,
+                    crypto::VERSION,
                     hex::encode(msg)
                 )
             });
             return;
+        }
+        let json = self.g.0.keys.read().open_sub(msg);
+        let req = if let Some(x) = json.and_then(|j| serde_json::from_slice::<SprayerReq>(&j).ok())
+        {
+            x
+        } else {
+            self.log(&|| debug!("Subscription from {} with wrong password", from));
+            return;
         };
-        if msg.yes_please_dos_me_passwd != self.g.0.passwd {
-            self.log(&|| debug!("Packet from {} with wrong password", from));
+        let now_ms = util::now_ms();
+        if req.time_ms + SUB_MAX_SKEW_MS < now_ms || req.time_ms > now_ms + SUB_MAX_SKEW_MS {
+            self.log(&|| {
+                info!(
+                    "Subscription from {} is stale or their clock is off (theirs {} ours {})",
+                    from, req.time_ms, now_ms
+                )
+            });
+            return;
+        }
+        // Nothing is sent to an address until it has shown that it is really there
+        let keys = self.g.0.keys.read();
+        let age = req
+            .cookie
+            .and_then(|c| keys.cookie_age(&from, now_ms, &c[..]));
+        if age != Some(0) {
+            let _ = self
+                .g
+                .0
+                .socket
+                .send_to(&keys.seal_cookie(&from, now_ms), from);
+        }
+        drop(keys);
+        if age.is_none() {
+            self.log(&|| debug!("Subscription from {} without a cookie", from));
+            return;
+        }
+        if !self.g.incoming_subscription(from, req.time_ms, req.filter) {
+            self.log(&|| debug!("Replayed subscription from {}", from));
             return;
         }
         self.log(&|| debug!("Got subscription from {}", from));
-        self.g.incoming_subscription(from);
+        self.g.feedback(from, req.received);
+        if !req.nack.is_empty() {
+            let count = self.g.retransmit(from, &req.nack);
+            self.log(&|| {
+                debug!(
+                    "NACK from {} for {:?}, resending {} packets",
+                    from, req.nack, count
+                )
+            });
+        }
     }
 
     // If there's a stub packet then this is returned
@@ -763,21 +1482,27 @@ impl SprayWorker {
             let buf = &mut self.rchunk.bytes[self.rchunk.ecur..(self.rchunk.ecur + max_recv)];
             match self.g.0.socket.recv_from(buf) {
                 Ok((len, fr)) => {
-                    let mut ok = false;
                     let pkt_recv = len / PKT_LENGTH * PKT_LENGTH;
-                    if pkt_recv > 0 {
-                        if let Some(sub) = self.g.0.subscribed_to.get(&fr) {
-                            sub.packets_received.fetch_add(pkt_recv / 1024, atomic::Ordering::Relaxed);
-                            self.rchunk.ecur += pkt_recv;
-                            ok = true;
-                        }
-                    }
+                    let mut ok = false;
                     if len > pkt_recv {
                         out = Some((fr, Vec::from(&buf[pkt_recv..len])));
                         ok = true;
                     }
+                    if pkt_recv > 0 {
+                        let ecur = self.rchunk.ecur;
+                        let buf = &mut self.rchunk.bytes[ecur..(ecur + pkt_recv)];
+                        if let Some(good) = self.g.accept_packets(&fr, buf) {
+                            self.rchunk.ecur += good * PKT_LENGTH;
+                            ok = true;
+                            if good * PKT_LENGTH < pkt_recv {
+                                self.log(&|| debug!("Dropped bad or replayed packets from {}", fr));
+                            }
+                        }
+                    }
                     if !ok {
-                      self.log(&|| warn!("Got message (len {}) from unsubscribed node {}", len, fr));
+                        self.log(&|| {
+                            warn!("Got message (len {}) from unsubscribed node {}", len, fr)
+                        });
                     }
                 }
                 Err(e) => {
@@ -848,10 +1573,20 @@ impl SprayWorker {
                 &self.rchunk.bytes[self.rchunk.ecur + anns_len..self.rchunk.ecur + len],
             );
             self.maybe_subscribe(&x[0..stub_len], address);
-        } else if let Some(sub) = self.g.0.subscribed_to.get(&address) {
-            sub.packets_received
-                .fetch_add(count, atomic::Ordering::Relaxed);
-            self.rchunk.ecur += len;
+        } else if let Some(good) = self.g.accept_packets(
+            &address,
+            &mut self.rchunk.bytes[self.rchunk.ecur..(self.rchunk.ecur + len)],
+        ) {
+            self.rchunk.ecur += good * PKT_LENGTH;
+            if good < count {
+                self.log(&|| {
+                    debug!(
+                        "Dropped {} bad or replayed packets from {}",
+                        count - good,
+                        address
+                    )
+                });
+            }
         } else {
             self.log(&|| {
                 warn!(
@@ -891,18 +1626,219 @@ impl SprayWorker {
                 // keep polling until we have neatly a full buffer
                 continue;
             }
-            let bufs = self
-                .rchunk
-                .ann_iter()
-                .map(|v| &v[MSG_PREFIX..])
-                .collect::<Vec<_>>();
-            overflow += self.g.push_anns(&bufs);
-            let handler = self.g.0.handler.read();
-            match &*handler {
-                Some(h) => h.on_anns(&bufs),
-                None => (),
-            }
+            overflow += self.g.deliver(&self.rchunk);
             self.rchunk.reset();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(subscribe_to: &[&str], tcp_bind: &str) -> Config {
+        Config {
+            passwd: "pass".into(),
+            bind: "127.0.0.1:0".into(),
+            workers: 1,
+            subscribe_to: subscribe_to.iter().map(|s| s.to_string()).collect(),
+            log_peer_stats: false,
+            mss: 1472,
+            spray_at: Vec::new(),
+            mcast: String::new(),
+            nack: false,
+            max_kbps: 0,
+            adaptive: false,
+            tcp_bind: tcp_bind.into(),
+            relay: false,
+            min_work: 0,
+            filter_work: 0,
+            filter_height: 0,
+        }
+    }
+
+    #[test]
+    fn test_wanted() {
+        let mut ann = [0_u8; 1024];
+        ann[8..12].copy_from_slice(&0x20000fff_u32.to_le_bytes());
+        ann[12..16].copy_from_slice(&100_i32.to_le_bytes());
+        let any = SprayerFilter::default();
+        assert!(wanted(&any, 200, &ann));
+        let f = SprayerFilter {
+            min_work: 0x20000fff,
+            height_window: 2,
+        };
+        assert!(wanted(&f, 101, &ann));
+        assert!(!wanted(&f, 102, &ann));
+        let f = SprayerFilter {
+            min_work: 0,
+            height_window: u32::MAX,
+        };
+        assert!(wanted(&f, i32::MAX, &ann));
+        let f = SprayerFilter {
+            min_work: 0x200000ff,
+            height_window: 0,
+        };
+        assert!(!wanted(&f, 100, &ann));
+    }
+
+    struct Collect(Arc<Mutex<Vec<Vec<u8>>>>);
+    impl OnAnns for Collect {
+        fn on_anns(&self, anns: &[&[u8]]) {
+            self.0.lock().extend(anns.iter().map(|a| a.to_vec()));
+        }
+    }
+
+    // Unoptimized, making a chunk needs more than the default test thread stack
+    fn with_stack(f: fn()) {
+        std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_tcp() {
+        with_stack(tcp_transfer);
+    }
+
+    #[test]
+    fn test_udp() {
+        with_stack(udp_transfer);
+    }
+
+    fn udp_transfer() {
+        let a = Sprayer::new(&cfg(&[], "")).unwrap();
+        let addr = a.0.socket.local_addr().unwrap();
+        let b = Sprayer::new(&cfg(&[&addr.to_string()], "")).unwrap();
+        let got = Arc::new(Mutex::new(Vec::new()));
+        b.set_handler(Collect(Arc::clone(&got)));
+        a.start();
+        b.start();
+        // The first subscription only gets a cookie, b has to come back with it
+        for _ in 0..500 {
+            if !a.0.m.read().subscribers.is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let b_addr = b.0.socket.local_addr().unwrap();
+        assert_eq!(a.0.m.read().subscribers[0].peer, b_addr);
+        assert!(b.0.subscribed_to.read()[&addr].cookie.lock().is_some());
+
+        // The same subscription from anywhere else gets nothing but a cookie
+        let c = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let req = serde_json::to_vec(&SprayerReq {
+            time_ms: util::now_ms(),
+            cookie: *b.0.subscribed_to.read()[&addr].cookie.lock(),
+            ..Default::default()
+        })
+        .unwrap();
+        c.send_to(&a.0.keys.read().seal_sub(&req), addr).unwrap();
+        let mut buf = [0_u8; 2048];
+        c.set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let (len, _) = c.recv_from(&mut buf).unwrap();
+        assert!(crypto::is_cookie(&buf[..len]));
+        assert_eq!(a.0.m.read().subscribers.len(), 1);
+
+        // Anns are delivered once the receiver has close to a whole chunk
+        let anns = vec![[3_u8; 1024]; ANN_PER_CHUNK];
+        a.push_anns(&anns.iter().map(|a| &a[..]).collect::<Vec<_>>());
+        for _ in 0..500 {
+            if !got.lock().is_empty() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(got.lock().iter().all(|a| a[..] == [3_u8; 1024][..]));
+        assert!(!got.lock().is_empty());
+    }
+
+    fn tcp_transfer() {
+        let a = Sprayer::new(&cfg(&[], "127.0.0.1:0")).unwrap();
+        let port =
+            a.0.tcp_listener
+                .lock()
+                .as_ref()
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+        let b = Sprayer::new(&cfg(&[&format!("tcp://127.0.0.1:{}", port)], "")).unwrap();
+        let got = Arc::new(Mutex::new(Vec::new()));
+        b.set_handler(Collect(Arc::clone(&got)));
+        a.start();
+        b.start();
+        while a.0.m.read().tcp_subscribers.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let anns = [[1_u8; 1024], [2_u8; 1024]];
+        a.push_anns(&[&anns[0][..], &anns[1][..]]);
+        for _ in 0..500 {
+            if got.lock().len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut got = got.lock().clone();
+        got.sort();
+        assert_eq!(got, vec![anns[0].to_vec(), anns[1].to_vec()]);
+    }
+
+    #[test]
+    fn test_tcp_replay() {
+        with_stack(tcp_replay);
+    }
+
+    fn tcp_replay() {
+        use std::io::{Read, Write};
+        let a = Sprayer::new(&cfg(&[], "127.0.0.1:0")).unwrap();
+        let addr =
+            a.0.tcp_listener
+                .lock()
+                .as_ref()
+                .unwrap()
+                .local_addr()
+                .unwrap();
+        a.start();
+        let req = serde_json::to_vec(&SprayerReq {
+            time_ms: util::now_ms(),
+            ..Default::default()
+        })
+        .unwrap();
+        let sealed = a.0.keys.read().seal_sub(&req);
+        let mut frame = (sealed.len() as u32).to_le_bytes().to_vec();
+        frame.extend(sealed);
+        let mut first = std::net::TcpStream::connect(addr).unwrap();
+        first.write_all(&frame).unwrap();
+        while a.0.m.read().tcp_subscribers.is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut again = std::net::TcpStream::connect(addr).unwrap();
+        again.write_all(&frame).unwrap();
+        // Hung up on without being sent anything
+        assert_eq!(again.read(&mut [0_u8; 16]).unwrap(), 0);
+        assert_eq!(a.0.m.read().tcp_subscribers.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_mcast() {
+        let g: Ipv6Addr = "ff02::1:6".parse().unwrap();
+        assert_eq!(parse_mcast("ff02::1:6").unwrap(), Mcast::V6(g, 0));
+        assert_eq!(parse_mcast("ff02::1:6%3").unwrap(), Mcast::V6(g, 3));
+        assert_eq!(
+            parse_mcast("ff02::1:6%lo").unwrap(),
+            Mcast::V6(g, if_index("lo").unwrap())
+        );
+        assert!(parse_mcast("ff02::1:6%nosuchif0").is_err());
+        assert!(parse_mcast("fc00::1").is_err());
+        assert!(parse_mcast("239.1.2.3%3").is_err());
+        assert_eq!(
+            parse_mcast("239.1.2.3").unwrap(),
+            Mcast::V4(Ipv4Addr::new(239, 1, 2, 3))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Per peer send rate limit, a token bucket counted in packets.
+//
+// In adaptive mode the peer tells us how many packets it has received (in every
+// subscription) and we compare that with how many we put on the wire, if too many
+// are lost then the limit is cut to a fraction of the rate which we were sending
+// at, and while the peer is keeping up and we have more to send, it is raised again.
+
+// Bucket holds this much time worth of sending
+const BURST_MS: f64 = 50.0;
+
+// But always enough for one full size GSO send
+const MIN_BURST_PACKETS: f64 = 64.0;
+
+// Don't judge loss over less than this many packets
+const ADAPT_MIN_PACKETS: u64 = 256;
+
+const LOSS_HIGH: f64 = 0.02;
+const LOSS_LOW: f64 = 0.005;
+const DECREASE: f64 = 0.7;
+const INCREASE: f64 = 1.1;
+const ADAPTIVE_MIN_KBPS: f64 = 1000.0;
+
+fn packets_per_ms(kbps: f64, pkt_len: usize) -> f64 {
+    kbps / 8.0 / pkt_len as f64
+}
+
+pub struct RateLimit {
+    pkt_len: usize,
+    // 0 means unlimited
+    max_kbps: f64,
+    kbps: f64,
+    adaptive: bool,
+    tokens: f64,
+    last_ms: u64,
+    // time, packets sent and packets received at the last feedback
+    last_fb: Option<(u64, u64, u64)>,
+}
+impl RateLimit {
+    pub fn new(max_kbps: u64, adaptive: bool, pkt_len: usize) -> RateLimit {
+        RateLimit {
+            pkt_len,
+            max_kbps: max_kbps as f64,
+            kbps: max_kbps as f64,
+            adaptive,
+            tokens: 0.0,
+            last_ms: 0,
+            last_fb: None,
+        }
+    }
+
+    /// Current limit in kilobits per second, 0 if unlimited.
+    pub fn kbps(&self) -> f64 {
+        self.kbps
+    }
+
+    fn refill(&mut self, now_ms: u64) {
+        let ppms = packets_per_ms(self.kbps, self.pkt_len);
+        let cap = f64::max(ppms * BURST_MS, MIN_BURST_PACKETS);
+        let ms = now_ms.saturating_sub(self.last_ms) as f64;
+        self.tokens = f64::min(self.tokens + ppms * ms, cap);
+        self.last_ms = now_ms;
+    }
+
+    /// Take up to `want` packets worth of tokens, returns how many we may send.
+    pub fn take(&mut self, now_ms: u64, want: usize) -> usize {
+        if self.kbps <= 0.0 {
+            return want;
+        }
+        self.refill(now_ms);
+        let n = std::cmp::min(self.tokens as usize, want);
+        self.tokens -= n as f64;
+        n
+    }
+
+    /// Give back tokens for packets which were taken but could not be sent.
+    pub fn refund(&mut self, packets: usize) {
+        if self.kbps > 0.0 {
+            self.tokens += packets as f64;
+        }
+    }
+
+    /// Feedback from the peer, `sent` is how many packets we have sent it in total
+    /// and `received` is how many it says it got, `backlogged` means there is still
+    /// more waiting to be sent.
+    pub fn feedback(&mut self, now_ms: u64, sent: u64, received: u64, backlogged: bool) {
+        if !self.adaptive {
+            return;
+        }
+        let (ms0, sent0, recv0) = if let Some(fb) = self.last_fb {
+            fb
+        } else {
+            self.last_fb = Some((now_ms, sent, received));
+            return;
+        };
+        let d_sent = sent.saturating_sub(sent0);
+        if d_sent < ADAPT_MIN_PACKETS && sent >= sent0 {
+            // Not enough to go on yet, keep accumulating
+            return;
+        }
+        self.last_fb = Some((now_ms, sent, received));
+        if sent < sent0 || now_ms <= ms0 {
+            // Counters started over
+            return;
+        }
+        let d_recv = received.saturating_sub(recv0);
+        let loss = d_sent.saturating_sub(d_recv) as f64 / d_sent as f64;
+        if loss > LOSS_HIGH {
+            let rate = (d_sent * self.pkt_len as u64 * 8) as f64 / (now_ms - ms0) as f64;
+            let cur = if self.kbps > 0.0 {
+                f64::min(self.kbps, rate)
+            } else {
+                rate
+            };
+            self.kbps = f64::max(cur * DECREASE, ADAPTIVE_MIN_KBPS);
+        } else if loss < LOSS_LOW && backlogged && self.kbps > 0.0 {
+            self.kbps *= INCREASE;
+            if self.max_kbps > 0.0 && self.kbps > self.max_kbps {
+                self.kbps = self.max_kbps;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        // 1000 packets of 125 bytes per second
+        let mut rl = RateLimit::new(1000, false, 125);
+        assert_eq!(rl.take(0, 1000), 0);
+        assert_eq!(rl.take(10, 1000), 10);
+        assert_eq!(rl.take(10, 1000), 0);
+        rl.refund(3);
+        assert_eq!(rl.take(10, 1000), 3);
+        // capped at the burst size
+        assert_eq!(rl.take(100_000, 1000), 64);
+        assert_eq!(RateLimit::new(0, false, 125).take(0, 1000), 1000);
+    }
+
+    #[test]
+    fn test_adaptive() {
+        let mut rl = RateLimit::new(0, true, 125);
+        rl.feedback(1000, 0, 0, true);
+        // 10000 packets per second sent (10000 kbps), half lost
+        rl.feedback(2000, 10000, 5000, true);
+        assert!((rl.kbps() - 7000.0).abs() < 0.001);
+        // no loss, grows while there is more to send
+        rl.feedback(3000, 17000, 12000, true);
+        assert!((rl.kbps() - 7700.0).abs() < 0.001);
+        rl.feedback(4000, 24000, 19000, false);
+        assert!((rl.kbps() - 7700.0).abs() < 0.001);
+        // not enough packets to tell
+        rl.feedback(5000, 24100, 19000, true);
+        assert!((rl.kbps() - 7700.0).abs() < 0.001);
+    }
+}
@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Recording of the anns which a sprayer receives, and replaying them later to its
+// subscribers, so that what a block miner does with them can be reproduced without
+// a live ann handler.
+//
+// A recording is the magic followed by one record per batch of anns as it arrived:
+//   [ ms since the first batch (u64 LE) ][ count (u32 LE) ][ count * 1024 byte ann ]
+use crate::{OnAnns, Sprayer, MAX_SEND_QUEUE_CHUNKS_PER_PEER};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use packetcrypt_util::util;
+use parking_lot::Mutex;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8; 8] = b"pcspray1";
+const ANN_LEN: usize = 1024;
+
+// Sanity limit when reading, the sprayer never receives more than a chunk at once
+const MAX_BATCH: usize = 1 << 16;
+
+// When replaying as fast as possible, wait for the send queues to get this short
+const REPLAY_MAX_QUEUED: usize = MAX_SEND_QUEUE_CHUNKS_PER_PEER / 2;
+
+struct RecorderMut {
+    start_ms: u64,
+    out: BufWriter<File>,
+}
+
+pub struct Recorder {
+    m: Mutex<RecorderMut>,
+    // Only complain once
+    failed: AtomicBool,
+}
+impl Recorder {
+    pub fn create(path: &str) -> Result<Recorder> {
+        let f = File::create(path).with_context(|| format!("File::create({})", path))?;
+        let mut out = BufWriter::new(f);
+        out.write_all(MAGIC)?;
+        out.flush()?;
+        Ok(Recorder {
+            m: Mutex::new(RecorderMut { start_ms: 0, out }),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    fn write(&self, anns: &[&[u8]]) -> Result<()> {
+        let mut m = self.m.lock();
+        let now_ms = util::now_ms();
+        if m.start_ms == 0 {
+            m.start_ms = now_ms;
+        }
+        let ms = now_ms - m.start_ms;
+        m.out.write_all(&ms.to_le_bytes())?;
+        m.out.write_all(&(anns.len() as u32).to_le_bytes())?;
+        for ann in anns {
+            m.out.write_all(ann)?;
+        }
+        // So that the recording is usable whenever we are stopped
+        m.out.flush()?;
+        Ok(())
+    }
+}
+impl OnAnns for Recorder {
+    fn on_anns(&self, anns: &[&[u8]]) {
+        if anns.is_empty() {
+            return;
+        }
+        if let Err(e) = self.write(anns) {
+            if !self.failed.swap(true, atomic::Ordering::Relaxed) {
+                warn!("Unable to write to recording: {}", e);
+            }
+        }
+    }
+}
+
+pub struct Recording {
+    r: BufReader<File>,
+}
+impl Recording {
+    pub fn open(path: &str) -> Result<Recording> {
+        let f = File::open(path).with_context(|| format!("File::open({})", path))?;
+        let mut r = BufReader::new(f);
+        let mut magic = [0_u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("{} is not a sprayer recording", path);
+        }
+        Ok(Recording { r })
+    }
+
+    /// Read the next batch into `buf`, returns its time in ms since the first batch
+    /// or None at the end of the recording.
+    pub fn next(&mut self, buf: &mut Vec<u8>) -> Result<Option<u64>> {
+        let mut ms = [0_u8; 8];
+        match self.r.read_exact(&mut ms) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let mut count = [0_u8; 4];
+        self.r.read_exact(&mut count)?;
+        let count = u32::from_le_bytes(count) as usize;
+        if count > MAX_BATCH {
+            bail!("Batch of {} anns is too big, corrupt recording?", count);
+        }
+        buf.resize(count * ANN_LEN, 0);
+        self.r
+            .read_exact(&mut buf[..])
+            .context("Recording is truncated")?;
+        Ok(Some(u64::from_le_bytes(ms)))
+    }
+}
+
+/// Send the anns from a recording to our subscribers, `speed` 2.0 is twice as fast
+/// as they were recorded and 0.0 is as fast as the subscribers can take them.
+pub fn replay(spray: &Sprayer, path: &str, speed: f64) -> Result<()> {
+    let mut rec = Recording::open(path)?;
+    // Otherwise everything is sent to nobody
+    if spray.peer_count() == 0 {
+        info!("Waiting for a subscriber before replaying {}", path);
+        while spray.peer_count() == 0 {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    let start = Instant::now();
+    let mut buf = Vec::new();
+    let mut anns = 0;
+    let mut overflow = 0;
+    while let Some(ms) = rec.next(&mut buf)? {
+        if speed > 0.0 {
+            let due = Duration::from_secs_f64(ms as f64 / 1000.0 / speed);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        } else {
+            while spray.queued_chunks() > REPLAY_MAX_QUEUED {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let batch = buf.chunks(ANN_LEN).collect::<Vec<_>>();
+        overflow += spray.push_anns(&batch);
+        anns += batch.len();
+    }
+    while spray.queued_chunks() > 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    info!(
+        "Replayed {} anns from {} in {} seconds, {} overflowed",
+        anns,
+        path,
+        start.elapsed().as_secs(),
+        overflow
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let dir = packetcrypt_util::util::TempDir::new("spray");
+        let path = &format!("{}/anns.rec", dir.path());
+        let anns = [[1_u8; ANN_LEN], [2_u8; ANN_LEN], [3_u8; ANN_LEN]];
+        let rec = Recorder::create(path).unwrap();
+        rec.on_anns(&[&anns[0][..], &anns[1][..]]);
+        rec.on_anns(&[]);
+        rec.on_anns(&[&anns[2][..]]);
+        drop(rec);
+
+        let mut r = Recording::open(path).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(r.next(&mut buf).unwrap(), Some(0));
+        assert_eq!(buf, [&anns[0][..], &anns[1][..]].concat());
+        assert!(r.next(&mut buf).unwrap().is_some());
+        assert_eq!(buf, &anns[2][..]);
+        assert_eq!(r.next(&mut buf).unwrap(), None);
+    }
+}
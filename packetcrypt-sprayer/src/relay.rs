@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Relay mode, for sprayers which subscribe to more than one upstream and pass the
+// anns on, so that they can be arranged in a tree without multiplying the traffic.
+//
+// Anns are deduplicated by hash in one table per parent block height, the same as the
+// ann handler does, and the tables slide forward with the highest parent block height
+// which we have seen. Anns which are for an older block or have too little work are
+// dropped as well.
+//
+// The height is whatever the anns say, so a height which is far above the top is not
+// believed until enough different anns for it have come in, otherwise one ann with a
+// bogus height would make everything else look stale. The anns for such a height are
+// held back until then, and passed on once it is believed.
+use packetcrypt_util::hash;
+use packetcrypt_util::metrics::Counter;
+use parking_lot::Mutex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+// How many blocks back we still pass on anns for
+const HEIGHT_WINDOW: i32 = 3;
+
+// Different anns which it takes for a height far above the top to be believed
+const CONFIRM_ANNS: usize = 256;
+
+// Far ahead heights which are waiting for confirmation, any more and we start over
+const MAX_AHEAD: usize = 16;
+
+fn dedup_hash(ann: &[u8]) -> u64 {
+    let h = hash::compress32(ann);
+    u64::from_le_bytes(h[..8].try_into().unwrap())
+}
+
+/// Highest parent block height of the anns which we have seen.
+#[derive(Default)]
+pub struct TopHeight {
+    top: i32,
+    ahead: HashMap<i32, HashSet<u64>>,
+}
+impl TopHeight {
+    pub fn get(&self) -> i32 {
+        self.top
+    }
+
+    /// True if the height is far above the top and not yet believed.
+    pub fn is_ahead(&self, height: i32) -> bool {
+        self.ahead.contains_key(&height)
+    }
+
+    /// Take account of an ann, returns false if its height is too far above the top
+    /// to be believed yet.
+    pub fn see(&mut self, height: i32, ann: &[u8]) -> bool {
+        if height <= self.top {
+            return true;
+        }
+        if self.top != 0 && height <= self.top.saturating_add(HEIGHT_WINDOW) {
+            self.top = height;
+            self.ahead.retain(|h, _| *h > height);
+            return true;
+        }
+        if !self.ahead.contains_key(&height) && self.ahead.len() >= MAX_AHEAD {
+            self.ahead.clear();
+        }
+        let confirmed = {
+            let anns = self.ahead.entry(height).or_default();
+            anns.insert(dedup_hash(ann));
+            anns.len() >= CONFIRM_ANNS
+        };
+        if confirmed {
+            self.top = height;
+            self.ahead.retain(|h, _| *h > height);
+        }
+        confirmed
+    }
+}
+
+#[derive(Default)]
+struct Tables {
+    top: TopHeight,
+    seen: HashMap<i32, HashSet<u64>>,
+    // Anns for the heights which are not yet believed, by dedup hash
+    held: HashMap<i32, HashMap<u64, Vec<u8>>>,
+}
+
+#[derive(Default)]
+pub struct Relay {
+    // 0 means any amount of work, otherwise anns with a higher (easier) target are dropped
+    min_work: u32,
+    tables: Mutex<Tables>,
+    pub fresh: Counter,
+    pub dup: Counter,
+    pub stale: Counter,
+    pub low_work: Counter,
+    // Too far above the top, held until the height is confirmed
+    pub ahead: Counter,
+}
+impl Relay {
+    pub fn new(min_work: u32) -> Relay {
+        Relay {
+            min_work,
+            ..Default::default()
+        }
+    }
+
+    /// Keep only the anns which are worth passing on, along with any which were held
+    /// back for a height which has now been confirmed.
+    pub fn filter<'a>(&self, anns: &[&'a [u8]]) -> Vec<Cow<'a, [u8]>> {
+        let mut low_work = 0;
+        let hashed = anns
+            .iter()
+            .filter(|ann| {
+                let ok = self.min_work == 0 || packetcrypt_sys::work_bits(ann) <= self.min_work;
+                low_work += !ok as u64;
+                ok
+            })
+            .map(|ann| {
+                (
+                    *ann,
+                    packetcrypt_sys::parent_block_height(ann),
+                    dedup_hash(ann),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.low_work.add(low_work);
+
+        let mut out = Vec::with_capacity(hashed.len());
+        let (mut dup, mut stale, mut ahead) = (0, 0, 0);
+        let mut t = self.tables.lock();
+        for (ann, height, dedup) in hashed {
+            let top = t.top.get();
+            if !t.top.see(height, ann) {
+                ahead += 1;
+                t.held
+                    .entry(height)
+                    .or_default()
+                    .entry(dedup)
+                    .or_insert_with(|| ann.to_vec());
+                continue;
+            }
+            let mut ready = Vec::new();
+            if t.top.get() != top {
+                let top = t.top.get();
+                t.seen.retain(|h, _| *h > top - HEIGHT_WINDOW);
+                let heights = t.held.keys().filter(|h| **h <= top).copied();
+                for h in heights.collect::<Vec<_>>() {
+                    let held = t.held.remove(&h).unwrap();
+                    ready.extend(held.into_iter().map(|(d, a)| (Cow::Owned(a), h, d)));
+                }
+            }
+            ready.push((Cow::Borrowed(ann), height, dedup));
+            for (ann, height, dedup) in ready {
+                if height <= t.top.get() - HEIGHT_WINDOW {
+                    stale += 1;
+                } else if !t.seen.entry(height).or_default().insert(dedup) {
+                    dup += 1;
+                } else {
+                    out.push(ann);
+                }
+            }
+        }
+        // Heights which were given up on
+        let Tables { top, held, .. } = &mut *t;
+        held.retain(|h, _| top.is_ahead(*h));
+        drop(t);
+        self.dup.add(dup);
+        self.stale.add(stale);
+        self.ahead.add(ahead);
+        self.fresh.add(out.len() as u64);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ann(height: i32, work_bits: u32, n: u8) -> [u8; 1024] {
+        let mut a = [n; 1024];
+        a[8..12].copy_from_slice(&work_bits.to_le_bytes());
+        a[12..16].copy_from_slice(&height.to_le_bytes());
+        a
+    }
+
+    // They are held back until there are enough of them, then all of them go through
+    fn confirm(r: &Relay, height: i32) {
+        let anns = (0..CONFIRM_ANNS)
+            .map(|i| {
+                let mut a = ann(height, 0, 0);
+                a[100..108].copy_from_slice(&i.to_le_bytes());
+                a
+            })
+            .collect::<Vec<_>>();
+        let anns = anns.iter().map(|a| &a[..]).collect::<Vec<_>>();
+        let (first, last) = anns.split_at(CONFIRM_ANNS - 1);
+        assert_eq!(r.filter(first).len(), 0);
+        assert_eq!(r.filter(last).len(), CONFIRM_ANNS);
+        assert_eq!(r.tables.lock().top.get(), height);
+        assert!(r.tables.lock().held.get(&height).is_none());
+    }
+
+    #[test]
+    fn test_filter() {
+        let r = Relay::new(0x20000fff);
+        confirm(&r, 10);
+        let (a, b) = (ann(10, 0x20000fff, 1), ann(10, 0x20000fff, 2));
+        assert_eq!(r.filter(&[&a[..], &b[..], &a[..]]).len(), 2);
+        assert_eq!(r.filter(&[&b[..]]).len(), 0);
+        let easy = ann(10, 0x2000ffff, 3);
+        assert_eq!(r.filter(&[&easy[..]]).len(), 0);
+        // Moving up two blocks, 10 is still in the window
+        let c = ann(12, 0x20000fff, 4);
+        assert_eq!(r.filter(&[&c[..], &a[..]]).len(), 1);
+        let old = ann(9, 0x20000fff, 5);
+        assert_eq!(r.filter(&[&old[..]]).len(), 0);
+        assert_eq!(
+            (r.fresh.get(), r.dup.get(), r.low_work.get(), r.stale.get()),
+            (CONFIRM_ANNS as u64 + 3, 3, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_far_ahead() {
+        let r = Relay::new(0);
+        confirm(&r, 10);
+        assert_eq!(r.ahead.get(), CONFIRM_ANNS as u64 - 1);
+        // One ann claiming a far off block does not make the rest stale
+        let bogus = ann(1_000_000, 0, 1);
+        assert_eq!(r.filter(&[&bogus[..], &bogus[..]]).len(), 0);
+        assert_eq!(r.ahead.get(), CONFIRM_ANNS as u64 + 1);
+        assert_eq!(r.tables.lock().held[&1_000_000].len(), 1);
+        let a = ann(11, 0, 2);
+        assert_eq!(r.filter(&[&a[..]]).len(), 1);
+        // But lots of anns for a new block do move it, after a relay was cut off
+        confirm(&r, 20);
+        assert_eq!(r.filter(&[&a[..]]).len(), 0);
+        assert_eq!(r.stale.get(), 1);
+        // Anns held for a height which is given up on are dropped with it
+        for h in 0..MAX_AHEAD as i32 {
+            let a = ann(100 + h, 0, 3);
+            assert_eq!(r.filter(&[&a[..]]).len(), 0);
+        }
+        assert!(r.tables.lock().held.get(&1_000_000).is_none());
+        assert_eq!(r.tables.lock().held.len(), 1);
+    }
+}
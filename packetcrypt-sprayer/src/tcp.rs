@@ -0,0 +1,271 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Stream transport for peers which can't use UDP, e.g. because they are behind NAT.
+//
+// The subscriber connects and sends one frame with a sealed subscription, the same
+// as over UDP, then the sprayer sends frames of sealed packets from the subscriber's
+// send queue, a frame is a 4 byte little endian length followed by that many bytes:
+//   [ len ][ packet ][ packet ]...
+// Empty frames are sent as keepalives while there is nothing to send.
+//
+// A tcp subscription is never sent by anyone else, so one which was already used to
+// open a connection is dropped as a replay.
+use crate::{
+    crypto, Chunk, Sprayer, Subscriber, Subscription, CHUNK_LEN, PKT_LENGTH,
+    SECONDS_UNTIL_SUB_TIMEOUT, SUB_MAX_SKEW_MS,
+};
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use packetcrypt_util::protocol::SprayerReq;
+use packetcrypt_util::util;
+use parking_lot::RwLock;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use std::time::Duration;
+
+const KEEPALIVE_MS: u64 = 5000;
+const RECONNECT_SECS: u64 = 5;
+
+// Connections from subscribers beyond this many are closed right away
+const MAX_CONNS: usize = 256;
+
+// How long a new connection has to send its subscription
+const HANDSHAKE_SECS: u64 = 5;
+
+// How often the send loop looks for anns when it can't be woken, and how long it waits
+// for the rate limit
+const IDLE_WAIT_MS: u64 = 1000;
+const RATE_WAIT_MS: u64 = 5;
+
+// A subscription is much smaller than a packet
+const MAX_SUB_LEN: usize = PKT_LENGTH;
+
+pub struct TcpSubscription {
+    pub stop: AtomicBool,
+    pub running: AtomicBool,
+    // Known once we are connected
+    pub peer: RwLock<Option<SocketAddr>>,
+    pub sub: Subscription,
+}
+impl TcpSubscription {
+    pub fn new() -> TcpSubscription {
+        TcpSubscription {
+            stop: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            peer: RwLock::new(None),
+            sub: Subscription::new(),
+        }
+    }
+}
+
+fn read_frame(s: &mut TcpStream, buf: &mut [u8]) -> Result<usize> {
+    let mut len = [0_u8; 4];
+    s.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > buf.len() {
+        bail!("Frame of {} bytes is too big", len);
+    }
+    s.read_exact(&mut buf[..len])?;
+    Ok(len)
+}
+
+fn write_frame(s: &mut TcpStream, data: &[u8]) -> Result<()> {
+    s.write_all(&(data.len() as u32).to_le_bytes())?;
+    s.write_all(data)?;
+    Ok(())
+}
+
+impl Sprayer {
+    pub(crate) fn tcp_listen(&self, listener: TcpListener) {
+        info!("Accepting sprayer subscriptions over tcp");
+        for s in listener.incoming() {
+            match s {
+                Ok(s) => {
+                    let conns = self.0.tcp_conns.fetch_add(1, atomic::Ordering::Relaxed);
+                    if conns >= MAX_CONNS {
+                        self.0.tcp_conns.fetch_sub(1, atomic::Ordering::Relaxed);
+                        debug!(
+                            "Too many sprayer tcp connections, dropping {:?}",
+                            s.peer_addr()
+                        );
+                        continue;
+                    }
+                    let g = self.clone();
+                    std::thread::spawn(move || {
+                        let peer = s.peer_addr();
+                        if let Err(e) = g.tcp_subscriber(s) {
+                            info!("Sprayer tcp subscriber {:?} disconnected: {}", peer, e);
+                        }
+                        g.0.tcp_conns.fetch_sub(1, atomic::Ordering::Relaxed);
+                    });
+                }
+                Err(e) => warn!("Error accepting sprayer tcp connection {}", e),
+            }
+        }
+    }
+
+    fn tcp_subscriber(&self, mut s: TcpStream) -> Result<()> {
+        let peer = s.peer_addr()?;
+        s.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_SECS)))?;
+        // Nothing is read after the subscription, but a peer which stops reading
+        // must not hold the connection forever
+        s.set_write_timeout(Some(Duration::from_secs(SECONDS_UNTIL_SUB_TIMEOUT as u64)))?;
+        let mut msg = [0_u8; MAX_SUB_LEN];
+        let len = read_frame(&mut s, &mut msg)?;
+        let json = self.0.keys.read().open_sub(&msg[..len]);
+        let req = if let Some(x) = json.and_then(|j| serde_json::from_slice::<SprayerReq>(&j).ok())
+        {
+            x
+        } else {
+            bail!("Subscription with wrong password");
+        };
+        let now_ms = util::now_ms();
+        if req.time_ms + SUB_MAX_SKEW_MS < now_ms || req.time_ms > now_ms + SUB_MAX_SKEW_MS {
+            bail!(
+                "Subscription is stale or their clock is off (theirs {} ours {})",
+                req.time_ms,
+                now_ms
+            );
+        }
+        {
+            let mut seen = self.0.tcp_subs_seen.lock();
+            // Anything older is refused as stale anyway
+            seen.retain(|_, t| *t + SUB_MAX_SKEW_MS >= now_ms);
+            let tag: [u8; crypto::TAG_LEN] = msg[len - crypto::TAG_LEN..len].try_into()?;
+            if seen.insert(tag, req.time_ms).is_some() {
+                bail!("Replayed subscription");
+            }
+        }
+        info!("Got tcp subscription from {}", peer);
+        let sub = Arc::new(self.new_subscriber(peer, (now_ms / 1000) as usize));
+        *sub.filter.lock() = req.filter;
+        self.0.m.write().tcp_subscribers.push(Arc::clone(&sub));
+        let res = self.tcp_send_loop(&sub, &mut s);
+        self.0
+            .m
+            .write()
+            .tcp_subscribers
+            .retain(|x| !Arc::ptr_eq(x, &sub));
+        res
+    }
+
+    fn tcp_send_loop(&self, sub: &Arc<Subscriber>, s: &mut TcpStream) -> Result<()> {
+        let mut last_send_ms = util::now_ms();
+        loop {
+            let chunk = {
+                let mut sq = sub.send_queue.lock();
+                if sq.q.is_empty() {
+                    sub.ready
+                        .wait_for(&mut sq, Duration::from_millis(IDLE_WAIT_MS));
+                }
+                sq.q.pop_back()
+            };
+            if let Some(mut chunk) = chunk {
+                let allowed = sub.rate.lock().take(util::now_ms(), chunk.len());
+                if allowed == 0 {
+                    sub.send_queue.lock().q.push_back(chunk);
+                    std::thread::sleep(Duration::from_millis(RATE_WAIT_MS));
+                    continue;
+                }
+                let end = chunk.bcur + allowed * PKT_LENGTH;
+                let res = write_frame(s, &chunk.bytes[chunk.bcur..end]);
+                chunk.bcur = end;
+                sub.packets_sent
+                    .fetch_add(allowed as u64, atomic::Ordering::Relaxed);
+                if chunk.is_empty() {
+                    self.0.chunk_pool.give(chunk);
+                } else {
+                    sub.send_queue.lock().q.push_back(chunk);
+                }
+                res?;
+                last_send_ms = util::now_ms();
+                continue;
+            }
+            // Dropped by reconfigure
+            if !self
+                .0
+                .m
+                .read()
+                .tcp_subscribers
+                .iter()
+                .any(|x| Arc::ptr_eq(x, sub))
+            {
+                return Ok(());
+            }
+            if last_send_ms + KEEPALIVE_MS < util::now_ms() {
+                write_frame(s, &[])?;
+                last_send_ms = util::now_ms();
+            }
+        }
+    }
+
+    /// Start the threads for tcp:// subscriptions which are not already running.
+    pub(crate) fn tcp_subscribe(&self) {
+        for (addr, ts) in self.0.tcp_subscribed_to.read().iter() {
+            if ts.running.swap(true, atomic::Ordering::Relaxed) {
+                continue;
+            }
+            let g = self.clone();
+            let addr = addr.clone();
+            let ts = Arc::clone(ts);
+            let chunk = self.0.chunk_pool.take();
+            std::thread::spawn(move || g.tcp_subscription(&addr, &ts, chunk));
+        }
+    }
+
+    fn tcp_subscription(&self, addr: &str, ts: &TcpSubscription, mut chunk: Box<Chunk>) {
+        while !ts.stop.load(atomic::Ordering::Relaxed) {
+            if let Err(e) = self.tcp_session(addr, ts, &mut chunk) {
+                warn!("Sprayer subscription to tcp://{} failed: {}", addr, e);
+            }
+            for _ in 0..RECONNECT_SECS {
+                if ts.stop.load(atomic::Ordering::Relaxed) {
+                    break;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+        debug!("Stopped subscription to tcp://{}", addr);
+        self.0.chunk_pool.give(chunk);
+    }
+
+    fn tcp_session(&self, addr: &str, ts: &TcpSubscription, chunk: &mut Chunk) -> Result<()> {
+        let mut s = TcpStream::connect(addr)?;
+        *ts.peer.write() = Some(s.peer_addr()?);
+        s.set_read_timeout(Some(Duration::from_secs(SECONDS_UNTIL_SUB_TIMEOUT as u64)))?;
+        let req = serde_json::to_vec(&SprayerReq {
+            time_ms: util::now_ms(),
+            filter: self.0.filter,
+            ..Default::default()
+        })?;
+        let req = self.0.keys.read().seal_sub(&req);
+        write_frame(&mut s, &req)?;
+        info!("Subscribed to tcp://{}", addr);
+        loop {
+            chunk.reset();
+            let len = read_frame(&mut s, &mut chunk.bytes[..CHUNK_LEN])?;
+            if ts.stop.load(atomic::Ordering::Relaxed) {
+                return Ok(());
+            }
+            if len % PKT_LENGTH != 0 {
+                bail!("Frame of {} bytes is not a whole number of packets", len);
+            }
+            let good = self.open_packets(&ts.sub, &mut chunk.bytes[..len]);
+            if good * PKT_LENGTH < len {
+                debug!(
+                    "Dropped {} bad or replayed packets from tcp://{}",
+                    len / PKT_LENGTH - good,
+                    addr
+                );
+            }
+            chunk.ecur = good * PKT_LENGTH;
+            let overflow = self.deliver(chunk);
+            if overflow > 0 {
+                debug!("Send overflow of {} anns", overflow);
+            }
+        }
+    }
+}
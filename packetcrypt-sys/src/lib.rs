@@ -82,6 +82,28 @@ pub fn check_block_work(
     mining_height: i32,
     proof: &[u8],
 ) -> Result<[u8; 32], String> {
+    check_block_share(
+        header,
+        low_nonce,
+        share_target,
+        anns,
+        coinbase,
+        mining_height,
+        proof,
+    )
+    .map(|(h, _)| h)
+}
+
+/// Same as check_block_work but also returns true if the share is good enough to be a block.
+pub fn check_block_share(
+    header: &[u8],
+    low_nonce: u32,
+    share_target: u32,
+    anns: &[[u8; 1024]],
+    coinbase: &[u8],
+    mining_height: i32,
+    proof: &[u8],
+) -> Result<([u8; 32], bool), String> {
     let mut hap = BytesMut::with_capacity(80 + 8 + (1024 * 4) + proof.len());
     hap.put(header);
     hap.put_u32_le(0);
@@ -107,8 +129,8 @@ pub fn check_block_work(
         )
     } as u32;
     match res {
-        Validate_checkBlock_Res_Validate_checkBlock_OK
-        | Validate_checkBlock_Res_Validate_checkBlock_SHARE_OK => Ok(hashout),
+        Validate_checkBlock_Res_Validate_checkBlock_OK => Ok((hashout, true)),
+        Validate_checkBlock_Res_Validate_checkBlock_SHARE_OK => Ok((hashout, false)),
         Validate_checkBlock_Res_Validate_checkBlock_INSUF_POW => {
             Err(format!("INSUF_POW {}", hex::encode(hashout)))
         }
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// A directory of ann files the way block miners download them: anns_<n>.bin, with an
+// index.json listing the files which are there. Only the newest files_to_keep files
+// are kept.
+use crate::protocol::IndexFile;
+use crate::util;
+use anyhow::{bail, Result};
+use log::info;
+use regex::Regex;
+use std::collections::VecDeque;
+
+pub struct AnnFiles {
+    dir: String,
+    tmpdir: String,
+    files_to_keep: usize,
+    files: VecDeque<String>,
+    pub next_file_num: usize,
+    pub last_write_ms: u64,
+}
+
+/// Carry on from the ann files which are already in `dir`. Files are written in
+/// `tmpdir` and then moved into `dir`, so both need to be on the same filesystem.
+pub async fn open(dir: &str, tmpdir: &str, files_to_keep: usize) -> Result<AnnFiles> {
+    if files_to_keep == 0 {
+        bail!("files_to_keep must be at least 1");
+    }
+    util::ensure_exists_dir(dir).await?;
+    util::ensure_exists_dir(tmpdir).await?;
+    let regex = Regex::new(r"^anns_([0-9]+)\.bin$").unwrap();
+    let mut files = util::numbered_files(dir, &regex).await?;
+    files.sort_unstable_by_key(|(_, num)| *num);
+    Ok(AnnFiles {
+        dir: dir.to_owned(),
+        tmpdir: tmpdir.to_owned(),
+        files_to_keep,
+        // File numbers begin at 1 so that highest_ann_file = 0 means there are none
+        next_file_num: files.last().map(|(_, num)| num + 1).unwrap_or(1),
+        files: files.into_iter().map(|(name, _)| name).collect(),
+        last_write_ms: util::now_ms(),
+    })
+}
+
+pub async fn write_index(af: &AnnFiles) -> Result<()> {
+    let index = IndexFile {
+        highest_ann_file: af.next_file_num - 1,
+        files: af.files.iter().cloned().collect(),
+    };
+    let json = bytes::Bytes::from(serde_json::to_vec(&index)?);
+    util::write_file("index.json", &af.tmpdir, &af.dir, std::iter::once(&json)).await
+}
+
+/// Write one ann file and delete the oldest ones past files_to_keep, the index is not
+/// updated until write_index().
+pub async fn write(af: &mut AnnFiles, anns: &[bytes::Bytes]) -> Result<()> {
+    let name = format!("anns_{}.bin", af.next_file_num);
+    util::write_file(&name, &af.tmpdir, &af.dir, anns.iter()).await?;
+    af.files.push_back(name);
+    af.next_file_num += 1;
+    while af.files.len() > af.files_to_keep {
+        let old = af.files.pop_front().unwrap();
+        if let Err(e) = tokio::fs::remove_file(format!("{}/{}", af.dir, old)).await {
+            info!("Unable to delete old ann file {}: {}", old, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ann_files() {
+        let dir = util::TempDir::new("annfiles");
+        let dir = dir.path();
+        let tmpdir = format!("{}/tmp", dir);
+        let ann = bytes::Bytes::from(vec![0_u8; 1024]);
+        let mut af = open(dir, &tmpdir, 2).await.unwrap();
+        assert_eq!(af.next_file_num, 1);
+        for _ in 0..3 {
+            write(&mut af, &[ann.clone()]).await.unwrap();
+        }
+        write_index(&af).await.unwrap();
+        assert!(!std::path::Path::new(&format!("{}/anns_1.bin", dir)).exists());
+
+        // Carries on after a restart
+        let af = open(dir, &tmpdir, 2).await.unwrap();
+        assert_eq!(af.next_file_num, 4);
+        let index = std::fs::read(format!("{}/index.json", dir)).unwrap();
+        let index: IndexFile = serde_json::from_slice(&index).unwrap();
+        assert_eq!(index.highest_ann_file, 3);
+        assert_eq!(index.files, vec!["anns_2.bin", "anns_3.bin"]);
+    }
+}
@@ -7,7 +7,10 @@ macro_rules! async_spawn {
     }};
 }
 
+pub mod annfiles;
 pub mod hash;
+pub mod metrics;
 pub mod poolclient;
 pub mod protocol;
+pub mod rpcclient;
 pub mod util;
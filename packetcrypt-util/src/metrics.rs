@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use anyhow::Result;
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::Filter;
+
+const PREFIX: &str = "packetcrypt_";
+
+/// A counter which only goes up, it can also be read as the change since the last
+/// time it was logged so that the stats lines and the metrics can share it.
+#[derive(Default)]
+pub struct Counter {
+    total: AtomicU64,
+    logged: AtomicU64,
+}
+impl Counter {
+    pub fn add(&self, n: u64) {
+        self.total.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+    /// Get the amount which has been added since the last call to take()
+    pub fn take(&self) -> u64 {
+        let total = self.total.load(Ordering::Relaxed);
+        total.wrapping_sub(self.logged.swap(total, Ordering::Relaxed))
+    }
+}
+
+/// A floating point value which can be set from anywhere.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+struct Family {
+    help: String,
+    kind: &'static str,
+    samples: Vec<String>,
+}
+
+/// Collectors write their metrics here, samples are grouped by name when rendered
+/// so it does not matter what order they are written in.
+#[derive(Default)]
+pub struct MetricsOut {
+    families: BTreeMap<String, Family>,
+}
+impl MetricsOut {
+    pub fn counter(&mut self, name: &str, help: &str, labels: &[(&str, &str)], v: f64) {
+        self.sample("counter", name, help, labels, v);
+    }
+    pub fn gauge(&mut self, name: &str, help: &str, labels: &[(&str, &str)], v: f64) {
+        self.sample("gauge", name, help, labels, v);
+    }
+    fn sample(
+        &mut self,
+        kind: &'static str,
+        name: &str,
+        help: &str,
+        labels: &[(&str, &str)],
+        v: f64,
+    ) {
+        let name = format!("{}{}", PREFIX, name);
+        let mut s = name.clone();
+        if !labels.is_empty() {
+            let l = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(s, "{{{}}}", l).unwrap();
+        }
+        write!(s, " {}", v).unwrap();
+        self.families
+            .entry(name)
+            .or_insert_with(|| Family {
+                help: String::from(help),
+                kind,
+                samples: Vec::new(),
+            })
+            .samples
+            .push(s);
+    }
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, f) in &self.families {
+            writeln!(out, "# HELP {} {}", name, f.help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, f.kind).unwrap();
+            for s in &f.samples {
+                writeln!(out, "{}", s).unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub type Collector = Box<dyn Fn(&mut MetricsOut) + Send + Sync>;
+
+pub struct MetricsS {
+    collectors: Mutex<Vec<Collector>>,
+}
+pub type Metrics = Arc<MetricsS>;
+
+pub fn new() -> Metrics {
+    Arc::new(MetricsS {
+        collectors: Mutex::new(Vec::new()),
+    })
+}
+
+/// Add a function which will be called to get metrics each time they are requested.
+pub fn register<F>(m: &Metrics, f: F)
+where
+    F: Fn(&mut MetricsOut) + Send + Sync + 'static,
+{
+    m.collectors.lock().unwrap().push(Box::new(f));
+}
+
+pub fn render(m: &Metrics) -> String {
+    let mut out = MetricsOut::default();
+    for c in m.collectors.lock().unwrap().iter() {
+        c(&mut out);
+    }
+    out.render()
+}
+
+fn routes(
+    m: &Metrics,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone + Send + Sync + 'static
+{
+    let m = Arc::clone(m);
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(move || {
+            warp::reply::with_header(render(&m), "Content-Type", "text/plain; version=0.0.4")
+        })
+}
+
+/// Begin serving metrics over http at the given address.
+pub async fn start(m: &Metrics, bind: &str) -> Result<()> {
+    let addr: SocketAddr = bind.parse()?;
+    let (addr, server) = warp::serve(routes(m)).try_bind_ephemeral(addr)?;
+    info!("Serving metrics at http://{}/metrics", addr);
+    tokio::spawn(server);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut out = MetricsOut::default();
+        out.counter("anns_total", "Anns", &[("pool", "a")], 1.0);
+        out.gauge("height", "Height", &[], 5.0);
+        out.counter("anns_total", "Anns", &[("pool", "b\"")], 2.0);
+        assert_eq!(
+            out.render(),
+            "# HELP packetcrypt_anns_total Anns\n\
+            # TYPE packetcrypt_anns_total counter\n\
+            packetcrypt_anns_total{pool=\"a\"} 1\n\
+            packetcrypt_anns_total{pool=\"b\\\"\"} 2\n\
+            # HELP packetcrypt_height Height\n\
+            # TYPE packetcrypt_height gauge\n\
+            packetcrypt_height 5\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let m = new();
+        register(&m, |out| out.gauge("height", "Height", &[], 5.0));
+        let res = warp::test::request()
+            .path("/metrics")
+            .reply(&routes(&m))
+            .await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.body().as_ref(),
+            &b"# HELP packetcrypt_height Height\n\
+            # TYPE packetcrypt_height gauge\n\
+            packetcrypt_height 5\n"[..]
+        );
+        let res = warp::test::request().path("/").reply(&routes(&m)).await;
+        assert_eq!(res.status(), 404);
+    }
+
+    #[test]
+    fn test_counter_take() {
+        let c = Counter::default();
+        c.add(3);
+        assert_eq!(c.take(), 3);
+        c.add(2);
+        assert_eq!(c.take(), 2);
+        assert_eq!(c.take(), 0);
+        assert_eq!(c.get(), 5);
+    }
+}
@@ -4,10 +4,15 @@ use crate::util;
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::Receiver;
+use tokio::sync::Notify;
 use tokio::sync::RwLock;
 
+// Whatever asks for a refresh, don't fetch the config more often than this
+const MIN_REFRESH_MS: u64 = 2_000;
+
 #[derive(Debug)]
 pub struct PoolClientM {
     mc: Option<MasterConf>,
@@ -21,6 +26,7 @@ pub struct PoolClientS {
     poll_seconds: u64,
     notify: broadcast::Sender<PoolUpdate>,
     history_depth: i32,
+    refresh: Notify,
 }
 pub type PoolClient = Arc<PoolClientS>;
 
@@ -35,6 +41,7 @@ pub fn new(url: &str, history_depth: i32, poll_seconds: u64) -> PoolClient {
         url: String::from(url),
         notify: tx,
         history_depth,
+        refresh: Notify::new(),
     })
 }
 
@@ -48,6 +55,13 @@ pub async fn update_chan(pcli: &PoolClient) -> Receiver<PoolUpdate> {
     pcli.notify.subscribe()
 }
 
+/// Check the pool config soon rather than waiting for the next poll, for when
+/// something tells us that what we have is out of date. Any number of refreshes
+/// within MIN_REFRESH_MS of the last fetch make one fetch.
+pub fn refresh(pcli: &PoolClient) {
+    pcli.refresh.notify();
+}
+
 fn fmt_blk(hash: &[u8; 32], height: i32) -> String {
     format!("{} @ {}", hex::encode(&hash[..]), height)
 }
@@ -122,6 +136,7 @@ async fn discover_blocks(pcli: &PoolClient, height: i32, hash: &[u8; 32]) -> Vec
 
 async fn cfg_loop(pcli: &PoolClient) {
     loop {
+        let fetch_ms = util::now_ms();
         let url = format!("{}/config.json", pcli.url);
         let text = match util::get_url_text(&url).await {
             Err(e) => {
@@ -172,7 +187,12 @@ async fn cfg_loop(pcli: &PoolClient) {
                 info!("Failed to send conf update to channel");
             }
         }
-        util::sleep_ms(1_000 * pcli.poll_seconds).await;
+        let poll = Duration::from_secs(pcli.poll_seconds);
+        let _ = tokio::time::timeout(poll, pcli.refresh.notified()).await;
+        let since_ms = util::now_ms().saturating_sub(fetch_ms);
+        if since_ms < MIN_REFRESH_MS {
+            util::sleep_ms(MIN_REFRESH_MS - since_ms).await;
+        }
     }
 }
 
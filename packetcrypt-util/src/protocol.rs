@@ -3,6 +3,7 @@ use anyhow::{bail, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use serde::{Deserialize, Serialize};
 use serde_hex::{SerHex, SerHexOpt, SerHexSeq, Strict};
+use std::collections::BTreeMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
@@ -78,9 +79,62 @@ pub struct AnnPostReply {
     pub warn: Vec<String>,
     pub error: Vec<String>,
     pub result: Option<AnnsEvent>,
+
+    // Number of anns which were not accepted, by reason, the rest of the post is
+    // accepted as long as there is a result
+    #[serde(default)]
+    pub rejected: BTreeMap<AnnRejectReason, u32>,
+}
+
+// Why the ann handler did not accept an ann
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnRejectReason {
+    // Signed by someone other than the pool
+    WrongSigningKey,
+    // Signed when the pool is not signing anns
+    UnexpectedSignature,
+    WrongParentHeight,
+    NotEnoughWork,
+    // Hash is all zeros or all ones
+    BadHash,
+    // Belongs to a different handler of the pool
+    SubmitElsewhere,
+    UnsupportedVersion,
+    // Already submitted, or more than once in the same post
+    Duplicate,
+    // Failed validation
+    Invalid,
+    // A reason which this version does not know about
+    #[serde(other)]
+    Unknown,
+}
+impl AnnRejectReason {
+    /// The same name as it has on the wire, for logs and metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            AnnRejectReason::WrongSigningKey => "wrong_signing_key",
+            AnnRejectReason::UnexpectedSignature => "unexpected_signature",
+            AnnRejectReason::WrongParentHeight => "wrong_parent_height",
+            AnnRejectReason::NotEnoughWork => "not_enough_work",
+            AnnRejectReason::BadHash => "bad_hash",
+            AnnRejectReason::SubmitElsewhere => "submit_elsewhere",
+            AnnRejectReason::UnsupportedVersion => "unsupported_version",
+            AnnRejectReason::Duplicate => "duplicate",
+            AnnRejectReason::Invalid => "invalid",
+            AnnRejectReason::Unknown => "unknown",
+        }
+    }
 }
 
-#[derive(Deserialize, Debug, Clone, Default, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockSubmitReply {
+    pub warn: Vec<String>,
+    pub error: Vec<String>,
+    pub result: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct MasterConf {
     #[serde(with = "SerHexOpt::<Strict>")]
@@ -199,6 +253,30 @@ pub fn work_decode(out: &mut Work, b: &mut Bytes) -> Result<()> {
     Ok(())
 }
 
+pub fn blockheader_encode(hdr: &BlockHeader, b: &mut BytesMut) {
+    b.reserve(80);
+    b.put_u32_le(hdr.version);
+    b.put(&hdr.hash_prev_block[..]);
+    b.put(&hdr.hash_merkle_root[..]);
+    b.put_i32_le(hdr.time_seconds);
+    b.put_u32_le(hdr.work_bits);
+    b.put_u32_le(hdr.nonce);
+}
+
+pub fn work_encode(w: &Work, b: &mut BytesMut) {
+    blockheader_encode(&w.header, b);
+    b.reserve(32 + 16 + w.coinbase_no_witness.len() + w.coinbase_merkle.len() * 32);
+    b.put(&w.signing_key[..]);
+    b.put_u32_le(w.share_target);
+    b.put_u32_le(w.ann_target);
+    b.put_i32_le(w.height);
+    b.put_u32_le(w.coinbase_no_witness.len() as u32);
+    b.put(&w.coinbase_no_witness[..]);
+    for h in &w.coinbase_merkle {
+        b.put(&h[..]);
+    }
+}
+
 pub fn put_varint(num: u64, b: &mut BytesMut) {
     if num <= 0xfc {
         b.put_u8(num as u8);
@@ -214,6 +292,27 @@ pub fn put_varint(num: u64, b: &mut BytesMut) {
     }
 }
 
+pub fn get_varint(b: &mut Bytes) -> Result<u64> {
+    if b.remaining() < 1 {
+        bail!("runt varint");
+    }
+    let (num, len) = match b.get_u8() {
+        0xfd => (0, 2),
+        0xfe => (0, 4),
+        0xff => (0, 8),
+        x => (x as u64, 0),
+    };
+    if b.remaining() < len {
+        bail!("runt varint");
+    }
+    Ok(match len {
+        2 => b.get_u16_le() as u64,
+        4 => b.get_u32_le() as u64,
+        8 => b.get_u64_le(),
+        _ => num,
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct BlkShare {
     #[serde(with = "SerHexSeq::<Strict>")]
@@ -223,11 +322,116 @@ pub struct BlkShare {
     pub header_and_proof: Bytes,
 }
 
+// Sent sealed with the sprayer password, see packetcrypt-sprayer crypto.rs
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct SprayerReq {
-    pub yes_please_dos_me_passwd: String,
+    // Clock of the sender, used to reject replayed subscriptions
+    pub time_ms: u64,
+
+    // Ranges of packet numbers (inclusive) which the sender never received
+    #[serde(default)]
+    pub nack: Vec<(u64, u64)>,
+
+    // Total packets which the sender has received from us, for rate control
+    #[serde(default)]
+    pub received: u64,
+
+    // Which anns the sender wants to receive
+    #[serde(default)]
+    pub filter: SprayerFilter,
 
-    // deprecated, nolonger used
-    pub num: Option<u32>,
-    pub count: Option<u32>,
+    // From the sprayer's reply to our first subscription, proves that we can be
+    // reached at the address we are subscribing from
+    #[serde(default, with = "SerHexOpt::<Strict>")]
+    pub cookie: Option<[u8; 16]>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct SprayerFilter {
+    // Only anns with work_bits at or below this compact target, 0 for any
+    #[serde(default)]
+    pub min_work: u32,
+
+    // Only anns for one of this many most recent parent blocks, 0 for any
+    #[serde(default)]
+    pub height_window: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_roundtrip() {
+        let mut w = Work::default();
+        w.header.version = 0x20000000;
+        w.header.hash_prev_block = [7_u8; 32];
+        w.header.time_seconds = 1_600_000_000;
+        w.header.work_bits = 0x1d00ffff;
+        w.share_target = 0x207fffff;
+        w.ann_target = 0x200fffff;
+        w.height = 1234;
+        w.coinbase_no_witness = Bytes::from(vec![1_u8, 2, 3, 4, 5]);
+        w.coinbase_merkle = vec![Bytes::from(vec![9_u8; 32]), Bytes::from(vec![8_u8; 32])];
+
+        let mut b = BytesMut::new();
+        work_encode(&w, &mut b);
+        let mut out = Work::default();
+        work_decode(&mut out, &mut b.freeze()).unwrap();
+        assert_eq!(out.header.hash_prev_block, w.header.hash_prev_block);
+        assert_eq!(out.header.work_bits, w.header.work_bits);
+        assert_eq!(out.height, w.height);
+        assert_eq!(out.share_target, w.share_target);
+        assert_eq!(out.ann_target, w.ann_target);
+        assert_eq!(out.coinbase_no_witness, w.coinbase_no_witness);
+        assert_eq!(out.coinbase_merkle, w.coinbase_merkle);
+    }
+
+    #[test]
+    fn ann_post_reply_rejected() {
+        let json = r#"{"warn":[],"error":[],"result":null,
+            "rejected":{"not_enough_work":3,"something_new":1}}"#;
+        let reply: AnnPostReply = serde_json::from_str(json).unwrap();
+        assert_eq!(reply.rejected[&AnnRejectReason::NotEnoughWork], 3);
+        assert_eq!(reply.rejected[&AnnRejectReason::Unknown], 1);
+        // Older handlers don't send it at all
+        let json = r#"{"warn":[],"error":[],"result":null}"#;
+        let reply: AnnPostReply = serde_json::from_str(json).unwrap();
+        assert!(reply.rejected.is_empty());
+        // name() has to agree with serde
+        for r in &[
+            AnnRejectReason::WrongSigningKey,
+            AnnRejectReason::UnexpectedSignature,
+            AnnRejectReason::WrongParentHeight,
+            AnnRejectReason::NotEnoughWork,
+            AnnRejectReason::BadHash,
+            AnnRejectReason::SubmitElsewhere,
+            AnnRejectReason::UnsupportedVersion,
+            AnnRejectReason::Duplicate,
+            AnnRejectReason::Invalid,
+            AnnRejectReason::Unknown,
+        ] {
+            let json = serde_json::to_string(r).unwrap();
+            assert_eq!(json, format!("\"{}\"", r.name()));
+        }
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        for n in &[
+            0_u64,
+            0xfc,
+            0xfd,
+            0xffff,
+            0x10000,
+            0xffff_ffff,
+            0x1_0000_0000,
+        ] {
+            let mut b = BytesMut::new();
+            put_varint(*n, &mut b);
+            let mut b = b.freeze();
+            assert_eq!(get_varint(&mut b).unwrap(), *n);
+            assert_eq!(b.remaining(), 0);
+        }
+    }
 }
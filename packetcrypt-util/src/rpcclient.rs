@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+use crate::protocol::BlockInfoHeader;
+use anyhow::{bail, format_err, Result};
+use core::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_hex::{SerHex, Strict};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const RPC_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Serialize)]
+struct RpcReq<'a> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcRes {
+    error: Option<RpcError>,
+}
+
+// The result is parsed from the reply itself rather than from a Value because
+// some of the types (SerHex) can only be deserialized from borrowed strings
+#[derive(Deserialize)]
+struct RpcResult<T> {
+    result: T,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct BlockTemplateTx {
+    pub data: String,
+    pub txid: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct BlockTemplate {
+    pub version: u32,
+    #[serde(with = "SerHex::<Strict>")]
+    pub previousblockhash: [u8; 32],
+    pub transactions: Vec<BlockTemplateTx>,
+    pub coinbasevalue: u64,
+    pub curtime: u32,
+    pub bits: String,
+    pub height: i32,
+    pub default_witness_commitment: Option<String>,
+}
+
+/// A minimal JSON-RPC client for talking to pktd (or anything which speaks its dialect).
+pub struct RpcClientS {
+    url: String,
+    user: String,
+    pass: String,
+    client: reqwest::Client,
+    next_id: AtomicU64,
+}
+pub type RpcClient = Arc<RpcClientS>;
+
+pub fn new(url: &str, user: &str, pass: &str) -> Result<RpcClient> {
+    Ok(Arc::new(RpcClientS {
+        url: String::from(url),
+        user: String::from(user),
+        pass: String::from(pass),
+        client: reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(RPC_TIMEOUT_SECS))
+            .build()?,
+        next_id: AtomicU64::new(0),
+    }))
+}
+
+pub async fn call<T: DeserializeOwned>(rpc: &RpcClient, method: &str, params: Value) -> Result<T> {
+    let req = RpcReq {
+        jsonrpc: "1.0",
+        id: rpc.next_id.fetch_add(1, Ordering::Relaxed),
+        method,
+        params,
+    };
+    let mut rb = rpc
+        .client
+        .post(&rpc.url)
+        .header("content-type", "application/json")
+        .body(serde_json::to_vec(&req)?);
+    if !rpc.user.is_empty() || !rpc.pass.is_empty() {
+        rb = rb.basic_auth(&rpc.user, Some(&rpc.pass));
+    }
+    let res = rb.send().await?;
+    let status = res.status();
+    let resbytes = res.bytes().await?;
+    parse_reply(method, status, &resbytes)
+}
+
+fn parse_reply<T: DeserializeOwned>(
+    method: &str,
+    status: reqwest::StatusCode,
+    resbytes: &[u8],
+) -> Result<T> {
+    let reply = if let Ok(x) = serde_json::from_slice::<RpcRes>(resbytes) {
+        x
+    } else {
+        bail!(
+            "RPC {} replied [{}]: [{}] which cannot be parsed",
+            method,
+            status,
+            String::from_utf8_lossy(resbytes)
+        );
+    };
+    if let Some(e) = reply.error {
+        bail!("RPC {} failed with error {}: {}", method, e.code, e.message);
+    }
+    serde_json::from_slice::<RpcResult<T>>(resbytes)
+        .map(|r| r.result)
+        .map_err(|e| format_err!("RPC {} bad result: {}", method, e))
+}
+
+pub async fn get_best_block_hash(rpc: &RpcClient) -> Result<[u8; 32]> {
+    let hash: String = call(rpc, "getbestblockhash", json!([])).await?;
+    let mut out = [0_u8; 32];
+    hex::decode_to_slice(&hash, &mut out)?;
+    Ok(out)
+}
+
+pub async fn get_block_header(rpc: &RpcClient, hash: &[u8; 32]) -> Result<BlockInfoHeader> {
+    call(rpc, "getblockheader", json!([hex::encode(hash), true])).await
+}
+
+pub async fn get_block_template(rpc: &RpcClient) -> Result<BlockTemplate> {
+    call(rpc, "getblocktemplate", json!([{ "rules": ["segwit"] }])).await
+}
+
+/// Submit a serialized block, returns Ok(None) if the block was accepted or Some(reason)
+/// if the node refused it.
+pub async fn submit_block(rpc: &RpcClient, block: &[u8]) -> Result<Option<String>> {
+    call(rpc, "submitblock", json!([hex::encode(block)])).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_block_header() {
+        // What pktd replies to getblockheader <hash> true
+        let reply = br#"{"result":{"hash":"0000000000000a2b6b1bc3f7a2ffe58a3a4d8fad3aa2ae8b4c13b4dae5f0b0d5","confirmations":1,"height":1047616,"version":536870912,"versionHex":"20000000","merkleroot":"4a0e55d2c05b3f9b2cc0dbb2bdf3a7d1b45b01c9d2bb57e4a4ecd9e88d8a3b1f","time":1600000000,"nonce":1234567,"bits":"1a0d3a8f","difficulty":1281529.5,"previousblockhash":"0000000000002f13e2b1d6b0e4e8a6bb5c4f7fe4bd4bd6c1f7f6bf30f1e7c1e0"},"error":null,"id":3}"#;
+        let h: BlockInfoHeader =
+            parse_reply("getblockheader", reqwest::StatusCode::OK, &reply[..]).unwrap();
+        assert_eq!(h.height, 1047616);
+        assert_eq!(h.version_hex, [0x20, 0, 0, 0]);
+        assert_eq!(h.bits, [0x1a, 0x0d, 0x3a, 0x8f]);
+        assert_eq!(&h.hash[..4], &[0, 0, 0, 0]);
+        assert_eq!(h.previousblockhash[31], 0xe0);
+
+        let err = br#"{"result":null,"error":{"code":-5,"message":"Block not found"},"id":4}"#;
+        let e = parse_reply::<BlockInfoHeader>("getblockheader", reqwest::StatusCode::OK, &err[..]);
+        assert!(e.unwrap_err().to_string().contains("Block not found"));
+    }
+}
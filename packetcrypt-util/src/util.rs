@@ -223,6 +223,26 @@ pub async fn ensure_exists_dir(path: &str) -> Result<()> {
     Ok(())
 }
 
+/// A new directory in the system temp dir which is deleted along with everything in it
+/// when dropped, so that tests clean up after themselves even when they fail.
+pub struct TempDir(String);
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("pc{}-{}-{}", name, process::id(), rand_u32()));
+        let path = path.to_str().unwrap().to_owned();
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+}
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 fn now_sec() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -1,12 +1,15 @@
 // SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
 use anyhow::{bail, Context, Result};
 use clap::{App, Arg, SubCommand};
-use log::warn;
+use log::{error, info, warn};
 use packetcrypt_annhandler::annhandler;
-use packetcrypt_annmine::annmine;
+use packetcrypt_annmine::{annmine, solo};
+use packetcrypt_blkhandler::blkhandler;
 use packetcrypt_blkmine::blkmine;
-use packetcrypt_pool::{paymakerclient, poolcfg};
+use packetcrypt_pool::{master, paymaker, paymakerclient, poolcfg};
+use packetcrypt_util::metrics::{self, Metrics};
 use packetcrypt_util::{poolclient, util};
+use serde::Deserialize;
 #[cfg(not(target_os = "windows"))]
 use tokio::signal::unix::{signal, SignalKind};
 
@@ -17,6 +20,8 @@ static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[cfg(feature = "leak_detect")]
 mod alloc;
 
+mod minercfg;
+
 #[cfg(feature = "leak_detect")]
 async fn leak_detect() -> Result<()> {
     let al = alloc::alloc_init().await?;
@@ -55,12 +60,73 @@ async fn exiter() -> Result<()> {
     Ok(())
 }
 
-async fn ah_main(config: &str, handler: &str) -> Result<()> {
+// Serve prometheus metrics if --metrics-bind was given, each subcommand registers
+// its collectors on the returned Metrics before it settles in to run.
+async fn start_metrics(bind: Option<&str>) -> Result<Option<Metrics>> {
+    let bind = if let Some(b) = bind {
+        b
+    } else {
+        return Ok(None);
+    };
+    let m = metrics::new();
+    metrics::start(&m, bind)
+        .await
+        .with_context(|| format!("Failed to start metrics server on [{}]", bind))?;
+    Ok(Some(m))
+}
+
+async fn load_pool_cfg(config: &str) -> Result<poolcfg::Config> {
     let confb = tokio::fs::read(config)
         .await
         .with_context(|| format!("Failed to read config file [{}]", config))?;
-    let mut cfg: poolcfg::Config = toml::de::from_slice(&confb[..])
-        .with_context(|| format!("Failed to parse config file [{}]", config))?;
+    Ok(toml::de::from_slice(&confb[..])
+        .with_context(|| format!("Failed to parse config file [{}]", config))?)
+}
+
+#[cfg(not(target_os = "windows"))]
+async fn ah_reload(config: &str, handler: &str, ah: &annhandler::AnnHandler) -> Result<()> {
+    let mut cfg = load_pool_cfg(config).await?;
+    let hconf = if let Some(x) = cfg.ann_handler.remove(handler) {
+        x
+    } else {
+        bail!("{} is not defined in the config file [{}]", handler, config);
+    };
+    let need_restart = annhandler::reload(ah, hconf)?;
+    if need_restart.is_empty() {
+        info!("Reloaded config file [{}]", config);
+    } else {
+        info!(
+            "Reloaded config file [{}], restart to apply changes to: {}",
+            config,
+            need_restart.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+async fn ah_reloader(config: &str, handler: &str, ah: &annhandler::AnnHandler) -> Result<()> {
+    let mut s = signal(SignalKind::hangup())?;
+    let (config, handler, ah) = (config.to_owned(), handler.to_owned(), ah.clone());
+    tokio::spawn(async move {
+        loop {
+            s.recv().await;
+            info!("Got SIGHUP, reloading config file [{}]", config);
+            if let Err(e) = ah_reload(&config, &handler, &ah).await {
+                error!("Unable to reload config file [{}]: {:?}", config, e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+async fn ah_reloader(_config: &str, _handler: &str, _ah: &annhandler::AnnHandler) -> Result<()> {
+    Ok(())
+}
+
+async fn ah_main(config: &str, handler: &str, mx: Option<Metrics>) -> Result<()> {
+    let mut cfg = load_pool_cfg(config).await?;
 
     let hconf = if let Some(x) = cfg.ann_handler.remove(handler) {
         x
@@ -81,8 +147,15 @@ async fn ah_main(config: &str, handler: &str) -> Result<()> {
     .await?;
     paymakerclient::start(&pmc).await;
 
-    let ah = annhandler::new(&pc, &pmc, hconf).await?;
+    let workdir = format!("{}/ah/{}", &cfg.root_workdir, handler);
+    let ah = annhandler::new(&pc, &pmc, hconf, &workdir).await?;
     annhandler::start(&ah).await;
+    ah_reloader(config, handler, &ah).await?;
+
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| annhandler::metrics(&ah, out));
+        metrics::register(&mx, move |out| paymakerclient::metrics(&pmc, out));
+    }
 
     poolclient::start(&pc).await;
 
@@ -90,6 +163,72 @@ async fn ah_main(config: &str, handler: &str) -> Result<()> {
     util::sleep_forever().await
 }
 
+async fn bh_main(config: &str, handler: &str, mx: Option<Metrics>) -> Result<()> {
+    let mut cfg = load_pool_cfg(config).await?;
+
+    let hconf = if let Some(x) = cfg.block_handler.remove(handler) {
+        x
+    } else {
+        bail!("{} is not defined in the config file [{}]", handler, config);
+    };
+
+    let pc = poolclient::new(&cfg.master_url, 6, 5);
+
+    let pmc = paymakerclient::new(
+        &pc,
+        paymakerclient::PaymakerClientCfg {
+            paylogdir: format!("{}/bh/paylogdir", &cfg.root_workdir),
+            password: cfg.paymaker_http_password,
+            paylog_submit_every_ms: 60_000,
+        },
+    )
+    .await?;
+    paymakerclient::start(&pmc).await;
+
+    let bh = blkhandler::new(&pc, &pmc, hconf)?;
+    blkhandler::start(&bh).await;
+
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| blkhandler::metrics(&bh, out));
+        metrics::register(&mx, move |out| paymakerclient::metrics(&pmc, out));
+    }
+
+    poolclient::start(&pc).await;
+
+    util::sleep_forever().await
+}
+
+async fn master_main(config: &str, mx: Option<Metrics>) -> Result<()> {
+    let cfg = load_pool_cfg(config).await?;
+    let mconf = if let Some(x) = cfg.master {
+        x
+    } else {
+        bail!("[master] is not defined in the config file [{}]", config);
+    };
+    let m = master::new(mconf, &cfg.master_url)?;
+    master::start(&m).await;
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| master::metrics(&m, out));
+    }
+    util::sleep_forever().await
+}
+
+async fn paymaker_main(config: &str, mx: Option<Metrics>) -> Result<()> {
+    let cfg = load_pool_cfg(config).await?;
+    let pconf = if let Some(x) = cfg.paymaker {
+        x
+    } else {
+        bail!("[paymaker] is not defined in the config file [{}]", config);
+    };
+    let workdir = format!("{}/paymaker", &cfg.root_workdir);
+    let pm = paymaker::new(pconf, &cfg.paymaker_http_password, &workdir).await?;
+    paymaker::start(&pm).await;
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| paymaker::metrics(&pm, out));
+    }
+    util::sleep_forever().await
+}
+
 const DEFAULT_ADDR: &str = "pkt1q6hqsqhqdgqfd8t3xwgceulu7k9d9w5t2amath0qxyfjlvl3s3u4sjza2g2";
 
 fn warn_if_addr_default(payment_addr: &str) {
@@ -101,39 +240,80 @@ fn warn_if_addr_default(payment_addr: &str) {
     }
 }
 
-async fn blk_main(ba: blkmine::BlkArgs) -> Result<()> {
+// The sprayer part of the block miner args, which are given flat alongside the rest
+#[derive(Deserialize)]
+struct BlkSprayArgs {
+    subscribe: Option<Vec<String>>,
+    handlerpass: String,
+    sprayerthreads: usize,
+    bind: Option<String>,
+    mss: usize,
+    mcast: Option<String>,
+    #[serde(default)]
+    nack: bool,
+    #[serde(default)]
+    filterwork: u32,
+    #[serde(default)]
+    filterheight: u32,
+}
+
+// The sprayer part of the solo ann miner args
+#[derive(Deserialize)]
+struct SoloSprayArgs {
+    bind: Option<String>,
+    #[serde(default)]
+    passwd: String,
+    #[serde(default)]
+    sprayat: Vec<String>,
+    sprayerthreads: usize,
+    mss: usize,
+}
+
+async fn blk_main(ba: blkmine::BlkArgs, mx: Option<Metrics>) -> Result<()> {
     warn_if_addr_default(&ba.payment_addr);
     let bm = blkmine::new(ba).await?;
     bm.start().await?;
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| blkmine::metrics(&bm, out));
+    }
     util::sleep_forever().await
 }
 
-async fn ann_main(
-    pools: Vec<String>,
-    threads: usize,
-    payment_addr: &str,
-    uploaders: usize,
-    upload_timeout: usize,
-    mine_old_anns: i32,
-) -> Result<()> {
-    warn_if_addr_default(payment_addr);
-    let am = annmine::new(annmine::AnnMineCfg {
-        pools,
-        miner_id: util::rand_u32(),
-        workers: threads,
-        uploaders,
-        pay_to: String::from(payment_addr),
-        upload_timeout,
-        mine_old_anns,
-    })
-    .await?;
+async fn ann_main(cfg: annmine::AnnMineCfg, mx: Option<Metrics>) -> Result<()> {
+    warn_if_addr_default(&cfg.pay_to);
+    let am = annmine::new(cfg).await?;
     annmine::start(&am).await?;
+    if let Some(mx) = mx {
+        metrics::register(&mx, move |out| annmine::metrics(&am, out));
+    }
 
     util::sleep_forever().await
 }
 
-async fn sprayer_main(cfg: packetcrypt_sprayer::Config) -> Result<()> {
-    packetcrypt_sprayer::Sprayer::new(&cfg)?.start();
+async fn sprayer_main(
+    cfg: packetcrypt_sprayer::Config,
+    mx: Option<Metrics>,
+    record: Option<String>,
+    replay: Option<(String, f64)>,
+) -> Result<()> {
+    let spray = packetcrypt_sprayer::Sprayer::new(&cfg)?;
+    if let Some(record) = record {
+        spray.set_handler(packetcrypt_sprayer::record::Recorder::create(&record)?);
+        info!("Recording received anns to {}", record);
+    }
+    spray.start();
+    if let Some(mx) = mx {
+        let spray = spray.clone();
+        metrics::register(&mx, move |out| spray.metrics(out));
+    }
+    if let Some((replay, speed)) = replay {
+        tokio::task::spawn_blocking(move || {
+            packetcrypt_sprayer::record::replay(&spray, &replay, speed)
+        })
+        .await
+        .unwrap()?;
+        return Ok(());
+    }
     util::sleep_forever().await
 }
 
@@ -157,15 +337,6 @@ async fn bench_ann(threads: usize) -> Result<()> {
         .unwrap()
 }
 
-macro_rules! get_strs {
-    ($m:ident, $s:expr) => {
-        if let Some(x) = $m.values_of($s) {
-            x.map(|x| x.to_string()).collect::<Vec<String>>()
-        } else {
-            return Ok(());
-        }
-    };
-}
 macro_rules! get_str {
     ($m:ident, $s:expr) => {
         if let Some(x) = $m.value_of($s) {
@@ -175,11 +346,6 @@ macro_rules! get_str {
         }
     };
 }
-macro_rules! get_usize {
-    ($m:ident, $s:expr) => {
-        get_num!($m, $s, usize)
-    };
-}
 macro_rules! get_num {
     ($m:ident, $s:expr, $n:ident) => {{
         let s = get_str!($m, $s);
@@ -199,91 +365,120 @@ async fn async_main(matches: clap::ArgMatches<'_>) -> Result<()> {
     util::setup_env(matches.occurrences_of("v")).await?;
     if let Some(ann) = matches.subcommand_matches("ann") {
         // ann miner
-        let pools = get_strs!(ann, "pools");
-        let payment_addr = get_str!(ann, "paymentaddr");
-        let threads = get_usize!(ann, "threads");
-        let uploaders = get_usize!(ann, "uploaders");
-        let upload_timeout = get_usize!(ann, "uploadtimeout");
-        let mine_old_anns = get_num!(ann, "mineold", i32);
-        ann_main(
-            pools,
-            threads,
-            payment_addr,
-            uploaders,
-            upload_timeout,
-            mine_old_anns,
-        )
-        .await?;
+        let t = minercfg::load(ann, "ann", minercfg::ANN_KEYS).await?;
+        let mut cfg: annmine::AnnMineCfg = minercfg::parse("ann", t)?;
+        if cfg.pools.is_empty() {
+            bail!("No pools to mine in, list them on the command line or in `ann.pools`");
+        }
+        cfg.miner_id = util::rand_u32();
+        let mx = start_metrics(ann.value_of("metricsbind")).await?;
+        ann_main(cfg, mx).await?;
+    } else if let Some(s) = matches.subcommand_matches("solo") {
+        // ann miner without a pool
+        let t = minercfg::load(s, "solo", minercfg::SOLO_KEYS).await?;
+        let spray: SoloSprayArgs = minercfg::parse("solo", t.clone())?;
+        let mut cfg: solo::SoloCfg = minercfg::parse("solo", t)?;
+        cfg.miner_id = util::rand_u32();
+        cfg.spray_cfg = if let Some(bind) = spray.bind {
+            if spray.passwd.is_empty() {
+                bail!("When sprayer is enabled, passwd is required");
+            }
+            Some(packetcrypt_sprayer::Config {
+                passwd: spray.passwd,
+                bind,
+                workers: spray.sprayerthreads,
+                subscribe_to: Vec::new(),
+                log_peer_stats: false,
+                mss: spray.mss,
+                spray_at: spray.sprayat,
+                mcast: String::new(),
+                nack: false,
+                max_kbps: 0,
+                adaptive: false,
+                tcp_bind: String::new(),
+                relay: false,
+                min_work: 0,
+                filter_work: 0,
+                filter_height: 0,
+            })
+        } else {
+            if !spray.sprayat.is_empty() {
+                bail!("sprayat needs bind, the address to spray from");
+            }
+            None
+        };
+        solo::run(cfg).await?;
     } else if let Some(ah) = matches.subcommand_matches("ah") {
         // ann handler
         let config = get_str!(ah, "config");
         let handler = get_str!(ah, "handler");
-        ah_main(config, handler).await?;
+        let mx = start_metrics(ah.value_of("metricsbind")).await?;
+        ah_main(config, handler, mx).await?;
+    } else if let Some(bh) = matches.subcommand_matches("bh") {
+        // block handler
+        let config = get_str!(bh, "config");
+        let handler = get_str!(bh, "handler");
+        let mx = start_metrics(bh.value_of("metricsbind")).await?;
+        bh_main(config, handler, mx).await?;
+    } else if let Some(m) = matches.subcommand_matches("master") {
+        // pool master
+        let config = get_str!(m, "config");
+        let mx = start_metrics(m.value_of("metricsbind")).await?;
+        master_main(config, mx).await?;
+    } else if let Some(pm) = matches.subcommand_matches("paymaker") {
+        let config = get_str!(pm, "config");
+        let mx = start_metrics(pm.value_of("metricsbind")).await?;
+        paymaker_main(config, mx).await?;
     } else if let Some(blk) = matches.subcommand_matches("blk") {
-        let spray_cfg = if blk.is_present("subscribe") {
-            let passwd: String = get_str!(blk, "handlerpass").into();
-            if passwd.is_empty() {
-                bail!("When sprayer is enabled, --handlerpass is required");
+        let t = minercfg::load(blk, "blk", minercfg::BLK_KEYS).await?;
+        let spray: BlkSprayArgs = minercfg::parse("blk", t.clone())?;
+        let mut ba: blkmine::BlkArgs = minercfg::parse("blk", t)?;
+        ba.spray_cfg = if let Some(subscribe_to) = spray.subscribe {
+            if spray.handlerpass.is_empty() {
+                bail!("When sprayer is enabled, handlerpass is required");
             }
-            let bind: String = get_str!(blk, "bind").into();
+            let bind = spray.bind.unwrap_or_default();
             if bind.is_empty() {
-                bail!("When sprayer is enabled, --bind is required");
-            }
-            let subscribe_to = get_strs!(blk, "subscribe");
-            let workers = get_usize!(blk, "sprayerthreads");
-            let mss = get_usize!(blk, "mss");
-            let mcast = if blk.is_present("mcast") {
-                get_str!(blk, "mcast")
-            } else {
-                ""
+                bail!("When sprayer is enabled, bind is required");
             }
-            .to_owned();
             Some(packetcrypt_sprayer::Config {
-                passwd,
+                passwd: spray.handlerpass,
                 bind,
-                workers,
+                workers: spray.sprayerthreads,
                 subscribe_to,
                 log_peer_stats: false,
-                mss,
+                mss: spray.mss,
                 spray_at: Vec::new(),
-                mcast,
+                mcast: spray.mcast.unwrap_or_default(),
+                nack: spray.nack,
+                max_kbps: 0,
+                adaptive: false,
+                tcp_bind: String::new(),
+                relay: false,
+                min_work: 0,
+                filter_work: spray.filterwork,
+                filter_height: spray.filterheight,
             })
         } else {
-            if blk.is_present("bind") {
-                bail!("--bind (bind UDP sprayer socket) is nonsensical without --subscribe");
+            if spray.bind.is_some() {
+                bail!("bind (bind UDP sprayer socket) is nonsensical without subscribe");
             }
             None
         };
-        blk_main(blkmine::BlkArgs {
-            max_mem: get_usize!(blk, "memorysizemb") * 1024 * 1024,
-            min_free_space: get_num!(blk, "minfree", f64),
-            payment_addr: get_str!(blk, "paymentaddr").into(),
-            threads: get_usize!(blk, "threads"),
-            downloader_count: get_usize!(blk, "downloaders"),
-            pool_master: get_str!(blk, "pool").into(),
-            upload_timeout: get_usize!(blk, "uploadtimeout"),
-            uploaders: get_usize!(blk, "uploaders"),
-            handler_pass: get_str!(blk, "handlerpass").into(),
-            spray_cfg,
-        })
-        .await?;
+        let mx = start_metrics(blk.value_of("metricsbind")).await?;
+        blk_main(ba, mx).await?;
     } else if let Some(spray) = matches.subcommand_matches("sprayer") {
-        let spray_at = if spray.is_present("sprayat") {
-            get_strs!(spray, "sprayat")
+        let t = minercfg::load(spray, "sprayer", minercfg::SPRAYER_KEYS).await?;
+        let mut cfg: packetcrypt_sprayer::Config = minercfg::parse("sprayer", t)?;
+        cfg.log_peer_stats = true;
+        let record = spray.value_of("record").map(String::from);
+        let replay = if let Some(replay) = spray.value_of("replay") {
+            Some((replay.to_owned(), get_num!(spray, "speed", f64)))
         } else {
-            Vec::new()
+            None
         };
-        sprayer_main(packetcrypt_sprayer::Config {
-            passwd: get_str!(spray, "passwd").into(),
-            bind: get_str!(spray, "bind").into(),
-            workers: get_usize!(spray, "threads"),
-            subscribe_to: get_strs!(spray, "subscribe"),
-            log_peer_stats: true,
-            mss: get_usize!(spray, "mss"),
-            spray_at,
-            mcast: "".to_owned(),
-        })
-        .await?;
+        let mx = start_metrics(spray.value_of("metricsbind")).await?;
+        sprayer_main(cfg, mx, record, replay).await?;
     } else if let Some(bench) = matches.subcommand_matches("bench") {
         if let Some(blk) = bench.subcommand_matches("blk") {
             let max_mem = get_num!(blk, "memorysizemb", u64) * 1024 * 1024;
@@ -324,6 +519,13 @@ async fn main() -> Result<()> {
                 .multiple(true)
                 .help("Verbose logging"),
         )
+        .arg(
+            Arg::with_name("metricsbind")
+                .long("metrics-bind")
+                .help("Serve prometheus metrics at this address (e.g. 127.0.0.1:9100)")
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("ah")
                 .about("Run announcement handler")
@@ -342,14 +544,63 @@ async fn main() -> Result<()> {
                         .index(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("bh")
+                .about("Run block handler")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Select the config file, default: pool.toml")
+                        .default_value("./pool.toml")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("handler")
+                        .help("Name of the block handler in the config (e.g. bh0)")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("master")
+                .about("Run pool master")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Select the config file, default: pool.toml")
+                        .default_value("./pool.toml")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("paymaker")
+                .about("Run paymaker")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Select the config file, default: pool.toml")
+                        .default_value("./pool.toml")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("ann")
                 .about("Run announcement miner")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Read settings from the [ann] section of this file, flags override it")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .short("t")
                         .long("threads")
-                        .help("Number of threads to mine with")
+                        .help("Number of threads to mine with, with weighted pools each pool has this many and one pool at a time is mining")
                         .default_value(&cpus_str)
                         .takes_value(true),
                 )
@@ -383,16 +634,135 @@ async fn main() -> Result<()> {
                         .help("how many blocks old to mine annoucements, -1 to let the pool decide")
                         .default_value("-1"),
                 )
+                .arg(
+                    Arg::with_name("spooldir")
+                        .long("spooldir")
+                        .help("Keep anns which cannot be uploaded in this directory and upload them later")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("spoolmaxmb")
+                        .long("spoolmaxmb")
+                        .help("Most disk space to use for spooled anns, per pool")
+                        .default_value("1024"),
+                )
+                .arg(
+                    Arg::with_name("spoolmaxage")
+                        .long("spoolmaxage")
+                        .help("Seconds after which spooled anns are given up on")
+                        .default_value("3600"),
+                )
                 .arg(
                     Arg::with_name("pools")
-                        .help("The pools to mine in")
-                        .required(true)
+                        .help("The pools to mine in, pool@0.7 to give a pool 70% of the mining")
+                        .required_unless("config")
                         .min_values(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("solo")
+                .about("Mine announcements on the tip of a pktd node, without a pool")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Read settings from the [solo] section of this file, flags override it")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rpcurl")
+                        .long("rpcurl")
+                        .help("JSON-RPC url of the node, e.g. http://127.0.0.1:64765")
+                        .required_unless("config")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rpcuser")
+                        .long("rpcuser")
+                        .help("Username for the node's JSON-RPC")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rpcpass")
+                        .long("rpcpass")
+                        .help("Password for the node's JSON-RPC")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("threads")
+                        .short("t")
+                        .long("threads")
+                        .help("Number of threads to mine with")
+                        .default_value(&cpus_str)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .help("Compact target to mine anns at, the default 545259519 is 0x207fffff")
+                        .default_value("545259519")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("outdir")
+                        .short("o")
+                        .long("outdir")
+                        .help("Write anns to ann files in this directory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("filestokeep")
+                        .long("filestokeep")
+                        .help("Keep this many of the newest ann files in outdir")
+                        .default_value("500")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("bind")
+                        .short("b")
+                        .long("bind")
+                        .help("UDP socket to bind to for spraying anns to block miners")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("passwd")
+                        .long("passwd")
+                        .help("Password which block miners subscribe with, their handlerpass")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sprayat")
+                        .long("sprayat")
+                        .help("Spray anns at these block miners without waiting for them to subscribe")
+                        .min_values(1),
+                )
+                .arg(
+                    Arg::with_name("sprayerthreads")
+                        .short("S")
+                        .long("sprayerthreads")
+                        .help("Number of threads to run in the sprayer interface")
+                        .default_value("1")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("mss")
+                        .short("M")
+                        .long("maxsegmentsize")
+                        .help("Maximum packet size to send when using UDP sprayer, remember IP and UDP overhead")
+                        .default_value("1472")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("blk")
                 .about("Run block miner")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Read settings from the [blk] section of this file, flags override it")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("paymentaddr")
                         .short("p")
@@ -435,7 +805,7 @@ async fn main() -> Result<()> {
                 .arg(
                     Arg::with_name("pool")
                         .help("The pool server to use")
-                        .required(true)
+                        .required_unless("config")
                         .index(1),
                 )
                 .arg(
@@ -458,7 +828,7 @@ async fn main() -> Result<()> {
                     Arg::with_name("subscribe")
                         .short("s")
                         .long("subscribe")
-                        .help("Sprayer interface to subscribe to")
+                        .help("Sprayer interface to subscribe to, tcp://host:port to subscribe over tcp")
                         .takes_value(true)
                         .min_values(1),
                 )
@@ -496,13 +866,37 @@ async fn main() -> Result<()> {
                 .arg(
                     Arg::with_name("mcast")
                     .long("mcast")
-                    .help("Connect to this multicast group")
+                    .help("Connect to this multicast group, for ipv6 add %<interface> e.g. ff02::1:6%eth0")
+                    .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("nack")
+                    .long("nack")
+                    .help("Ask the sprayers which we subscribe to for anns which went missing"),
+                )
+                .arg(
+                    Arg::with_name("filterwork")
+                    .long("filterwork")
+                    .help("Only receive anns with at least this much work, a compact target, 0 for any")
+                    .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("filterheight")
+                    .long("filterheight")
+                    .help("Only receive anns for this many of the most recent blocks, 0 for any")
                     .takes_value(true),
                 ),
         )
         .subcommand(
             SubCommand::with_name("sprayer")
                 .about("Launch ann sprayer daemon")
+                .arg(
+                    Arg::with_name("config")
+                        .short("C")
+                        .long("config")
+                        .help("Read settings from the [sprayer] section of this file, flags override it")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("threads")
                         .short("t")
@@ -517,13 +911,13 @@ async fn main() -> Result<()> {
                         .long("bind")
                         .help("Address to bind to")
                         .takes_value(true)
-                        .required(true),
+                        .required_unless("config"),
                 )
                 .arg(
                     Arg::with_name("passwd")
                         .short("P")
                         .long("passwd")
-                        .help("Password to use for authing with other sprayers")
+                        .help("Password which authenticates and encrypts traffic with other sprayers")
                         .default_value("")
                         .takes_value(true),
                 )
@@ -531,8 +925,8 @@ async fn main() -> Result<()> {
                     Arg::with_name("subscribe")
                         .short("s")
                         .long("subscribe")
-                        .help("Sprayers so subscribe to")
-                        .required(true)
+                        .help("Sprayers so subscribe to, tcp://host:port to subscribe over tcp")
+                        .required_unless_one(&["config", "replay"])
                         .min_values(1),
                 )
                 .arg(
@@ -549,6 +943,80 @@ async fn main() -> Result<()> {
                         .help("Maximum packet size to send, remember IP and UDP overhead")
                         .default_value("1472")
                         .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("mcast")
+                        .long("mcast")
+                        .help("Join and send from this multicast group, for ipv6 add %<interface> e.g. ff02::1:6%eth0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("nack")
+                        .long("nack")
+                        .help("Ask the sprayers which we subscribe to for anns which went missing"),
+                )
+                .arg(
+                    Arg::with_name("maxkbps")
+                        .long("maxkbps")
+                        .help("Limit the rate of sending to each peer, in kilobits per second")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("adaptive")
+                        .long("adaptive")
+                        .help("Slow down sending to peers which are losing packets"),
+                )
+                .arg(
+                    Arg::with_name("tcpbind")
+                        .long("tcpbind")
+                        .help("Also accept subscriptions over tcp on this address")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("relay")
+                        .long("relay")
+                        .help("Only pass on anns which are new and for a recent block"),
+                )
+                .arg(
+                    Arg::with_name("minwork")
+                        .long("minwork")
+                        .help("In relay mode, drop anns with less work than this compact target")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("filterwork")
+                        .long("filterwork")
+                        .help("Only receive anns with at least this much work, a compact target, 0 for any")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("filterheight")
+                        .long("filterheight")
+                        .help("Only receive anns for this many of the most recent blocks, 0 for any")
+                        .default_value("0")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("record")
+                        .long("record")
+                        .help("Write the anns which are received to this file, for --replay")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("replay")
+                        .long("replay")
+                        .help("Send the anns from this recording to the subscribers, once there are any, and exit")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .help("Replay speed, 2 is twice as fast as recorded, 0 is as fast as possible")
+                        .default_value("1")
+                        .takes_value(true),
                 ),
         )
         .subcommand(
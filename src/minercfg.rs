@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: (LGPL-2.1-only OR LGPL-3.0-only)
+//
+// Miner config file, one section per subcommand, e.g.
+//
+// [ann]
+//     pools = [ "http://pool.example" ]
+//     threads = 4
+//
+// Keys are named after the command line flags, any flag which is given explicitly
+// overrides the file and any key which is missing from both gets the clap default.
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use toml::value::{Table, Value};
+
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Str,
+    Strs,
+    Int,
+    Float,
+    Bool,
+}
+
+pub const ANN_KEYS: &[(&str, Kind)] = &[
+    ("pools", Kind::Strs),
+    ("threads", Kind::Int),
+    ("uploaders", Kind::Int),
+    ("uploadtimeout", Kind::Int),
+    ("paymentaddr", Kind::Str),
+    ("mineold", Kind::Int),
+    ("spooldir", Kind::Str),
+    ("spoolmaxmb", Kind::Int),
+    ("spoolmaxage", Kind::Int),
+];
+
+pub const SOLO_KEYS: &[(&str, Kind)] = &[
+    ("rpcurl", Kind::Str),
+    ("rpcuser", Kind::Str),
+    ("rpcpass", Kind::Str),
+    ("threads", Kind::Int),
+    ("target", Kind::Int),
+    ("outdir", Kind::Str),
+    ("filestokeep", Kind::Int),
+    ("bind", Kind::Str),
+    ("passwd", Kind::Str),
+    ("sprayat", Kind::Strs),
+    ("sprayerthreads", Kind::Int),
+    ("mss", Kind::Int),
+];
+
+pub const BLK_KEYS: &[(&str, Kind)] = &[
+    ("paymentaddr", Kind::Str),
+    ("threads", Kind::Int),
+    ("downloaders", Kind::Int),
+    ("minfree", Kind::Float),
+    ("memorysizemb", Kind::Int),
+    ("pool", Kind::Str),
+    ("uploadtimeout", Kind::Int),
+    ("handlerpass", Kind::Str),
+    ("subscribe", Kind::Strs),
+    ("sprayerthreads", Kind::Int),
+    ("bind", Kind::Str),
+    ("uploaders", Kind::Int),
+    ("mss", Kind::Int),
+    ("mcast", Kind::Str),
+    ("nack", Kind::Bool),
+    ("filterwork", Kind::Int),
+    ("filterheight", Kind::Int),
+];
+
+pub const SPRAYER_KEYS: &[(&str, Kind)] = &[
+    ("threads", Kind::Int),
+    ("bind", Kind::Str),
+    ("passwd", Kind::Str),
+    ("subscribe", Kind::Strs),
+    ("sprayat", Kind::Strs),
+    ("mss", Kind::Int),
+    ("mcast", Kind::Str),
+    ("nack", Kind::Bool),
+    ("maxkbps", Kind::Int),
+    ("adaptive", Kind::Bool),
+    ("tcpbind", Kind::Str),
+    ("relay", Kind::Bool),
+    ("minwork", Kind::Int),
+    ("filterwork", Kind::Int),
+    ("filterheight", Kind::Int),
+];
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Str => "a string",
+        Kind::Strs => "a list of strings",
+        Kind::Int => "an integer",
+        Kind::Float => "a number",
+        Kind::Bool => "true or false",
+    }
+}
+
+fn from_file(v: Value, kind: Kind) -> Option<Value> {
+    match (kind, v) {
+        (Kind::Str, v @ Value::String(_)) => Some(v),
+        (Kind::Int, v @ Value::Integer(_)) => Some(v),
+        (Kind::Float, v @ Value::Float(_)) => Some(v),
+        (Kind::Float, Value::Integer(i)) => Some(Value::Float(i as f64)),
+        (Kind::Bool, v @ Value::Boolean(_)) => Some(v),
+        (Kind::Strs, Value::Array(a)) if a.iter().all(|v| v.is_str()) => Some(Value::Array(a)),
+        _ => None,
+    }
+}
+
+fn from_cli(m: &clap::ArgMatches, name: &str, kind: Kind) -> Result<Option<Value>> {
+    if let Kind::Bool = kind {
+        // Flags which take no value
+        return Ok(if m.is_present(name) {
+            Some(Value::Boolean(true))
+        } else {
+            None
+        });
+    }
+    let s = if let Some(s) = m.value_of(name) {
+        s
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(match kind {
+        Kind::Str => Value::String(s.to_owned()),
+        Kind::Strs => Value::Array(
+            m.values_of(name)
+                .unwrap()
+                .map(|s| Value::String(s.to_owned()))
+                .collect(),
+        ),
+        Kind::Int => Value::Integer(
+            s.parse()
+                .with_context(|| format!("Unable to parse --{} [{}] as an integer", name, s))?,
+        ),
+        Kind::Float => Value::Float(
+            s.parse()
+                .with_context(|| format!("Unable to parse --{} [{}] as a number", name, s))?,
+        ),
+        Kind::Bool => unreachable!(),
+    }))
+}
+
+async fn load_section(config: &str, section: &str) -> Result<Table> {
+    let confb = tokio::fs::read(config)
+        .await
+        .with_context(|| format!("Failed to read config file [{}]", config))?;
+    let mut root: Table = toml::de::from_slice(&confb[..])
+        .with_context(|| format!("Failed to parse config file [{}]", config))?;
+    match root.remove(section) {
+        Some(Value::Table(t)) => Ok(t),
+        Some(_) => bail!("Config file [{}]: [{}] must be a table", config, section),
+        None => bail!("Config file [{}] has no [{}] section", config, section),
+    }
+}
+
+/// Merge the config file given by --config (if any) with the command line.
+pub async fn load(m: &clap::ArgMatches<'_>, section: &str, keys: &[(&str, Kind)]) -> Result<Table> {
+    let config = m.value_of("config");
+    let mut file = if let Some(config) = config {
+        load_section(config, section).await?
+    } else {
+        Table::new()
+    };
+    if let Some(k) = file.keys().find(|k| !keys.iter().any(|(n, _)| n == k)) {
+        bail!(
+            "Config file [{}]: unknown key `{}.{}`",
+            config.unwrap(),
+            section,
+            k
+        );
+    }
+    let mut out = Table::new();
+    for &(name, kind) in keys {
+        let explicit = m.occurrences_of(name) > 0;
+        let v = match file.remove(name) {
+            Some(v) if !explicit => Some(from_file(v, kind).with_context(|| {
+                format!(
+                    "Config file [{}]: `{}.{}` must be {}",
+                    config.unwrap(),
+                    section,
+                    name,
+                    kind_name(kind)
+                )
+            })?),
+            _ => from_cli(m, name, kind)?,
+        };
+        if let Some(v) = v {
+            out.insert(name.to_owned(), v);
+        }
+    }
+    Ok(out)
+}
+
+pub fn parse<T: DeserializeOwned>(section: &str, t: Table) -> Result<T> {
+    Value::Table(t)
+        .try_into()
+        .with_context(|| format!("Invalid [{}] configuration", section))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_file() {
+        assert_eq!(
+            from_file(Value::Integer(3), Kind::Float),
+            Some(Value::Float(3.0))
+        );
+        assert_eq!(from_file(Value::Integer(3), Kind::Str), None);
+        let a = Value::Array(vec![Value::String("a".into()), Value::Integer(1)]);
+        assert_eq!(from_file(a, Kind::Strs), None);
+    }
+}